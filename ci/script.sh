@@ -2,7 +2,7 @@
 
 set -euxo pipefail
 
-cargo check --features "stm32f429xx fs"
-cargo check --features "stm32f429xx hs"
+cargo check --features "stm32f429xx"
 cargo check --features "stm32f401xx"
 cargo check --features "gd32vf103xx"
+cargo test --features "sim"
@@ -0,0 +1,239 @@
+//! Drives `UsbBus` on the simulated core with a sequence of operations decoded from the input.
+//!
+//! The input is interpreted as a list of operations: bus events, well-formed and raw RX FIFO
+//! entries, raw interrupt flags, IN/OUT tokens and calls to `poll`, `read` and `write`. The harness
+//! checks that
+//!
+//! - the driver does not panic,
+//! - the driver does not misuse the core (`SimCore::take_errors`), e.g. pops an empty RX status
+//!   queue or leaves packet data in the RX FIFO,
+//! - SETUP and OUT packets sent to endpoint 0 are read back exactly once and in order, unless a
+//!   bus reset discards them or a later SETUP packet replaces them before they are read.
+
+use std::collections::VecDeque;
+use synopsys_usb_otg::sim::{Handshake, SimCore, SimUsb};
+use synopsys_usb_otg::UsbBus;
+use usb_device::bus::{PollResult, UsbBus as _, UsbBusAllocator};
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use usb_device::endpoint::{EndpointAddress, EndpointIn, EndpointOut};
+use usb_device::UsbDirection;
+
+type Bus = UsbBus<SimUsb>;
+
+const ENDPOINTS: usize = 4;
+
+/// Interrupt flags that can be raised on their own
+const INTERRUPTS: [u32; 8] = [
+    1 << 3,  // SOF
+    1 << 10, // ESUSP
+    1 << 11, // USBSUSP
+    1 << 12, // USBRST
+    1 << 13, // ENUMDNE
+    1 << 20, // IISOIXFR
+    1 << 21, // IPXFR/INCOMPISOOUT
+    1 << 31, // WKUPINT
+];
+
+/// USBRST and ENUMDNE
+const RESET_INTERRUPTS: u32 = (1 << 12) | (1 << 13);
+
+struct Input<'a> {
+    data: &'a [u8],
+}
+
+impl Input<'_> {
+    fn byte(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((&byte, rest)) => {
+                self.data = rest;
+                byte
+            }
+            None => 0,
+        }
+    }
+
+    fn bytes(&mut self, length: usize) -> Vec<u8> {
+        let length = length.min(self.data.len());
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        bytes.to_vec()
+    }
+}
+
+struct Harness<'a> {
+    core: SimCore,
+    device: UsbDevice<'a, Bus>,
+    /// Packets sent to endpoint 0 that were not read yet, and whether they are SETUP packets
+    expected: VecDeque<(bool, Vec<u8>)>,
+}
+
+impl Harness<'_> {
+    fn bus(&self) -> &Bus {
+        self.device.bus()
+    }
+
+    fn check_core(&self) {
+        let errors = self.core.take_errors();
+        assert!(errors.is_empty(), "the driver misused the core: {:?}", errors);
+    }
+
+    fn poll(&mut self) -> PollResult {
+        let result = self.bus().poll();
+        if let PollResult::Reset = result {
+            // Packets that were not read yet are discarded on reset
+            self.bus().reset();
+            self.expected.clear();
+        }
+        result
+    }
+
+    fn read(&mut self, index: usize) {
+        let mut buf = [0; 256];
+        let result = self.bus().read(EndpointAddress::from_parts(index, UsbDirection::Out), &mut buf);
+
+        if index != 0 {
+            return;
+        }
+        if let Ok(count) = result {
+            let packet = &buf[..count];
+            if self.expected.front().is_some_and(|(_, expected)| expected[..] == *packet) {
+                self.expected.pop_front();
+                return;
+            }
+
+            // A SETUP packet replaces the packets sent before it that were not read yet
+            let setup = self.expected.iter().position(|(is_setup, expected)| *is_setup && expected[..] == *packet);
+            match setup {
+                Some(index) => drop(self.expected.drain(..=index)),
+                None => panic!("wrong packet on endpoint 0: {:?}, expected {:?}", packet, self.expected.front()),
+            }
+        }
+    }
+
+    fn write(&mut self, index: usize, data: &[u8]) {
+        self.bus().write(EndpointAddress::from_parts(index, UsbDirection::In), data).ok();
+    }
+
+    /// Polls and reads all endpoints until the RX FIFO is empty.
+    fn drain(&mut self) {
+        for _ in 0..64 {
+            self.poll();
+            for index in 0..ENDPOINTS {
+                self.read(index);
+            }
+            self.check_core();
+
+            if self.core.rx_fifo_empty() {
+                break;
+            }
+        }
+        self.read(0);
+
+        assert!(self.core.rx_fifo_empty(), "RX FIFO is stuck");
+        assert!(self.expected.is_empty(), "lost packets on endpoint 0: {:?}", self.expected);
+    }
+
+    fn step(&mut self, input: &mut Input) {
+        match input.byte() % 12 {
+            0 => {
+                self.drain();
+                self.core.bus_reset();
+            }
+            1 => {
+                let packet = input.bytes(8);
+                let mut setup = [0; 8];
+                setup[..packet.len()].copy_from_slice(&packet);
+                if self.core.setup(0, &setup) == Handshake::Ack {
+                    self.expected.push_back((true, setup.to_vec()));
+                }
+            }
+            2 => {
+                let length = input.byte() as usize % 9;
+                let data = input.bytes(length);
+                if self.core.out(0, &data) == Handshake::Ack {
+                    self.expected.push_back((false, data));
+                }
+            }
+            3 => {
+                let ep = 1 + input.byte() % (ENDPOINTS as u8 - 1);
+                let status = input.byte() % 3 + 1;
+                let length = if status == 0x02 { input.byte() as usize } else { 0 };
+                let data = input.bytes(length);
+                self.core.push_rx(ep, status, &data);
+            }
+            4 => {
+                let ep = 1 + input.byte() % (ENDPOINTS as u8 - 1);
+                let length = input.byte() as usize % 80;
+                let data = input.bytes(length);
+                self.core.out(ep, &data);
+            }
+            5 => {
+                let ep = input.byte() % ENDPOINTS as u8;
+                self.core.in_token(ep).ok();
+            }
+            6 => {
+                let interrupt = INTERRUPTS[input.byte() as usize % INTERRUPTS.len()];
+                if interrupt & RESET_INTERRUPTS != 0 {
+                    // Packets in flight are discarded by a reset, and the host does not send new
+                    // ones before the reset is handled
+                    self.drain();
+                    self.core.raise_interrupts(interrupt);
+                    self.poll();
+                } else {
+                    self.core.raise_interrupts(interrupt);
+                }
+            }
+            7 | 8 => {
+                let flags = input.byte();
+                if let PollResult::Data { ep_out, ep_setup, .. } = self.poll() {
+                    // Leave some packets in the endpoint buffers
+                    if (ep_out | ep_setup) & 1 != 0 && flags & 1 != 0 {
+                        self.read(0);
+                    }
+                }
+            }
+            9 => {
+                let index = input.byte() as usize % ENDPOINTS;
+                self.read(index);
+            }
+            10 => {
+                let index = input.byte() as usize % ENDPOINTS;
+                let length = input.byte() as usize % 80;
+                let data = input.bytes(length);
+                self.write(index, &data);
+            }
+            _ => {
+                let index = input.byte() as usize % ENDPOINTS;
+                let direction = if input.byte() & 1 != 0 { UsbDirection::In } else { UsbDirection::Out };
+                let stalled = input.byte() & 1 != 0;
+                self.bus().set_stalled(EndpointAddress::from_parts(index, direction), stalled);
+            }
+        }
+
+        self.check_core();
+    }
+}
+
+/// Runs the operations encoded in `data`.
+pub fn run(data: &[u8]) {
+    let core = SimCore::new();
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    // The bulk OUT endpoint buffers several packets
+    let alloc: UsbBusAllocator<Bus> = UsbBus::new_with_out_queues(SimUsb, ep_memory, &[1, 3]);
+
+    let _bulk_out: EndpointOut<Bus> = alloc.bulk(64);
+    let _bulk_in: EndpointIn<Bus> = alloc.bulk(64);
+    let _interrupt_out: EndpointOut<Bus> = alloc.interrupt(8, 1);
+    let _interrupt_in: EndpointIn<Bus> = alloc.interrupt(8, 1);
+    let device = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x05dc)).max_packet_size_0(8).build();
+
+    let mut harness = Harness { core, device, expected: VecDeque::new() };
+    harness.core.bus_reset();
+    harness.poll();
+
+    let mut input = Input { data };
+    while !input.data.is_empty() {
+        harness.step(&mut input);
+    }
+    harness.drain();
+}
@@ -1,55 +1,644 @@
 use usb_device::{Result, UsbDirection, UsbError};
 use usb_device::bus::{UsbBusAllocator, PollResult};
 use usb_device::endpoint::{EndpointType, EndpointAddress};
-use crate::ral::{read_reg, write_reg, modify_reg, otg_global, otg_device, otg_pwrclk};
+use crate::ral::{read_reg, write_reg, modify_reg, otg_global, otg_global_dieptxfx, otg_device, otg_pwrclk};
 
 use crate::target::UsbRegisters;
 use crate::target::interrupt::{self, Mutex, CriticalSection};
 use crate::endpoint::{EndpointIn, EndpointOut, Endpoint};
-use crate::endpoint_memory::{EndpointMemoryAllocator, EndpointBufferState};
+use crate::endpoint_memory::{EndpointMemoryAllocator, EndpointBuffer, EndpointBufferState};
 use core::ops::Deref;
 use core::cmp;
-use crate::UsbPeripheral;
+use core::cell::Cell;
+use crate::{UsbPeripheral, PhyType, VbusSense, UsbRole};
+use crate::host::UsbHost;
+use crate::phy;
+
+/// Bus speed of the device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UsbSpeed {
+    /// High speed (480 Mbit/s)
+    High,
+    /// Full speed (12 Mbit/s)
+    Full,
+    /// Low speed (1.5 Mbit/s)
+    Low,
+}
+
+/// Isochronous endpoints that missed their (micro)frame.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct IsoIncomplete {
+    /// Bitmask of IN endpoints whose packet was not sent
+    pub ep_in: u16,
+    /// Bitmask of OUT endpoints that did not receive a packet
+    pub ep_out: u16,
+}
+
+/// Cable state change detected through VBUS sensing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CableEvent {
+    /// VBUS became valid, the device is connected to a host
+    Attached,
+    /// VBUS is gone, the device was unplugged
+    Detached,
+}
+
+/// OTG protocol events.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct OtgEvents {
+    /// A session request started by `UsbBus::request_session` succeeded
+    pub session_request_success: bool,
+    /// A session request started by `UsbBus::request_session` failed
+    pub session_request_failure: bool,
+    /// A connected B-device requested a session, the A-device should switch VBUS on
+    pub session_request_detected: bool,
+    /// VBUS dropped below the B-device session end threshold
+    pub session_end: bool,
+    /// Host negotiation succeeded, the core switched roles
+    pub host_negotiation_success: bool,
+    /// Host negotiation failed
+    pub host_negotiation_failure: bool,
+    /// The B-device requested host negotiation
+    pub host_negotiation_detected: bool,
+    /// The A-device timed out waiting for the B-device to connect
+    pub a_device_timeout: bool,
+}
+
+/// Maximum number of endpoints supported by the Synopsys OTG core.
+const MAX_ENDPOINTS: usize = 16;
+
+/// Duration of the remote wakeup signalling, must be within 1..=15 ms.
+const REMOTE_WAKEUP_SIGNAL_MS: u32 = 10;
 
 /// USB peripheral driver for STM32 microcontrollers.
 pub struct UsbBus<USB> {
     peripheral: USB,
-    regs: Mutex<UsbRegisters<USB>>,
-    endpoints_in: [EndpointIn; 4],
-    endpoints_out: [EndpointOut; 4],
+    regs: Mutex<UsbRegisters>,
+    endpoints_in: [EndpointIn; MAX_ENDPOINTS],
+    endpoints_out: [EndpointOut; MAX_ENDPOINTS],
     endpoint_allocator: EndpointMemoryAllocator,
+    /// Number of packets buffered by each OUT endpoint
+    out_queue_depths: [usize; MAX_ENDPOINTS],
+    dma: bool,
+    iso_incomplete: Mutex<Cell<IsoIncomplete>>,
+    role: Mutex<Cell<UsbRole>>,
+    role_change: Mutex<Cell<Option<UsbRole>>>,
+    host_taken: Mutex<Cell<bool>>,
+    otg_events: Mutex<Cell<OtgEvents>>,
+    cable_event: Mutex<Cell<Option<CableEvent>>>,
+    sof_enabled: Mutex<Cell<bool>>,
+    sof: Mutex<Cell<Option<u16>>>,
 }
 
 impl<USB: UsbPeripheral> UsbBus<USB> {
     /// Constructs a new USB peripheral driver.
     pub fn new(peripheral: USB, ep_memory: &'static mut [u32]) -> UsbBusAllocator<Self> {
-        let endpoints_in = [
-            EndpointIn::new(EndpointAddress::from_parts(0, UsbDirection::In)),
-            EndpointIn::new(EndpointAddress::from_parts(1, UsbDirection::In)),
-            EndpointIn::new(EndpointAddress::from_parts(2, UsbDirection::In)),
-            EndpointIn::new(EndpointAddress::from_parts(3, UsbDirection::In)),
-        ];
-        let endpoints_out = [
-            EndpointOut::new(EndpointAddress::from_parts(0, UsbDirection::Out)),
-            EndpointOut::new(EndpointAddress::from_parts(1, UsbDirection::Out)),
-            EndpointOut::new(EndpointAddress::from_parts(2, UsbDirection::Out)),
-            EndpointOut::new(EndpointAddress::from_parts(3, UsbDirection::Out)),
-        ];
-        let bus = UsbBus {
+        UsbBusAllocator::new(Self::new_bus(peripheral, ep_memory, false))
+    }
+
+    /// Constructs a new USB peripheral driver that buffers several packets per OUT endpoint.
+    ///
+    /// `out_queue_depths[i]` is the number of packets buffered by OUT endpoint `i`, endpoints
+    /// without an entry buffer a single packet. Packets are moved from the RX FIFO into the
+    /// buffer of an endpoint as long as it has room, so back-to-back packets are acknowledged
+    /// while the application is busy, and `read` returns them in order.
+    ///
+    /// Endpoint 0 always buffers a single packet, so `out_queue_depths[0]` must be 1. Panics if
+    /// a depth is 0 or if there are more entries than `UsbPeripheral::ENDPOINT_COUNT`. The queues
+    /// are not available in DMA mode, where the core writes each packet directly into the buffer
+    /// of the endpoint.
+    pub fn new_with_out_queues(peripheral: USB, ep_memory: &'static mut [u32], out_queue_depths: &[usize]) -> UsbBusAllocator<Self> {
+        assert!(out_queue_depths.len() <= USB::ENDPOINT_COUNT, "out_queue_depths has more entries than ENDPOINT_COUNT");
+        assert!(matches!(out_queue_depths.first(), None | Some(1)), "endpoint 0 buffers a single packet");
+        assert!(!out_queue_depths.contains(&0), "OUT queue depths must be at least 1");
+
+        let mut bus = Self::new_bus(peripheral, ep_memory, false);
+        bus.out_queue_depths[..out_queue_depths.len()].copy_from_slice(out_queue_depths);
+
+        UsbBusAllocator::new(bus)
+    }
+
+    /// Constructs a new USB peripheral driver that uses the internal DMA of the core.
+    ///
+    /// Packets are transferred directly between `ep_memory` and the core, so `ep_memory` must be
+    /// accessible by the AHB master of the peripheral and must not be cached. Only High Speed
+    /// variants of the peripheral support DMA.
+    pub fn new_with_dma(peripheral: USB, ep_memory: &'static mut [u32]) -> UsbBusAllocator<Self> {
+        assert!(USB::HIGH_SPEED, "DMA is only supported by High Speed peripherals");
+
+        UsbBusAllocator::new(Self::new_bus(peripheral, ep_memory, true))
+    }
+
+    fn new_bus(peripheral: USB, ep_memory: &'static mut [u32], dma: bool) -> Self {
+        assert!((1..=MAX_ENDPOINTS).contains(&USB::ENDPOINT_COUNT), "ENDPOINT_COUNT must be within 1..=16");
+        assert!(
+            match USB::VBUS_SENSE {
+                VbusSense::Disabled => true,
+                VbusSense::BSessionValid => !USB::VBUS_DETECTION_BLOCK,
+                VbusSense::VbusDetection => USB::VBUS_DETECTION_BLOCK,
+            },
+            "VBUS_SENSE does not match the GCCFG layout selected by VBUS_DETECTION_BLOCK"
+        );
+
+        let regs = UsbRegisters::new::<USB>();
+
+        let endpoints_in = core::array::from_fn(|index| {
+            EndpointIn::new(regs, EndpointAddress::from_parts(index, UsbDirection::In), dma, USB::HIGH_SPEED)
+        });
+        let endpoints_out = core::array::from_fn(|index| {
+            EndpointOut::new(regs, EndpointAddress::from_parts(index, UsbDirection::Out), dma)
+        });
+        UsbBus {
             peripheral,
-            regs: Mutex::new(UsbRegisters::new()),
+            regs: Mutex::new(regs),
             endpoint_allocator: EndpointMemoryAllocator::new(ep_memory),
+            out_queue_depths: [1; MAX_ENDPOINTS],
             endpoints_in,
             endpoints_out,
-        };
-
-        UsbBusAllocator::new(bus)
+            dma,
+            iso_incomplete: Mutex::new(Cell::new(IsoIncomplete::default())),
+            role: Mutex::new(Cell::new(UsbRole::Device)),
+            role_change: Mutex::new(Cell::new(None)),
+            host_taken: Mutex::new(Cell::new(false)),
+            otg_events: Mutex::new(Cell::new(OtgEvents::default())),
+            cable_event: Mutex::new(Cell::new(None)),
+            sof_enabled: Mutex::new(Cell::new(false)),
+            sof: Mutex::new(Cell::new(None)),
+        }
     }
 
     pub fn free(self) -> USB {
         self.peripheral
     }
 
+    /// Returns the bus speed negotiated with the host during the last bus reset.
+    pub fn speed(&self) -> UsbSpeed {
+        self.ungated(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            match read_reg!(otg_device, regs.device(), DSTS, ENUMSPD) {
+                0b00 => UsbSpeed::High,
+                0b10 => UsbSpeed::Low,
+                _ => UsbSpeed::Full,
+            }
+        })
+    }
+
+    /// Assigns a buffer for multi-packet transfers on a bulk or interrupt IN endpoint.
+    ///
+    /// Once a buffer is assigned, `write` on the endpoint accepts payloads of up to
+    /// `buffer.len() * 4` bytes and sends them as a single transfer of `max_packet_size` packets.
+    /// Completion of the whole transfer is reported once in `PollResult::Data::ep_in_complete`.
+    /// Payloads that are a multiple of `max_packet_size` are not terminated with a zero-length
+    /// packet. In DMA mode the buffer is read directly by the core and has the same memory
+    /// requirements as `ep_memory`.
+    pub fn set_in_transfer_buffer(&self, ep_addr: EndpointAddress, buffer: &'static mut [u32]) -> Result<()> {
+        if !ep_addr.is_in() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let buffer = EndpointBuffer::new(buffer);
+        self.ungated(|_| self.endpoints_in[ep_addr.index()].set_transfer_buffer(buffer))
+    }
+
+    /// Assigns a buffer for multi-packet transfers on a bulk or interrupt OUT endpoint.
+    ///
+    /// Once a buffer is assigned, the endpoint NAKs all packets until a transfer is started with
+    /// `start_out_transfer`. The buffer must be assigned while the endpoint is not enabled, e.g.
+    /// before the device is polled for the first time. In DMA mode the buffer is written
+    /// directly by the core and has the same memory requirements as `ep_memory`.
+    pub fn set_out_transfer_buffer(&self, ep_addr: EndpointAddress, buffer: &'static mut [u32]) -> Result<()> {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let buffer = EndpointBuffer::new(buffer);
+        self.ungated(|_| self.endpoints_out[ep_addr.index()].set_transfer_buffer(buffer))
+    }
+
+    /// Starts receiving a transfer of up to `length` bytes on an OUT endpoint with a transfer
+    /// buffer.
+    ///
+    /// `length` is rounded up to a multiple of `max_packet_size` and must fit into the buffer.
+    /// The transfer ends when all packets have been received or on a short packet. Completion is
+    /// reported once in `PollResult::Data::ep_out`, after which `read` returns the whole transfer.
+    pub fn start_out_transfer(&self, ep_addr: EndpointAddress, length: usize) -> Result<()> {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        self.ungated(|_| self.endpoints_out[ep_addr.index()].start_transfer(length))
+    }
+
+    /// Holds a bulk or interrupt OUT endpoint in NAK (`DOEPCTL.SNAK`), or releases it.
+    ///
+    /// While held, the endpoint NAKs all packets regardless of the room in its buffer, so the
+    /// host retries them until the endpoint is released. Packets that were already received can
+    /// still be read. The hold is kept across bus resets. Endpoint 0 cannot be held.
+    pub fn set_out_nak(&self, ep_addr: EndpointAddress, nak: bool) -> Result<()> {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        self.ungated(|_| self.endpoints_out[ep_addr.index()].set_nak(nak))
+    }
+
+    /// Returns the isochronous endpoints that missed their (micro)frame since the last call.
+    ///
+    /// Pending isochronous transfers that miss their (micro)frame are moved to the next one by
+    /// `poll`, so the application only needs this to account for the dropped packets.
+    pub fn take_iso_incomplete(&self) -> IsoIncomplete {
+        interrupt::free(|cs| self.iso_incomplete.borrow(cs).take())
+    }
+
+    /// Returns the current role of the core.
+    ///
+    /// Always returns `UsbRole::Device` unless `UsbPeripheral::DUAL_ROLE` is set.
+    pub fn role(&self) -> UsbRole {
+        self.ungated(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            if read_reg!(otg_global, regs.global(), GINTSTS, CMOD) != 0 {
+                UsbRole::Host
+            } else {
+                UsbRole::Device
+            }
+        })
+    }
+
+    /// Returns the new role if the role of a dual-role core changed since the last call.
+    ///
+    /// Role changes are handled by `poll`, the change is reported by the first `poll` after the
+    /// core switched its mode following the ID pin. The core does not interrupt when it switched,
+    /// so an application that only polls from the interrupt handler must also poll some time after
+    /// the ID pin changed, e.g. from a timer. When switching to the host role the device is
+    /// disconnected and `poll` reports `PollResult::Suspend` once; the application should then
+    /// stop using the device stack and bring up the host stack with `host`. When switching back
+    /// to the device role the host driver must be dropped, the device is reinitialized and
+    /// enumerates as usual.
+    pub fn take_role_change(&self) -> Option<UsbRole> {
+        interrupt::free(|cs| self.role_change.borrow(cs).take())
+    }
+
+    /// Returns the (micro)frame number of the last SOF received from the host.
+    pub fn frame_number(&self) -> u16 {
+        self.ungated(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16
+        })
+    }
+
+    /// Enables or disables the start-of-frame interrupt.
+    ///
+    /// While enabled, `poll` is triggered on every SOF (every 1 ms, or every 125 µs at high
+    /// speed) and the received frame numbers are reported by `take_sof`. The setting is kept
+    /// across `enable`.
+    pub fn set_sof_enabled(&self, enabled: bool) {
+        self.ungated(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            self.sof_enabled.borrow(cs).set(enabled);
+            self.sof.borrow(cs).set(None);
+            write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
+            modify_reg!(otg_global, regs.global(), GINTMSK, SOFM: enabled as u32);
+        });
+    }
+
+    /// Returns the (micro)frame number of the latest SOF if one was received since the last call.
+    ///
+    /// Requires `set_sof_enabled`. SOFs that arrive before the previous one was taken are merged.
+    pub fn take_sof(&self) -> Option<u16> {
+        interrupt::free(|cs| self.sof.borrow(cs).take())
+    }
+
+    /// Returns `true` if VBUS is present, i.e. the device is plugged into a host.
+    ///
+    /// Always returns `true` if `UsbPeripheral::VBUS_SENSE` is `VbusSense::Disabled`.
+    pub fn is_vbus_present(&self) -> bool {
+        if USB::VBUS_SENSE == VbusSense::Disabled {
+            return true;
+        }
+
+        self.ungated(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            read_reg!(otg_global, regs.global(), GOTGCTL, BSVLD) != 0
+        })
+    }
+
+    /// Returns the last cable state change since the previous call.
+    ///
+    /// Requires VBUS sensing. When the cable is unplugged the device is disconnected and `poll`
+    /// reports `PollResult::Reset`, so the device stack and the classes start over from the
+    /// default state.
+    pub fn take_cable_event(&self) -> Option<CableEvent> {
+        interrupt::free(|cs| self.cable_event.borrow(cs).take())
+    }
+
+    /// Returns the OTG protocol events since the last call.
+    pub fn take_otg_events(&self) -> OtgEvents {
+        interrupt::free(|cs| self.otg_events.borrow(cs).take())
+    }
+
+    /// Asks the A-device to switch VBUS on using the Session Request Protocol.
+    ///
+    /// The result is reported by `take_otg_events`. Returns `Unsupported` if
+    /// `UsbPeripheral::SRP_CAPABLE` is not set and `InvalidState` if a session is already valid.
+    pub fn request_session(&self) -> Result<()> {
+        if !USB::SRP_CAPABLE {
+            return Err(UsbError::Unsupported);
+        }
+
+        self.ungated(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            if read_reg!(otg_global, regs.global(), GOTGCTL, BSVLD) != 0 {
+                return Err(UsbError::InvalidState);
+            }
+
+            modify_reg!(otg_global, regs.global(), GOTGCTL, SRQ: 1);
+            Ok(())
+        })
+    }
+
+    /// Sets the state of the `b_hnp_enable` feature set by the host.
+    ///
+    /// usb-device does not handle SET_FEATURE(b_hnp_enable), the application must accept the
+    /// request in a class and pass it on here. The feature is cleared by a bus reset. Returns
+    /// `Unsupported` if `UsbPeripheral::HNP_CAPABLE` is not set.
+    pub fn set_hnp_enabled(&self, enabled: bool) -> Result<()> {
+        if !USB::HNP_CAPABLE {
+            return Err(UsbError::Unsupported);
+        }
+
+        self.ungated(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            modify_reg!(otg_global, regs.global(), GOTGCTL, DHNPEN: enabled as u32);
+        });
+
+        Ok(())
+    }
+
+    /// Requests the host role using the Host Negotiation Protocol.
+    ///
+    /// The switch happens once the host suspends the bus; on success `take_otg_events` reports
+    /// `host_negotiation_success` and the role change is handled like an ID pin change. Returns
+    /// `InvalidState` if the host did not enable HNP.
+    pub fn request_host_role(&self) -> Result<()> {
+        if !USB::HNP_CAPABLE {
+            return Err(UsbError::Unsupported);
+        }
+
+        self.ungated(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            if read_reg!(otg_global, regs.global(), GOTGCTL, DHNPEN) == 0 {
+                return Err(UsbError::InvalidState);
+            }
+
+            modify_reg!(otg_global, regs.global(), GOTGCTL, HNPRQ: 1);
+            Ok(())
+        })
+    }
+
+    /// Allows the connected B-device to take the host role, for use by an A-device in the host
+    /// role after the device accepted SET_FEATURE(b_hnp_enable).
+    ///
+    /// The negotiation starts when the bus is suspended with `UsbHost::suspend_port`.
+    pub fn set_host_hnp_enabled(&self, enabled: bool) -> Result<()> {
+        if !USB::HNP_CAPABLE {
+            return Err(UsbError::Unsupported);
+        }
+
+        self.ungated(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            modify_reg!(otg_global, regs.global(), GOTGCTL, HSHNPEN: enabled as u32);
+        });
+
+        Ok(())
+    }
+
+    /// Returns a host driver for the peripheral while a dual-role core is in the host role.
+    ///
+    /// The driver is handed out once each time the core switches to the host role, further calls
+    /// return `None`. It must be enabled with `UsbHost::enable` before use and must not be used
+    /// after the core switched back to the device role.
+    pub fn host(&self) -> Option<UsbHost<USB>> {
+        if !USB::DUAL_ROLE || self.role() != UsbRole::Host {
+            return None;
+        }
+
+        interrupt::free(|cs| {
+            let taken = self.host_taken.borrow(cs).replace(true);
+            if taken { None } else { Some(UsbHost::new_dual_role()) }
+        })
+    }
+
+    /// Wakes up a suspended host by driving resume signalling on the bus.
+    ///
+    /// `remote_wakeup_enabled` is the state of the DEVICE_REMOTE_WAKEUP feature set by the host,
+    /// as returned by `UsbDevice::remote_wakeup_enabled`. `delay_ms` is called once while the
+    /// resume signalling is active and must block for the given number of milliseconds; it is
+    /// called outside of a critical section.
+    ///
+    /// The USB specification only allows remote wakeup after the bus has been idle for at least
+    /// 5 ms. Suspend is reported by the core after 3 ms of idle bus, so the caller must wait for
+    /// at least 2 more milliseconds after the device entered the `Suspend` state.
+    ///
+    /// Returns `InvalidState` if the host did not enable remote wakeup or the bus is not
+    /// suspended (`DSTS.SUSPSTS`).
+    pub fn remote_wakeup(&self, remote_wakeup_enabled: bool, delay_ms: impl FnOnce(u32)) -> Result<()> {
+        if !remote_wakeup_enabled {
+            return Err(UsbError::InvalidState);
+        }
+
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // The registers of the core can only be read with the clocks restored
+            self.exit_low_power(cs);
+
+            if read_reg!(otg_device, regs.device(), DSTS, SUSPSTS) == 0 {
+                return Err(UsbError::InvalidState);
+            }
+
+            modify_reg!(otg_device, regs.device(), DCTL, RWUSIG: 1);
+            Ok(())
+        })?;
+
+        delay_ms(REMOTE_WAKEUP_SIGNAL_MS);
+
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            modify_reg!(otg_device, regs.device(), DCTL, RWUSIG: 0);
+        });
+
+        Ok(())
+    }
+
+    /// Restores the clocks and the transceiver power after suspend.
+    ///
+    /// Only `PCGCCTL` is accessible while HCLK is gated, this must be called before any other
+    /// register of the core is accessed outside of `ungated`.
+    fn exit_low_power(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+
+        if read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK, GATEHCLK) == (0, 0) {
+            return;
+        }
+
+        // Restore HCLK first, then power up the transceiver before restarting the PHY clock
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 0);
+
+        if USB::SUSPEND_POWER_DOWN && USB::PHY_TYPE == PhyType::InternalFullSpeed {
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
+        }
+
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK: 0);
+    }
+
+    /// Runs `f` in a critical section with HCLK restored if it is gated while the bus is suspended.
+    ///
+    /// HCLK is gated again when `f` returns, only `poll` wakes up the suspended core.
+    fn ungated<R>(&self, f: impl FnOnce(&CriticalSection) -> R) -> R {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            let gated = read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK) != 0;
+            if gated {
+                modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 0);
+            }
+
+            let result = f(cs);
+
+            if gated {
+                modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 1);
+            }
+            result
+        })
+    }
+
+    /// Waits until the core switched to the role selected by the ID pin.
+    fn wait_for_role(&self, cs: &CriticalSection) -> UsbRole {
+        let regs = self.regs.borrow(cs);
+
+        if !USB::DUAL_ROLE {
+            return UsbRole::Device;
+        }
+
+        let host = read_reg!(otg_global, regs.global(), GOTGCTL, CIDSTS) == 0;
+        while read_reg!(otg_global, regs.global(), GINTSTS, CMOD) != host as u32 {}
+
+        if host { UsbRole::Host } else { UsbRole::Device }
+    }
+
+    /// Initializes the device mode registers and connects the device.
+    fn start_device(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+
+        let vbus_sense = USB::VBUS_SENSE != VbusSense::Disabled;
+        let otg = USB::SRP_CAPABLE || USB::HNP_CAPABLE;
+
+        // Configuring Vbus sense
+        match USB::VBUS_SENSE {
+            VbusSense::Disabled if USB::VBUS_DETECTION_BLOCK => {
+                modify_reg!(otg_global, regs.global(), GCCFG, VBDEN: 0);
+                modify_reg!(otg_global, regs.global(), GOTGCTL, BVALOEN: 1, BVALOVAL: 1);
+            }
+            VbusSense::Disabled => {
+                modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, NOVBUSSENS: 1);
+            }
+            VbusSense::BSessionValid => {
+                modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 1, NOVBUSSENS: 0);
+            }
+            VbusSense::VbusDetection => {
+                modify_reg!(otg_global, regs.global(), GOTGCTL, BVALOEN: 0, BVALOVAL: 0);
+                modify_reg!(otg_global, regs.global(), GCCFG, VBDEN: 1);
+            }
+        }
+
+        // Enable PHY clock
+        write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
+
+        // Soft disconnect device
+        modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+
+        // Setup USB speed [and frame interval]
+        match USB::PHY_TYPE {
+            PhyType::InternalFullSpeed => {
+                modify_reg!(otg_device, regs.device(), DCFG,
+                    DSPD: 0b11 // Device speed: Full speed
+                );
+            }
+            PhyType::InternalHighSpeed | PhyType::ExternalHighSpeed => {
+                modify_reg!(otg_device, regs.device(), DCFG,
+                    DSPD: 0b00 // Device speed: High speed
+                );
+            }
+        }
+
+        // unmask EP interrupts
+        write_reg!(otg_device, regs.device(), DIEPMSK, XFRCM: 1);
+        if self.dma {
+            write_reg!(otg_device, regs.device(), DOEPMSK, XFRCM: 1, STUPM: 1);
+        }
+
+        // Incomplete isochronous transfer interrupts are only needed with isochronous endpoints
+        let iso_in = self.endpoints_in.iter().any(|ep| ep.is_isochronous());
+        let iso_out = self.endpoints_out.iter().any(|ep| ep.is_isochronous());
+
+        // unmask core interrupts
+        write_reg!(otg_global, regs.global(), GINTMSK,
+            USBRST: 1, ENUMDNEM: 1,
+            USBSUSPM: 1, WUIM: 1,
+            IEPINT: 1,
+            IISOIXFRM: iso_in as u32, PXFRM_IISOOXFRM: iso_out as u32,
+            SRQIM: vbus_sense as u32, OTGINT: (vbus_sense || otg) as u32,
+            CIDSCHGM: USB::DUAL_ROLE as u32,
+            SOFM: self.sof_enabled.borrow(cs).get() as u32,
+            RXFLVLM: !self.dma as u32,
+            OEPINT: self.dma as u32
+        );
+
+        // clear pending interrupts
+        write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
+
+        if self.dma {
+            // INCR4 bursts
+            modify_reg!(otg_global, regs.global(), GAHBCFG, DMAEN: 1, HBSTLEN: 0b0011);
+        }
+
+        // unmask global interrupt
+        modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
+
+        // connect(true)
+        if USB::PHY_TYPE == PhyType::InternalFullSpeed {
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
+        }
+        if !vbus_sense || read_reg!(otg_global, regs.global(), GOTGCTL, BSVLD) != 0 {
+            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 0);
+        }
+    }
+
+    /// Disconnects the device and leaves the core to the host driver.
+    fn stop_device(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+
+        modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+        self.deconfigure_all(cs);
+
+        // Only watch the ID pin and the OTG protocol until the host driver is enabled
+        let otg = USB::SRP_CAPABLE || USB::HNP_CAPABLE;
+        modify_reg!(otg_global, regs.global(), GAHBCFG, DMAEN: 0);
+        write_reg!(otg_global, regs.global(), GINTMSK, CIDSCHGM: 1, OTGINT: otg as u32, SRQIM: otg as u32);
+        write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
+        modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
+    }
+
     pub fn configure_all(&self, cs: &CriticalSection) {
         let regs = self.regs.borrow(cs);
 
@@ -59,59 +648,40 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
         } else {
             self.endpoint_allocator.total_rx_buffer_size_words() as u32 + 20
         };
-        write_reg!(otg_global, regs.global, GRXFSIZ, rx_fifo_size);
+        write_reg!(otg_global, regs.global(), GRXFSIZ, rx_fifo_size);
         let mut fifo_top = rx_fifo_size;
 
         // Tx FIFO #0
         let fifo_size = cmp::max(self.endpoints_in[0].fifo_size_words(), 16);
 
-        #[cfg(feature = "fs")]
-        write_reg!(otg_global, regs.global, DIEPTXF0,
+        write_reg!(otg_global, regs.global(), DIEPTXF0,
             TX0FD: fifo_size,
             TX0FSA: fifo_top
         );
-        #[cfg(feature = "hs")]
-        write_reg!(otg_global, regs.global, GNPTXFSIZ,
-            TX0FD: fifo_size,
-            TX0FSA: fifo_top
-        );
-
-        fifo_top += fifo_size;
-
-        // Tx FIFO #1
-        let fifo_size = cmp::max(self.endpoints_in[1].fifo_size_words(), 16);
-        write_reg!(otg_global, regs.global, DIEPTXF1,
-            INEPTXFD: fifo_size,
-            INEPTXSA: fifo_top
-        );
-        fifo_top += fifo_size;
 
-        // Tx FIFO #2
-        let fifo_size = cmp::max(self.endpoints_in[2].fifo_size_words(), 16);
-        write_reg!(otg_global, regs.global, DIEPTXF2,
-            INEPTXFD: fifo_size,
-            INEPTXSA: fifo_top
-        );
         fifo_top += fifo_size;
 
-        // Tx FIFO #3
-        let fifo_size = cmp::max(self.endpoints_in[3].fifo_size_words(), 16);
-        write_reg!(otg_global, regs.global, DIEPTXF3,
-            INEPTXFD: fifo_size,
-            INEPTXSA: fifo_top
-        );
-        fifo_top += fifo_size;
+        // Tx FIFOs #1..#N
+        for ep in &self.endpoints_in[1..USB::ENDPOINT_COUNT] {
+            let fifo_size = cmp::max(ep.fifo_size_words(), 16);
+            let fifo = regs.dieptxf(ep.address().index());
+            write_reg!(otg_global_dieptxfx, fifo, DIEPTXFx,
+                INEPTXFD: fifo_size,
+                INEPTXSA: fifo_top
+            );
+            fifo_top += fifo_size;
+        }
 
-        assert!(fifo_top <= crate::ral::otg_fifo::FIFO_DEPTH_WORDS);
+        assert!(fifo_top as usize <= USB::FIFO_DEPTH_WORDS, "Endpoint FIFO allocation exceeds FIFO_DEPTH_WORDS");
 
         // Flush Rx & Tx FIFOs
-        modify_reg!(otg_global, regs.global, GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
-        while read_reg!(otg_global, regs.global, GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+        modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+        while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
 
         for ep in &self.endpoints_in {
             if ep.is_initialized() {
                 // enabling EP TX interrupt
-                modify_reg!(otg_device, regs.device, DAINTMSK, |v| v | (0x0001 << ep.address().index()));
+                modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | (0x0001 << ep.address().index()));
 
                 ep.configure(cs);
             }
@@ -119,9 +689,9 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
 
         for ep in &self.endpoints_out {
             if ep.is_initialized() {
-                if ep.address().index() == 0 {
-                    // enabling RX interrupt from EP0
-                    modify_reg!(otg_device, regs.device, DAINTMSK, |v| v | 0x00010000);
+                if ep.address().index() == 0 || self.dma {
+                    // enabling RX interrupt from EP0 (or from all OUT endpoints in DMA mode)
+                    modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | (0x00010000 << ep.address().index()));
                 }
 
                 ep.configure(cs);
@@ -133,13 +703,13 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
         let regs = self.regs.borrow(cs);
 
         // disable interrupts
-        modify_reg!(otg_device, regs.device, DAINTMSK, IEPM: 0, OEPM: 0);
+        modify_reg!(otg_device, regs.device(), DAINTMSK, IEPM: 0, OEPM: 0);
 
-        for ep in &self.endpoints_in {
+        for ep in &self.endpoints_in[..USB::ENDPOINT_COUNT] {
             ep.deconfigure(cs);
         }
 
-        for ep in &self.endpoints_out {
+        for ep in &self.endpoints_out[..USB::ENDPOINT_COUNT] {
             ep.deconfigure(cs);
         }
     }
@@ -181,14 +751,26 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
         _interval: u8) -> Result<EndpointAddress>
     {
         if ep_dir == UsbDirection::In {
-            let ep = find_free_endpoint(&mut self.endpoints_in, ep_addr)?;
+            let ep = find_free_endpoint(&mut self.endpoints_in[..USB::ENDPOINT_COUNT], ep_addr)?;
+
+            if self.dma {
+                let buffer = self.endpoint_allocator.allocate_tx_buffer(max_packet_size as usize)?;
+                ep.set_dma_buffer(buffer);
+            }
             ep.initialize(ep_type, max_packet_size);
 
             Ok(ep.address())
         } else {
-            let ep = find_free_endpoint(&mut self.endpoints_out, ep_addr)?;
+            let ep = find_free_endpoint(&mut self.endpoints_out[..USB::ENDPOINT_COUNT], ep_addr)?;
 
-            let buffer = self.endpoint_allocator.allocate_rx_buffer(max_packet_size as usize)?;
+            let buffer_size = if self.dma && ep.address().index() == 0 {
+                // Room for 3 back-to-back SETUP packets
+                cmp::max(max_packet_size as usize, 24)
+            } else {
+                max_packet_size as usize
+            };
+            let packets = self.out_queue_depths[ep.address().index()];
+            let buffer = self.endpoint_allocator.allocate_rx_buffer(buffer_size, packets)?;
             ep.initialize(ep_type, max_packet_size, buffer);
 
             Ok(ep.address())
@@ -203,58 +785,37 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
             let regs = self.regs.borrow(cs);
 
             // Wait for AHB ready
-            while read_reg!(otg_global, regs.global, GRSTCTL, AHBIDL) == 0 {}
-
-            // Configure OTG as device
-            #[cfg(feature = "fs")]
-            modify_reg!(otg_global, regs.global, GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                TRDT: 0x6, // ??? USB turnaround time
-                FDMOD: 1 // Force device mode
-            );
-            #[cfg(feature = "hs")]
-            modify_reg!(otg_global, regs.global, GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                TRDT: 0x9, // ??? USB turnaround time
-                TOCAL: 0x1,
-                FDMOD: 1, // Force device mode
-                PHYSEL: 1
-            );
-
-            // Configuring Vbus sense and SOF output
-            //write_reg!(otg_global, regs.global, GCCFG, VBUSBSEN: 1);
-            write_reg!(otg_global, regs.global, GCCFG, 1 << 21); // set NOVBUSSENS
-
-            // Enable PHY clock
-            write_reg!(otg_pwrclk, regs.pwrclk, PCGCCTL, 0);
-
-            // Soft disconnect device
-            modify_reg!(otg_device, regs.device, DCTL, SDIS: 1);
-
-            // Setup USB FS speed [and frame interval]
-            modify_reg!(otg_device, regs.device, DCFG,
-                DSPD: 0b11 // Device speed: Full speed
-            );
-
-            // unmask EP interrupts
-            write_reg!(otg_device, regs.device, DIEPMSK, XFRCM: 1);
+            while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
 
-            // unmask core interrupts
-            write_reg!(otg_global, regs.global, GINTMSK,
-                USBRST: 1, ENUMDNEM: 1,
-                USBSUSPM: 1, WUIM: 1,
-                IEPINT: 1, RXFLVLM: 1
-            );
+            // Select the PHY
+            phy::configure::<USB>(*regs);
 
-            // clear pending interrupts
-            write_reg!(otg_global, regs.global, GINTSTS, 0xffffffff);
+            assert!(!USB::HNP_CAPABLE || USB::DUAL_ROLE, "HNP requires dual-role operation");
 
-            // unmask global interrupt
-            modify_reg!(otg_global, regs.global, GAHBCFG, GINT: 1);
+            if USB::DUAL_ROLE {
+                // The role follows the ID pin
+                modify_reg!(otg_global, regs.global(), GUSBCFG,
+                    SRPCAP: USB::SRP_CAPABLE as u32,
+                    HNPCAP: USB::HNP_CAPABLE as u32,
+                    FHMOD: 0,
+                    FDMOD: 0
+                );
+            } else {
+                // Configure OTG as device
+                modify_reg!(otg_global, regs.global(), GUSBCFG,
+                    SRPCAP: USB::SRP_CAPABLE as u32,
+                    HNPCAP: 0,
+                    FDMOD: 1 // Force device mode
+                );
+            }
 
-            // connect(true)
-            modify_reg!(otg_global, regs.global, GCCFG, PWRDWN: 1);
-            modify_reg!(otg_device, regs.device, DCTL, SDIS: 0);
+            let role = self.wait_for_role(cs);
+            self.role.borrow(cs).set(role);
+            if role == UsbRole::Device {
+                self.start_device(cs);
+            } else {
+                self.stop_device(cs);
+            }
         });
     }
 
@@ -264,95 +825,254 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
 
             self.configure_all(cs);
 
-            modify_reg!(otg_device, regs.device, DCFG, DAD: 0);
+            modify_reg!(otg_device, regs.device(), DCFG, DAD: 0);
         });
     }
 
     fn set_device_address(&self, addr: u8) {
-        interrupt::free(|cs| {
+        self.ungated(|cs| {
             let regs = self.regs.borrow(cs);
 
-            modify_reg!(otg_device, regs.device, DCFG, DAD: addr as u32);
+            modify_reg!(otg_device, regs.device(), DCFG, DAD: addr as u32);
         });
     }
 
     fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
-        if !ep_addr.is_in() || ep_addr.index() >= 4 {
+        if !ep_addr.is_in() || ep_addr.index() >= USB::ENDPOINT_COUNT {
             return Err(UsbError::InvalidEndpoint);
         }
 
-        self.endpoints_in[ep_addr.index()].write(buf).map(|_| buf.len())
+        self.ungated(|_| self.endpoints_in[ep_addr.index()].write(buf)).map(|_| buf.len())
     }
 
     fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
-        if !ep_addr.is_out() || ep_addr.index() >= 4 {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
             return Err(UsbError::InvalidEndpoint);
         }
 
-        self.endpoints_out[ep_addr.index()].read(buf)
+        self.ungated(|_| self.endpoints_out[ep_addr.index()].read(buf))
     }
 
     fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
-        if ep_addr.index() >= 4 {
+        if ep_addr.index() >= USB::ENDPOINT_COUNT {
             return;
         }
 
-        if ep_addr.is_in() {
-            self.endpoints_in[ep_addr.index()].set_stalled(stalled)
-        } else {
-            self.endpoints_out[ep_addr.index()].set_stalled(stalled)
-        }
+        self.ungated(|_| {
+            if ep_addr.is_in() {
+                self.endpoints_in[ep_addr.index()].set_stalled(stalled)
+            } else {
+                self.endpoints_out[ep_addr.index()].set_stalled(stalled)
+            }
+        })
     }
 
     fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
-        if ep_addr.index() >= 4 {
+        if ep_addr.index() >= USB::ENDPOINT_COUNT {
             return true;
         }
 
-        if ep_addr.is_in() {
-            self.endpoints_in[ep_addr.index()].is_stalled()
-        } else {
-            self.endpoints_out[ep_addr.index()].is_stalled()
-        }
+        self.ungated(|_| {
+            if ep_addr.is_in() {
+                self.endpoints_in[ep_addr.index()].is_stalled()
+            } else {
+                self.endpoints_out[ep_addr.index()].is_stalled()
+            }
+        })
     }
 
     fn suspend(&self) {
-        // Nothing to do here?
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            if read_reg!(otg_device, regs.device(), DSTS, SUSPSTS) == 0 {
+                return;
+            }
+
+            if USB::SUSPEND_POWER_DOWN && USB::PHY_TYPE == PhyType::InternalFullSpeed {
+                modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+            }
+
+            // Stop the PHY clock first, then gate HCLK
+            modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK: 1);
+            modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 1);
+        });
     }
 
     fn resume(&self) {
-        // Nothing to do here?
+        interrupt::free(|cs| {
+            self.exit_low_power(cs);
+        });
     }
 
     fn poll(&self) -> PollResult {
         interrupt::free(|cs| {
             let regs = self.regs.borrow(cs);
 
-            let (wakeup, suspend, enum_done, reset, iep, rxflvl) = read_reg!(otg_global, regs.global, GINTSTS,
-                WKUPINT, USBSUSP, ENUMDNE, USBRST, IEPINT, RXFLVL
+            if read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK) != 0 {
+                // Any unmasked interrupt wakes up the suspended core, e.g. a resume, a reset or an
+                // unplugged cable. The clocks stay gated otherwise.
+                let pending = self.ungated(|_| {
+                    read_reg!(otg_global, regs.global(), GINTSTS) & read_reg!(otg_global, regs.global(), GINTMSK) != 0
+                });
+                if !pending {
+                    return PollResult::None;
+                }
+                self.exit_low_power(cs);
+            }
+
+            let (wakeup, suspend, enum_done, reset, iep, oep, rxflvl, iso_in, iso_out) = read_reg!(otg_global, regs.global(), GINTSTS,
+                WKUPINT, USBSUSP, ENUMDNE, USBRST, IEPINT, OEPINT, RXFLVL, IISOIXFR, IPXFR_INCOMPISOOUT
             );
+            let (session_request, otg) = read_reg!(otg_global, regs.global(), GINTSTS, SRQINT, OTGINT);
+
+            let host_mode = read_reg!(otg_global, regs.global(), GINTSTS, CMOD) != 0;
+            let mut detached = false;
+
+            if session_request != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, SRQINT: 1);
+
+                if host_mode {
+                    let mut events = self.otg_events.borrow(cs).get();
+                    events.session_request_detected = true;
+                    self.otg_events.borrow(cs).set(events);
+                } else if USB::VBUS_SENSE != VbusSense::Disabled {
+                    // VBUS is valid, connect the pull-up
+                    modify_reg!(otg_device, regs.device(), DCTL, SDIS: 0);
+                    self.cable_event.borrow(cs).set(Some(CableEvent::Attached));
+                }
+            }
+
+            if otg != 0 {
+                use otg_global::GOTGINT;
+
+                let otg_status = read_reg!(otg_global, regs.global(), GOTGINT);
+                write_reg!(otg_global, regs.global(), GOTGINT, otg_status);
+
+                let (session_request_success, host_negotiation_success) = read_reg!(otg_global, regs.global(), GOTGCTL, SRQSCS, HNGSCS);
+                let mut events = self.otg_events.borrow(cs).get();
+
+                if otg_status & GOTGINT::SEDET::mask != 0 {
+                    events.session_end = true;
+
+                    // VBUS is gone, disconnect the pull-up
+                    if !host_mode && USB::VBUS_SENSE != VbusSense::Disabled {
+                        modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+                        self.cable_event.borrow(cs).set(Some(CableEvent::Detached));
+                        detached = true;
+                    }
+                }
+                if otg_status & GOTGINT::SRSSCHG::mask != 0 {
+                    events.session_request_success |= session_request_success != 0;
+                    events.session_request_failure |= session_request_success == 0;
+                    modify_reg!(otg_global, regs.global(), GOTGCTL, SRQ: 0);
+                }
+                if otg_status & GOTGINT::HNSSCHG::mask != 0 {
+                    events.host_negotiation_success |= host_negotiation_success != 0;
+                    events.host_negotiation_failure |= host_negotiation_success == 0;
+                    modify_reg!(otg_global, regs.global(), GOTGCTL, HNPRQ: 0);
+                }
+                if otg_status & GOTGINT::HNGDET::mask != 0 {
+                    events.host_negotiation_detected = true;
+                }
+                if otg_status & GOTGINT::ADTOCHG::mask != 0 {
+                    events.a_device_timeout = true;
+                }
+
+                self.otg_events.borrow(cs).set(events);
+            }
+
+            if USB::DUAL_ROLE {
+                // The role changes with the ID pin or after host negotiation
+                if read_reg!(otg_global, regs.global(), GINTSTS, CIDSCHG) != 0 {
+                    write_reg!(otg_global, regs.global(), GINTSTS, CIDSCHG: 1);
+
+                    let id_host = read_reg!(otg_global, regs.global(), GOTGCTL, CIDSTS) == 0;
+                    if host_mode != id_host {
+                        // The core switches its mode some time after the ID pin changed, without
+                        // an interrupt. The switch is finished by a later poll.
+                        return PollResult::None;
+                    }
+                }
+
+                let role = if host_mode { UsbRole::Host } else { UsbRole::Device };
+
+                if role != self.role.borrow(cs).get() {
+                    self.role.borrow(cs).set(role);
+                    self.role_change.borrow(cs).set(Some(role));
+                    self.host_taken.borrow(cs).set(false);
+
+                    return match role {
+                        UsbRole::Device => {
+                            self.start_device(cs);
+                            PollResult::None
+                        }
+                        UsbRole::Host => {
+                            self.stop_device(cs);
+                            PollResult::Suspend
+                        }
+                    };
+                }
+
+                // The core is driven by the host driver
+                if role == UsbRole::Host {
+                    return PollResult::None;
+                }
+            }
+
+            if self.sof_enabled.borrow(cs).get() && read_reg!(otg_global, regs.global(), GINTSTS, SOF) != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
+
+                let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16;
+                self.sof.borrow(cs).set(Some(frame_number));
+            }
+
+            if detached {
+                // Drop everything in flight, the device stack starts over from the default state
+                self.deconfigure_all(cs);
+
+                modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+                while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+
+                write_reg!(otg_global, regs.global(), GINTSTS,
+                    USBRST: 1, ENUMDNE: 1, USBSUSP: 1, WKUPINT: 1, IISOIXFR: 1, IPXFR_INCOMPISOOUT: 1
+                );
+
+                return PollResult::Reset;
+            }
 
             if reset != 0 {
-                write_reg!(otg_global, regs.global, GINTSTS, USBRST: 1);
+                write_reg!(otg_global, regs.global(), GINTSTS, USBRST: 1);
+
+                if USB::HNP_CAPABLE {
+                    // b_hnp_enable is cleared by a bus reset
+                    modify_reg!(otg_global, regs.global(), GOTGCTL, DHNPEN: 0);
+                }
 
                 self.deconfigure_all(cs);
 
                 // Flush RX
-                modify_reg!(otg_global, regs.global, GRSTCTL, RXFFLSH: 1);
-                while read_reg!(otg_global, regs.global, GRSTCTL, RXFFLSH) == 1 {}
+                modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1);
+                while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH) == 1 {}
+
+                if enum_done == 0 {
+                    // The flags read above are stale after the flush, the remaining ones are
+                    // handled by the next poll
+                    return PollResult::None;
+                }
             }
 
             if enum_done != 0 {
-                write_reg!(otg_global, regs.global, GINTSTS, ENUMDNE: 1);
+                write_reg!(otg_global, regs.global(), GINTSTS, ENUMDNE: 1);
 
                 PollResult::Reset
             } else if wakeup != 0 {
                 // Clear the interrupt
-                write_reg!(otg_global, regs.global, GINTSTS, WKUPINT: 1);
+                write_reg!(otg_global, regs.global(), GINTSTS, WKUPINT: 1);
 
                 PollResult::Resume
             } else if suspend != 0 {
-                write_reg!(otg_global, regs.global, GINTSTS, USBSUSP: 1);
+                write_reg!(otg_global, regs.global(), GINTSTS, USBSUSP: 1);
 
                 PollResult::Suspend
             } else {
@@ -360,43 +1080,94 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
                 let mut ep_in_complete = 0;
                 let mut ep_setup = 0;
 
-                use crate::ral::{endpoint_in, endpoint_out};
+                use crate::ral::endpoint_in;
 
-                // RXFLVL & IEPINT flags are read-only, there is no need to clear them
-                if rxflvl != 0 {
-                    let (epnum, data_size, status) = read_reg!(otg_global, regs.global, GRXSTSR, EPNUM, BCNT, PKTSTS);
-                    match status {
-                        0x02 => { // OUT received
-                            ep_out |= 1 << epnum;
-                        }
-                        0x06 => { // SETUP received
-                            // flushing TX if something stuck in control endpoint
-                            let ep = endpoint_in::instance(epnum as usize);
-                            if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
-                                modify_reg!(otg_global, regs.global, GRSTCTL, TXFNUM: epnum, TXFFLSH: 1);
-                                while read_reg!(otg_global, regs.global, GRSTCTL, TXFFLSH) == 1 {}
-                            }
-                            ep_setup |= 1 << epnum;
-                        }
-                        0x03 | 0x04 => { // OUT completed | SETUP completed
-                            let ep = endpoint_out::instance(epnum as usize);
-                            modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
-                            read_reg!(otg_global, regs.global, GRXSTSP); // pop GRXSTSP
+                if iso_in != 0 || iso_out != 0 {
+                    write_reg!(otg_global, regs.global(), GINTSTS, IISOIXFR: iso_in, IPXFR_INCOMPISOOUT: iso_out);
+
+                    let mut incomplete = self.iso_incomplete.borrow(cs).get();
+                    for ep in &self.endpoints_in {
+                        if iso_in != 0 && ep.reschedule_isochronous() {
+                            incomplete.ep_in |= 1 << ep.address().index();
                         }
-                        _ => {
-                            read_reg!(otg_global, regs.global, GRXSTSP); // pop GRXSTSP
+                    }
+                    for ep in &self.endpoints_out {
+                        if iso_out != 0 && ep.reschedule_isochronous() {
+                            incomplete.ep_out |= 1 << ep.address().index();
                         }
                     }
+                    self.iso_incomplete.borrow(cs).set(incomplete);
+                }
+
+                // RXFLVL, IEPINT & OEPINT flags are read-only, there is no need to clear them
+                if rxflvl != 0 && !self.dma {
+                    let (epnum, data_size, status) = read_reg!(otg_global, regs.global(), GRXSTSR, EPNUM, BCNT, PKTSTS);
+                    let ep = &self.endpoints_out[epnum as usize];
 
-                    if status == 0x02 || status == 0x06 {
-                        let ep = &self.endpoints_out[epnum as usize];
+                    if (status == 0x02 || status == 0x03) && ep.has_transfer_buffer(cs) {
+                        read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
 
-                        let mut buffer = ep.buffer.borrow(cs).borrow_mut();
-                        if buffer.state() == EndpointBufferState::Empty {
-                            read_reg!(otg_global, regs.global, GRXSTSP); // pop GRXSTSP
+                        if status == 0x02 {
+                            ep.receive_transfer_packet(cs, data_size as usize);
+                        } else {
+                            ep.complete_transfer(cs);
+                        }
+                    } else {
+                        match status {
+                            0x02 => { // OUT received
+                                ep_out |= 1 << epnum;
+                            }
+                            0x06 => { // SETUP received
+                                // flushing TX if something stuck in control endpoint
+                                let ep = regs.endpoint_in(epnum as usize);
+                                if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
+                                    modify_reg!(otg_global, regs.global(), GRSTCTL, TXFNUM: epnum, TXFFLSH: 1);
+                                    while read_reg!(otg_global, regs.global(), GRSTCTL, TXFFLSH) == 1 {}
+                                }
+                                ep_setup |= 1 << epnum;
+                            }
+                            0x03 | 0x04 => { // OUT completed | SETUP completed
+                                if ep.is_initialized() {
+                                    ep.receive_next(cs);
+                                }
+                                read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+                            }
+                            _ => {
+                                read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+                            }
+                        }
 
+                        if status == 0x02 || status == 0x06 {
+                            let mut buffer = ep.buffer.borrow(cs).borrow_mut();
                             let is_setup = status == 0x06;
-                            buffer.fill_from_fifo(data_size as u16, is_setup).ok();
+                            if is_setup {
+                                // A SETUP packet starts a new control transfer, the unread data of
+                                // the previous one is stale and must not block the RX FIFO
+                                buffer.clear();
+                            }
+                            if !buffer.is_full() {
+                                read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+
+                                buffer.fill_from_fifo(*regs, data_size as u16, is_setup).ok();
+                            }
+                        }
+                    }
+                }
+
+                if oep != 0 && self.dma {
+                    let daint = read_reg!(otg_device, regs.device(), DAINT, OEPINT);
+                    for ep in &self.endpoints_out {
+                        if ep.is_initialized() && daint & (1 << ep.address().index()) != 0 {
+                            ep.handle_dma_interrupt(cs);
+
+                            if ep.address().index() == 0 && ep.buffer_state() == EndpointBufferState::DataSetup {
+                                // flushing TX if something stuck in control endpoint
+                                let ep_regs = regs.endpoint_in(0);
+                                if read_reg!(endpoint_in, ep_regs, DIEPTSIZ, PKTCNT) != 0 {
+                                    modify_reg!(otg_global, regs.global(), GRSTCTL, TXFNUM: 0, TXFFLSH: 1);
+                                    while read_reg!(otg_global, regs.global(), GRSTCTL, TXFFLSH) == 1 {}
+                                }
+                            }
                         }
                     }
                 }
@@ -404,7 +1175,10 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
                 if iep != 0 {
                     for ep in &self.endpoints_in {
                         if ep.is_initialized() {
-                            let ep_regs = endpoint_in::instance(ep.address().index());
+                            let ep_regs = regs.endpoint_in(ep.address().index());
+                            if !self.dma && read_reg!(endpoint_in, ep_regs, DIEPINT, TXFE) != 0 {
+                                ep.handle_fifo_empty(cs);
+                            }
                             if read_reg!(endpoint_in, ep_regs, DIEPINT, XFRC) != 0 {
                                 write_reg!(endpoint_in, ep_regs, DIEPINT, XFRC: 1);
                                 ep_in_complete |= 1 << ep.address().index();
@@ -1,25 +1,31 @@
 use usb_device::{Result, UsbError};
 use usb_device::endpoint::{EndpointType, EndpointAddress};
 use crate::endpoint_memory::{EndpointBuffer, EndpointBufferState};
-use crate::ral::{read_reg, write_reg, modify_reg, endpoint_in, endpoint_out, endpoint0_out};
-use crate::target::fifo_write;
+use crate::ral::{read_reg, write_reg, modify_reg, otg_device, endpoint_in, endpoint_out, endpoint0_out};
+use crate::target::{fifo_write, UsbRegisters};
 use crate::target::interrupt::{self, CriticalSection, Mutex};
 use core::ops::{Deref, DerefMut};
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
+use core::cmp;
+use core::sync::atomic::{fence, Ordering};
 
 /// Arbitrates access to the endpoint-specific registers and packet buffer memory.
 pub struct Endpoint {
     ep_type: Option<EndpointType>,
     max_packet_size: u16,
     address: EndpointAddress,
+    usb: UsbRegisters,
+    dma: bool,
 }
 
 impl Endpoint {
-    pub fn new(address: EndpointAddress) -> Endpoint {
+    pub fn new(usb: UsbRegisters, address: EndpointAddress, dma: bool) -> Endpoint {
         Endpoint {
             ep_type: None,
             max_packet_size: 0,
             address,
+            usb,
+            dma,
         }
     }
 
@@ -37,6 +43,65 @@ impl Endpoint {
         self.max_packet_size = max_packet_size;
     }
 
+    pub fn is_isochronous(&self) -> bool {
+        matches!(self.ep_type, Some(EndpointType::Isochronous))
+    }
+
+    /// Returns the parity of the current (micro)frame number
+    fn frame_parity(&self) -> u32 {
+        read_reg!(otg_device, self.usb.device(), DSTS, FNSOF) & 1
+    }
+
+    /// Enables the endpoint, scheduling isochronous endpoints for the next (micro)frame.
+    pub fn enable(&self) {
+        self.enable_with_nak(false);
+    }
+
+    /// Enables the endpoint, keeping an OUT endpoint NAKing all packets if `nak` is set.
+    fn enable_with_nak(&self, nak: bool) {
+        let (odd, even) = if self.is_isochronous() {
+            let odd_now = self.frame_parity();
+            (odd_now ^ 1, odd_now)
+        } else {
+            (0, 0)
+        };
+
+        if self.address.is_in() {
+            let ep = self.usb.endpoint_in(self.address.index());
+            modify_reg!(endpoint_in, ep, DIEPCTL, SODDFRM: odd, SD0PID_SEVNFRM: even, CNAK: 1, EPENA: 1);
+        } else {
+            let ep = self.usb.endpoint_out(self.address.index());
+            modify_reg!(endpoint_out, ep, DOEPCTL, SODDFRM: odd, SD0PID_SEVNFRM: even, CNAK: !nak as u32, SNAK: nak as u32, EPENA: 1);
+        }
+    }
+
+    /// Moves a pending isochronous transfer that missed its (micro)frame to the next one.
+    ///
+    /// Returns `true` if a transfer was pending for the current (micro)frame.
+    pub fn reschedule_isochronous(&self) -> bool {
+        if !self.is_isochronous() {
+            return false;
+        }
+
+        let odd_now = self.frame_parity();
+        if self.address.is_in() {
+            let ep = self.usb.endpoint_in(self.address.index());
+            let (enabled, odd) = read_reg!(endpoint_in, ep, DIEPCTL, EPENA, EONUM_DPID);
+            if enabled != 0 && odd == odd_now {
+                modify_reg!(endpoint_in, ep, DIEPCTL, SODDFRM: odd_now ^ 1, SD0PID_SEVNFRM: odd_now);
+                return true;
+            }
+        } else {
+            let ep = self.usb.endpoint_out(self.address.index());
+            let (enabled, odd) = read_reg!(endpoint_out, ep, DOEPCTL, EPENA, EONUM_DPID);
+            if enabled != 0 && odd == odd_now {
+                modify_reg!(endpoint_out, ep, DOEPCTL, SODDFRM: odd_now ^ 1, SD0PID_SEVNFRM: odd_now);
+                return true;
+            }
+        }
+        false
+    }
+
     pub fn set_stalled(&self, stalled: bool) {
         if !self.is_initialized() {
             return;
@@ -47,10 +112,10 @@ impl Endpoint {
             }
 
             if self.address.is_in() {
-                let ep = endpoint_in::instance(self.address.index());
+                let ep = self.usb.endpoint_in(self.address.index());
                 modify_reg!(endpoint_in, ep, DIEPCTL, STALL: stalled as u32);
             } else {
-                let ep = endpoint_out::instance(self.address.index());
+                let ep = self.usb.endpoint_out(self.address.index());
                 modify_reg!(endpoint_out, ep, DOEPCTL, STALL: stalled as u32);
             }
         })
@@ -58,10 +123,10 @@ impl Endpoint {
 
     pub fn is_stalled(&self) -> bool {
         let stall = if self.address.is_in() {
-            let ep = endpoint_in::instance(self.address.index());
+            let ep = self.usb.endpoint_in(self.address.index());
             read_reg!(endpoint_in, ep, DIEPCTL, STALL)
         } else {
-            let ep = endpoint_out::instance(self.address.index());
+            let ep = self.usb.endpoint_out(self.address.index());
             read_reg!(endpoint_out, ep, DOEPCTL, STALL)
         };
         stall != 0
@@ -78,19 +143,24 @@ impl Endpoint {
             };
 
             if self.address.is_in() {
-                let regs = endpoint_in::instance(self.address.index());
+                let regs = self.usb.endpoint_in(self.address.index());
 
                 write_reg!(endpoint_in, regs, DIEPCTL, MPSIZ: mpsiz as u32, SNAK: 1);
 
                 write_reg!(endpoint_in, regs, DIEPTSIZ, PKTCNT: 0, XFRSIZ: self.max_packet_size as u32);
             } else {
-                let regs = endpoint0_out::instance();
-                write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 1, PKTCNT: 1, XFRSIZ: self.max_packet_size as u32);
-                modify_reg!(endpoint0_out, regs, DOEPCTL0, MPSIZ: mpsiz as u32, EPENA: 1, CNAK: 1);
+                let regs = self.usb.endpoint0_out();
+                if self.dma {
+                    // The endpoint is enabled once the DMA buffer is assigned
+                    modify_reg!(endpoint0_out, regs, DOEPCTL0, MPSIZ: mpsiz as u32);
+                } else {
+                    write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 1, PKTCNT: 1, XFRSIZ: self.max_packet_size as u32);
+                    modify_reg!(endpoint0_out, regs, DOEPCTL0, MPSIZ: mpsiz as u32, EPENA: 1, CNAK: 1);
+                }
             }
         } else {
             if self.address.is_in() {
-                let regs = endpoint_in::instance(self.address.index());
+                let regs = self.usb.endpoint_in(self.address.index());
                 write_reg!(endpoint_in, regs, DIEPCTL,
                     SNAK: 1,
                     USBAEP: 1,
@@ -100,11 +170,10 @@ impl Endpoint {
                     MPSIZ: self.max_packet_size as u32
                 );
             } else {
-                let regs = endpoint_out::instance(self.address.index());
+                // The endpoint is enabled by `EndpointOut::configure`
+                let regs = self.usb.endpoint_out(self.address.index());
                 write_reg!(endpoint_out, regs, DOEPCTL,
                     SD0PID_SEVNFRM: 1,
-                    CNAK: 1,
-                    EPENA: 1,
                     USBAEP: 1,
                     EPTYP: self.ep_type.unwrap() as u32,
                     MPSIZ: self.max_packet_size as u32
@@ -115,7 +184,7 @@ impl Endpoint {
 
     pub fn deconfigure(&self, _cs: &CriticalSection) {
         if self.address.is_in() {
-            let regs = endpoint_in::instance(self.address.index());
+            let regs = self.usb.endpoint_in(self.address.index());
 
             // deactivating endpoint
             modify_reg!(endpoint_in, regs, DIEPCTL, USBAEP: 0);
@@ -132,7 +201,7 @@ impl Endpoint {
 
             // TODO: deconfiguring TX FIFO
         } else {
-            let regs = endpoint_out::instance(self.address.index());
+            let regs = self.usb.endpoint_out(self.address.index());
 
             // deactivating endpoint
             modify_reg!(endpoint_out, regs, DOEPCTL, USBAEP: 0);
@@ -149,19 +218,64 @@ impl Endpoint {
 }
 
 
+/// State of a multi-packet IN transfer.
+#[derive(Default)]
+struct InTransfer {
+    buffer: Option<EndpointBuffer>,
+    length: usize,
+    offset: usize,
+}
+
 pub struct EndpointIn {
     common: Endpoint,
+    buffer: Mutex<RefCell<Option<EndpointBuffer>>>,
+    transfer: Mutex<RefCell<InTransfer>>,
+    high_speed: bool,
 }
 
 impl EndpointIn {
-    pub fn new(address: EndpointAddress) -> EndpointIn {
+    pub fn new(usb: UsbRegisters, address: EndpointAddress, dma: bool, high_speed: bool) -> EndpointIn {
         EndpointIn {
-            common: Endpoint::new(address),
+            common: Endpoint::new(usb, address, dma),
+            buffer: Mutex::new(RefCell::new(None)),
+            transfer: Mutex::new(RefCell::new(InTransfer::default())),
+            high_speed,
         }
     }
 
+    /// Value of `DIEPTSIZ.MCNT`, the field is reserved on Full Speed cores
+    fn multi_count(&self) -> u32 {
+        self.high_speed as u32
+    }
+
+    /// Assigns the transmit buffer used in DMA mode
+    pub fn set_dma_buffer(&mut self, buffer: EndpointBuffer) {
+        self.buffer = Mutex::new(RefCell::new(Some(buffer)));
+    }
+
+    /// Assigns the buffer used to stage multi-packet transfers
+    pub fn set_transfer_buffer(&self, buffer: EndpointBuffer) -> Result<()> {
+        if !matches!(self.ep_type, Some(EndpointType::Bulk) | Some(EndpointType::Interrupt)) {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        interrupt::free(|cs| {
+            let ep = self.usb.endpoint_in(self.address.index());
+            if read_reg!(endpoint_in, ep, DIEPCTL, EPENA) != 0 {
+                return Err(UsbError::WouldBlock);
+            }
+
+            self.transfer.borrow(cs).replace(InTransfer {
+                buffer: Some(buffer),
+                length: 0,
+                offset: 0,
+            });
+            Ok(())
+        })
+    }
+
     pub fn write(&self, buf: &[u8]) -> Result<()> {
-        let ep = endpoint_in::instance(self.address.index());
+        let ep = self.usb.endpoint_in(self.address.index());
         if !self.is_initialized() {
             return Err(UsbError::InvalidEndpoint);
         }
@@ -170,48 +284,252 @@ impl EndpointIn {
         }
 
         if buf.len() > self.max_packet_size as usize {
-            return Err(UsbError::BufferOverflow);
+            return self.write_transfer(buf);
+        }
+
+        if self.dma {
+            return self.write_dma(buf);
         }
 
         if !buf.is_empty() {
             // Check for FIFO free space
-            let size_words = (buf.len() + 3) / 4;
+            let size_words = buf.len().div_ceil(4);
             if size_words > read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize {
                 return Err(UsbError::WouldBlock);
             }
         }
 
-        #[cfg(feature = "fs")]
-        write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: 1, XFRSIZ: buf.len() as u32);
-        #[cfg(feature = "hs")]
-        write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: 1, PKTCNT: 1, XFRSIZ: buf.len() as u32);
+        write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: self.multi_count(), PKTCNT: 1, XFRSIZ: buf.len() as u32);
+
+        self.enable();
+
+        fifo_write(self.usb, self.address.index(), buf);
+
+        Ok(())
+    }
+
+    fn write_dma(&self, buf: &[u8]) -> Result<()> {
+        let ep = self.usb.endpoint_in(self.address.index());
+
+        let address = interrupt::free(|cs| {
+            let buffer = self.buffer.borrow(cs).borrow();
+            let buffer = buffer.as_ref().ok_or(UsbError::InvalidEndpoint)?;
+            buffer.write_packet(buf)?;
+            Ok(buffer.address())
+        })?;
 
-        modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
+        write_reg!(endpoint_in, ep, DIEPDMA, address);
+        write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: self.multi_count(), PKTCNT: 1, XFRSIZ: buf.len() as u32);
 
-        fifo_write(self.address.index(), buf);
+        // Make sure the buffer is written before the core starts fetching it
+        fence(Ordering::SeqCst);
+
+        self.enable();
 
         Ok(())
     }
 
+    fn write_transfer(&self, buf: &[u8]) -> Result<()> {
+        if self.address.index() == 0 {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let ep = self.usb.endpoint_in(self.address.index());
+
+        interrupt::free(|cs| {
+            let mut transfer = self.transfer.borrow(cs).borrow_mut();
+            let buffer = transfer.buffer.as_ref().ok_or(UsbError::BufferOverflow)?;
+
+            buffer.write_packet(buf)?;
+
+            let packets = buf.len().div_ceil(self.max_packet_size as usize);
+            if self.dma {
+                write_reg!(endpoint_in, ep, DIEPDMA, buffer.address());
+            }
+            write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: self.multi_count(), PKTCNT: packets as u32, XFRSIZ: buf.len() as u32);
+
+            // Make sure the buffer is written before the core starts fetching it
+            fence(Ordering::SeqCst);
+
+            self.enable();
+
+            if !self.dma {
+                transfer.length = buf.len();
+                transfer.offset = 0;
+                self.fill_fifo(&mut transfer);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Pushes as many packets of the pending transfer into the TX FIFO as it can hold.
+    fn fill_fifo(&self, transfer: &mut InTransfer) {
+        let ep = self.usb.endpoint_in(self.address.index());
+        let InTransfer { buffer, length, offset } = transfer;
+
+        if let Some(buffer) = buffer {
+            while *offset < *length {
+                let size = cmp::min(self.max_packet_size as usize, *length - *offset);
+                if size.div_ceil(4) > read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize {
+                    break;
+                }
+
+                buffer.write_to_fifo(self.usb, self.address.index(), *offset, size);
+                *offset += size;
+            }
+        }
+
+        // Get notified when there is room for the rest of the transfer
+        let mask = 1 << self.address.index();
+        if *offset < *length {
+            modify_reg!(otg_device, self.usb.device(), DIEPEMPMSK, |v| v | mask);
+        } else {
+            modify_reg!(otg_device, self.usb.device(), DIEPEMPMSK, |v| v & !mask);
+        }
+    }
+
+    /// Handles the TX FIFO empty interrupt, continuing the pending transfer.
+    pub fn handle_fifo_empty(&self, cs: &CriticalSection) {
+        let mut transfer = self.transfer.borrow(cs).borrow_mut();
+        if transfer.offset < transfer.length {
+            self.fill_fifo(&mut transfer);
+        }
+    }
+
+    pub fn deconfigure(&self, cs: &CriticalSection) {
+        Endpoint::deconfigure(self, cs);
+
+        let mut transfer = self.transfer.borrow(cs).borrow_mut();
+        transfer.length = 0;
+        transfer.offset = 0;
+        modify_reg!(otg_device, self.usb.device(), DIEPEMPMSK, |v| v & !(1 << self.address.index()));
+    }
+
     pub fn fifo_size_words(&self) -> u32 {
         if self.is_initialized() {
-            (self.max_packet_size as u32 + 3) / 4
+            (self.max_packet_size as u32).div_ceil(4)
         } else {
             0
         }
     }
 }
 
+/// State of a multi-packet OUT transfer.
+#[derive(Default)]
+struct OutTransfer {
+    buffer: Option<EndpointBuffer>,
+    active: bool,
+    size: usize,
+    received: usize,
+}
+
 pub struct EndpointOut {
     common: Endpoint,
     pub(crate) buffer: Mutex<RefCell<EndpointBuffer>>,
+    transfer: Mutex<RefCell<OutTransfer>>,
+    /// The last packet completed while the buffer was full, the endpoint NAKs until it is read
+    rx_blocked: Mutex<Cell<bool>>,
+    /// The endpoint is held in NAK by the application
+    nak: Mutex<Cell<bool>>,
 }
 
 impl EndpointOut {
-    pub fn new(address: EndpointAddress) -> EndpointOut {
+    pub fn new(usb: UsbRegisters, address: EndpointAddress, dma: bool) -> EndpointOut {
         EndpointOut {
-            common: Endpoint::new(address),
+            common: Endpoint::new(usb, address, dma),
             buffer: Mutex::new(RefCell::new(EndpointBuffer::default())),
+            transfer: Mutex::new(RefCell::new(OutTransfer::default())),
+            rx_blocked: Mutex::new(Cell::new(false)),
+            nak: Mutex::new(Cell::new(false)),
+        }
+    }
+
+    /// Assigns the buffer used to receive multi-packet transfers
+    pub fn set_transfer_buffer(&self, buffer: EndpointBuffer) -> Result<()> {
+        if !matches!(self.ep_type, Some(EndpointType::Bulk) | Some(EndpointType::Interrupt)) {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        interrupt::free(|cs| {
+            let ep = self.usb.endpoint_out(self.address.index());
+            if read_reg!(endpoint_out, ep, DOEPCTL, EPENA) != 0 {
+                return Err(UsbError::WouldBlock);
+            }
+
+            self.transfer.borrow(cs).replace(OutTransfer {
+                buffer: Some(buffer),
+                ..OutTransfer::default()
+            });
+            Ok(())
+        })
+    }
+
+    /// Returns `true` if the endpoint receives data in multi-packet transfers
+    pub fn has_transfer_buffer(&self, cs: &CriticalSection) -> bool {
+        self.transfer.borrow(cs).borrow().buffer.is_some()
+    }
+
+    /// Arms the endpoint to receive a transfer of up to `length` bytes into the transfer buffer.
+    pub fn start_transfer(&self, length: usize) -> Result<()> {
+        interrupt::free(|cs| {
+            let mut transfer = self.transfer.borrow(cs).borrow_mut();
+            let OutTransfer { buffer, active, size, received } = &mut *transfer;
+            let buffer = buffer.as_ref().ok_or(UsbError::InvalidEndpoint)?;
+
+            if *active || buffer.state() != EndpointBufferState::Empty {
+                return Err(UsbError::WouldBlock);
+            }
+
+            // OUT transfer size must be a multiple of the packet size
+            let max_packet_size = self.max_packet_size as usize;
+            let packets = cmp::max(length.div_ceil(max_packet_size), 1);
+            let xfrsize = packets * max_packet_size;
+            if xfrsize > buffer.capacity() || packets > 0x3ff || xfrsize > 0x7ffff {
+                return Err(UsbError::BufferOverflow);
+            }
+
+            *active = true;
+            *size = xfrsize;
+            *received = 0;
+
+            let regs = self.usb.endpoint_out(self.address.index());
+            if self.dma {
+                write_reg!(endpoint_out, regs, DOEPDMA, buffer.address());
+            }
+            write_reg!(endpoint_out, regs, DOEPTSIZ, PKTCNT: packets as u32, XFRSIZ: xfrsize as u32);
+
+            // Make sure the previous contents of the buffer are no longer accessed
+            fence(Ordering::SeqCst);
+
+            self.arm(cs);
+
+            Ok(())
+        })
+    }
+
+    /// Copies a packet of the active transfer from the RX FIFO into the transfer buffer.
+    pub fn receive_transfer_packet(&self, cs: &CriticalSection, data_size: usize) {
+        let mut transfer = self.transfer.borrow(cs).borrow_mut();
+        let OutTransfer { buffer, received, .. } = &mut *transfer;
+
+        if let Some(buffer) = buffer {
+            if buffer.append_from_fifo(self.usb, *received, data_size).is_ok() {
+                *received += data_size;
+            }
+        }
+    }
+
+    /// Finishes the active transfer, making the received data available to `read`.
+    pub fn complete_transfer(&self, cs: &CriticalSection) {
+        let mut transfer = self.transfer.borrow(cs).borrow_mut();
+        let OutTransfer { buffer, active, received, .. } = &mut *transfer;
+
+        if let Some(buffer) = buffer {
+            if *active {
+                buffer.set_received(*received);
+                *active = false;
+            }
         }
     }
 
@@ -229,12 +547,166 @@ impl EndpointOut {
         }
 
         interrupt::free(|cs| {
-            self.buffer.borrow(cs).borrow_mut().read_packet(buf)
+            if let Some(buffer) = self.transfer.borrow(cs).borrow_mut().buffer.as_mut() {
+                return buffer.read_packet(buf);
+            }
+
+            let result = self.buffer.borrow(cs).borrow_mut().read_packet(buf);
+
+            if self.dma && result.is_ok() {
+                self.start_dma_receive(cs);
+            } else if result.is_ok() && self.rx_blocked.borrow(cs).replace(false) {
+                self.arm(cs);
+            }
+
+            result
         })
     }
 
+    pub fn configure(&self, cs: &CriticalSection) {
+        Endpoint::configure(self, cs);
+
+        if self.address.index() == 0 {
+            if self.dma {
+                self.start_dma_receive(cs);
+            }
+        } else if self.has_transfer_buffer(cs) {
+            // NAK until a transfer is started
+        } else if self.dma {
+            self.start_dma_receive(cs);
+        } else {
+            self.arm(cs);
+        }
+    }
+
+    pub fn deconfigure(&self, cs: &CriticalSection) {
+        Endpoint::deconfigure(self, cs);
+
+        // Packets received before a reset must not be mistaken for new ones
+        self.buffer.borrow(cs).borrow_mut().clear();
+        self.rx_blocked.borrow(cs).set(false);
+
+        let mut transfer = self.transfer.borrow(cs).borrow_mut();
+        transfer.active = false;
+        if let Some(buffer) = transfer.buffer.as_mut() {
+            buffer.clear();
+        }
+    }
+
+    /// Holds the endpoint in NAK, or releases it.
+    pub fn set_nak(&self, nak: bool) -> Result<()> {
+        if !self.is_initialized() || self.address.index() == 0 || self.is_isochronous() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        interrupt::free(|cs| {
+            self.nak.borrow(cs).set(nak);
+
+            let regs = self.usb.endpoint_out(self.address.index());
+            if nak {
+                modify_reg!(endpoint_out, regs, DOEPCTL, SNAK: 1);
+            } else {
+                modify_reg!(endpoint_out, regs, DOEPCTL, CNAK: 1);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Enables the endpoint, which keeps NAKing while it is held by `set_nak`.
+    fn arm(&self, cs: &CriticalSection) {
+        self.enable_with_nak(self.nak.borrow(cs).get());
+    }
+
+    /// Re-enables the endpoint after a completed OUT or SETUP transfer in slave mode.
+    ///
+    /// If the buffer is full, the endpoint keeps NAKing until a packet is read, so that the next
+    /// one cannot block the shared RX FIFO.
+    pub fn receive_next(&self, cs: &CriticalSection) {
+        if !self.buffer.borrow(cs).borrow().is_full() {
+            self.arm(cs);
+        } else {
+            self.rx_blocked.borrow(cs).set(true);
+        }
+    }
+
+    /// Arms the endpoint to receive the next packet into its buffer using DMA.
+    pub fn start_dma_receive(&self, cs: &CriticalSection) {
+        let buffer = self.buffer.borrow(cs).borrow();
+
+        if self.address.index() == 0 {
+            let regs = self.usb.endpoint0_out();
+            write_reg!(endpoint0_out, regs, DOEPDMA0, buffer.address());
+            write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 3, PKTCNT: 1, XFRSIZ: self.max_packet_size as u32);
+            modify_reg!(endpoint0_out, regs, DOEPCTL0, EPENA: 1, CNAK: 1);
+        } else {
+            let regs = self.usb.endpoint_out(self.address.index());
+            write_reg!(endpoint_out, regs, DOEPDMA, buffer.address());
+            write_reg!(endpoint_out, regs, DOEPTSIZ, PKTCNT: 1, XFRSIZ: self.max_packet_size as u32);
+            self.arm(cs);
+        }
+    }
+
+    /// Handles OUT endpoint interrupts in DMA mode, updating the buffer state.
+    pub fn handle_dma_interrupt(&self, cs: &CriticalSection) {
+        let mut buffer = self.buffer.borrow(cs).borrow_mut();
+
+        if self.address.index() == 0 {
+            let regs = self.usb.endpoint0_out();
+            let (setup, xfrc) = read_reg!(endpoint0_out, regs, DOEPINT0, STUP, XFRC);
+
+            // Make sure the buffer is not read before the core has finished writing it
+            fence(Ordering::SeqCst);
+
+            if xfrc != 0 {
+                write_reg!(endpoint0_out, regs, DOEPINT0, XFRC: 1);
+
+                if setup == 0 && buffer.state() == EndpointBufferState::Empty {
+                    let remaining = read_reg!(endpoint0_out, regs, DOEPTSIZ0, XFRSIZ) as u16;
+                    buffer.fill_from_dma(0, self.max_packet_size - remaining, false).ok();
+                }
+            }
+
+            if setup != 0 {
+                write_reg!(endpoint0_out, regs, DOEPINT0, STUP: 1);
+
+                buffer.clear();
+
+                // Up to 3 back-to-back SETUP packets may have been received, the last one wins
+                if let Some(received) = read_reg!(endpoint0_out, regs, DOEPDMA0).checked_sub(buffer.address()) {
+                    let offset = (received as usize).saturating_sub(8);
+                    buffer.fill_from_dma(offset, 8, true).ok();
+                }
+            }
+        } else {
+            let regs = self.usb.endpoint_out(self.address.index());
+
+            if read_reg!(endpoint_out, regs, DOEPINT, XFRC) != 0 {
+                write_reg!(endpoint_out, regs, DOEPINT, XFRC: 1);
+
+                // Make sure the buffer is not read before the core has finished writing it
+                fence(Ordering::SeqCst);
+
+                let remaining = read_reg!(endpoint_out, regs, DOEPTSIZ, XFRSIZ) as usize;
+
+                let mut transfer = self.transfer.borrow(cs).borrow_mut();
+                if transfer.active {
+                    transfer.received = transfer.size - remaining;
+                    drop(transfer);
+                    self.complete_transfer(cs);
+                } else {
+                    buffer.fill_from_dma(0, self.max_packet_size - remaining as u16, false).ok();
+                }
+            }
+        }
+    }
+
     pub fn buffer_state(&self) -> EndpointBufferState {
         interrupt::free(|cs| {
+            if let Some(buffer) = self.transfer.borrow(cs).borrow().buffer.as_ref() {
+                return buffer.state();
+            }
+
             self.buffer.borrow(cs).borrow().state()
         })
     }
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
-use core::{slice, mem};
+use core::{cmp, slice, mem};
 use vcell::VolatileCell;
-use crate::target::fifo_read_into;
+use crate::target::{fifo_read_into, fifo_write_from, UsbRegisters};
 use usb_device::{Result, UsbError};
 
 #[derive(Eq, PartialEq)]
@@ -11,29 +11,60 @@ pub enum EndpointBufferState {
     DataSetup,
 }
 
+/// Buffer holding one packet, or a ring of OUT packets.
+///
+/// With more than one slot, the buffer is split into equally sized slots that start with a word
+/// holding the size of the packet.
 pub struct EndpointBuffer {
     buffer: &'static mut [VolatileCell<u32>],
-    data_size: u16,
-    has_data: bool,
+    data_size: usize,
     is_setup: bool,
+    slots: usize,
+    /// Slot of the oldest packet
+    head: usize,
+    /// Number of packets in the buffer
+    count: usize,
 }
 
 impl EndpointBuffer {
     pub fn new(buffer: &'static mut [u32]) -> Self {
+        Self::with_slots(buffer, 1)
+    }
+
+    /// Creates a ring buffer of `slots` packets.
+    pub fn with_slots(buffer: &'static mut [u32], slots: usize) -> Self {
         Self {
-            buffer: unsafe { mem::transmute(buffer) },
+            buffer: unsafe { mem::transmute::<&mut [u32], &mut [VolatileCell<u32>]>(buffer) },
             data_size: 0,
-            has_data: false,
-            is_setup: false
+            is_setup: false,
+            slots: cmp::max(slots, 1),
+            head: 0,
+            count: 0,
         }
     }
 
+    fn slot_words(&self) -> usize {
+        self.buffer.len() / self.slots
+    }
+
+    /// Returns the data words and the size word, if any, of a slot.
+    fn slot(&self, slot: usize) -> (&[VolatileCell<u32>], Option<&VolatileCell<u32>>) {
+        if self.slots == 1 {
+            return (self.buffer, None);
+        }
+
+        let words = self.slot_words();
+        let (size, data) = self.buffer[slot * words..(slot + 1) * words].split_first().unwrap();
+        (data, Some(size))
+    }
+
     pub fn read_packet(&mut self, mut buf: &mut [u8]) -> Result<usize> {
-        if !self.has_data {
+        if self.count == 0 {
             return Err(UsbError::WouldBlock)
         }
 
-        let data_size = self.data_size as usize;
+        let (data, size) = self.slot(self.head);
+        let data_size = size.map_or(self.data_size, |size| size.get() as usize);
 
         if buf.len() < data_size {
             return Err(UsbError::BufferOverflow);
@@ -42,7 +73,7 @@ impl EndpointBuffer {
         let mut index = 0;
         let mut current_size = data_size;
         while current_size >= 4 {
-            let word = self.buffer[index].get();
+            let word = data[index].get();
             index += 1;
 
             let bytes = word.to_ne_bytes();
@@ -52,37 +83,174 @@ impl EndpointBuffer {
             current_size -= 4;
         }
         if current_size > 0 {
-            let word = self.buffer[index].get();
+            let word = data[index].get();
             let bytes = word.to_ne_bytes();
             buf[..current_size].copy_from_slice(&bytes[..current_size]);
         }
 
-        self.has_data = false;
+        self.head = (self.head + 1) % self.slots;
+        self.count -= 1;
 
         Ok(data_size)
     }
 
-    pub fn fill_from_fifo(&mut self, data_size: u16, is_setup: bool) -> Result<()> {
-        if self.has_data {
+    pub fn fill_from_fifo(&mut self, usb: UsbRegisters, data_size: u16, is_setup: bool) -> Result<()> {
+        if self.is_full() {
             return Err(UsbError::WouldBlock);
         }
 
+        let words = (data_size as usize).div_ceil(4);
+
         if data_size as usize > self.capacity() {
+            // Drain the packet, the FIFO would be out of sync with the status queue otherwise
+            let fifo = usb.fifo(0);
+            for _ in 0..words {
+                fifo.read();
+            }
             return Err(UsbError::BufferOverflow);
         }
 
-        let words = (data_size as usize + 3) / 4;
-        fifo_read_into(&self.buffer[..words]);
+        let (data, size) = self.slot((self.head + self.count) % self.slots);
+        fifo_read_into(usb, &data[..words]);
+        if let Some(size) = size {
+            size.set(data_size as u32);
+        }
 
         self.is_setup = is_setup;
+        self.data_size = data_size as usize;
+        self.count += 1;
+
+        Ok(())
+    }
+
+    /// Reads a packet from the RX FIFO into the buffer at byte `offset`, without changing the
+    /// buffer state.
+    ///
+    /// The packet is drained from the FIFO even if it does not fit into the buffer.
+    pub fn append_from_fifo(&self, usb: UsbRegisters, offset: usize, data_size: usize) -> Result<()> {
+        let words = data_size.div_ceil(4);
+
+        if offset + data_size > self.capacity() {
+            let fifo = usb.fifo(0);
+            for _ in 0..words {
+                fifo.read();
+            }
+            return Err(UsbError::BufferOverflow);
+        }
+
+        if offset % 4 == 0 {
+            let start = offset / 4;
+            fifo_read_into(usb, &self.buffer[start..start + words]);
+        } else {
+            // Packets of odd-sized endpoints start in the middle of a word
+            let fifo = usb.fifo(0);
+            for word in 0..words {
+                let u32_bytes = fifo.read().to_ne_bytes();
+                for (i, byte) in u32_bytes.iter().enumerate() {
+                    let pos = offset + word * 4 + i;
+                    if pos < offset + data_size {
+                        let mut bytes = self.buffer[pos / 4].get().to_ne_bytes();
+                        bytes[pos % 4] = *byte;
+                        self.buffer[pos / 4].set(u32::from_ne_bytes(bytes));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Marks the first `data_size` bytes of the buffer as received OUT data.
+    pub fn set_received(&mut self, data_size: usize) {
+        self.is_setup = false;
         self.data_size = data_size;
-        self.has_data = true;
+        self.head = 0;
+        self.count = 1;
+    }
+
+    /// Discards the data stored in the buffer.
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.count = 0;
+    }
+
+    /// Marks the buffer as filled by the DMA controller.
+    ///
+    /// `offset` is the byte offset of the data within the buffer; the data is moved to the start
+    /// of the buffer if required.
+    pub fn fill_from_dma(&mut self, offset: usize, data_size: u16, is_setup: bool) -> Result<()> {
+        if offset + data_size as usize > self.capacity() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let offset_words = offset / 4;
+        if offset_words != 0 {
+            for i in 0..(data_size as usize).div_ceil(4) {
+                self.buffer[i].set(self.buffer[offset_words + i].get());
+            }
+        }
+
+        self.is_setup = is_setup;
+        self.data_size = data_size as usize;
+        self.head = 0;
+        self.count = 1;
+
+        Ok(())
+    }
+
+    /// Copies data to be transmitted into the buffer.
+    pub fn write_packet(&self, mut buf: &[u8]) -> Result<()> {
+        if buf.len() > self.capacity() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let mut index = 0;
+        while buf.len() >= 4 {
+            let mut u32_bytes = [0u8; 4];
+            u32_bytes.copy_from_slice(&buf[..4]);
+            buf = &buf[4..];
+            self.buffer[index].set(u32::from_ne_bytes(u32_bytes));
+            index += 1;
+        }
+        if !buf.is_empty() {
+            let mut u32_bytes = [0u8; 4];
+            u32_bytes[..buf.len()].copy_from_slice(buf);
+            self.buffer[index].set(u32::from_ne_bytes(u32_bytes));
+        }
 
         Ok(())
     }
 
+    /// Writes `len` bytes starting at byte `offset` of the buffer into the TX FIFO of `channel`.
+    pub fn write_to_fifo(&self, usb: UsbRegisters, channel: usize, offset: usize, len: usize) {
+        let words = len.div_ceil(4);
+
+        if offset % 4 == 0 {
+            let start = offset / 4;
+            fifo_write_from(usb, channel, &self.buffer[start..start + words]);
+        } else {
+            // Packets of odd-sized endpoints start in the middle of a word
+            let fifo = usb.fifo(channel);
+            for word in 0..words {
+                let mut u32_bytes = [0u8; 4];
+                for (i, byte) in u32_bytes.iter_mut().enumerate() {
+                    let pos = offset + word * 4 + i;
+                    if pos < offset + len {
+                        *byte = self.buffer[pos / 4].get().to_ne_bytes()[pos % 4];
+                    }
+                }
+                fifo.write(u32::from_ne_bytes(u32_bytes));
+            }
+        }
+    }
+
+    /// Returns the address of the buffer memory as seen by the DMA controller.
+    pub fn address(&self) -> u32 {
+        self.buffer.as_ptr() as u32
+    }
+
     pub fn state(&self) -> EndpointBufferState {
-        if self.has_data {
+        if self.count != 0 {
             if self.is_setup {
                 EndpointBufferState::DataSetup
             } else {
@@ -93,8 +261,14 @@ impl EndpointBuffer {
         }
     }
 
+    /// Returns `true` if there is no room for another packet.
+    pub fn is_full(&self) -> bool {
+        self.count == self.slots
+    }
+
+    /// Returns the maximum size of a packet.
     pub fn capacity(&self) -> usize {
-        self.buffer.len() * 4
+        self.slot(0).0.len() * 4
     }
 }
 
@@ -108,6 +282,8 @@ impl Default for EndpointBuffer {
 pub struct EndpointMemoryAllocator {
     next_free_offset: usize,
     max_size_words: usize,
+    /// Sum of the largest packet sizes of the OUT endpoints, which sizes the RX FIFO
+    rx_packet_words: usize,
     memory: &'static mut [u32],
 }
 
@@ -116,12 +292,19 @@ impl EndpointMemoryAllocator {
         Self {
             next_free_offset: 0,
             max_size_words: 0,
+            rx_packet_words: 0,
             memory
         }
     }
 
-    pub fn allocate_rx_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
-        let size_words = (size + 3) / 4;
+    fn allocate_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
+        let buffer = self.allocate_words(size)?;
+        self.max_size_words = cmp::max(self.max_size_words, size.div_ceil(4));
+        Ok(EndpointBuffer::new(buffer))
+    }
+
+    fn allocate_words(&mut self, size: usize) -> Result<&'static mut [u32]> {
+        let size_words = size.div_ceil(4);
 
         let offset = self.next_free_offset;
         if offset + size_words > self.memory.len() {
@@ -129,20 +312,45 @@ impl EndpointMemoryAllocator {
         }
 
         self.next_free_offset += size_words;
-        self.max_size_words = core::cmp::max(self.max_size_words, size_words);
 
         let buffer = unsafe {
-            let ptr = self.memory.as_mut_ptr().offset(offset as isize);
+            let ptr = self.memory.as_mut_ptr().add(offset);
             slice::from_raw_parts_mut(ptr, size_words)
         };
-        Ok(EndpointBuffer::new(buffer))
+        Ok(buffer)
+    }
+
+    /// Allocates a buffer for an OUT endpoint that holds up to `packets` packets of `size` bytes
+    pub fn allocate_rx_buffer(&mut self, size: usize, packets: usize) -> Result<EndpointBuffer> {
+        let packets = cmp::max(packets, 1);
+        let slot_words = if packets > 1 {
+            // The size of each packet is stored in front of it
+            size.div_ceil(4) + 1
+        } else {
+            size.div_ceil(4)
+        };
+
+        let buffer = self.allocate_words(slot_words * packets * 4)?;
+        self.max_size_words = cmp::max(self.max_size_words, size.div_ceil(4));
+        // The RX FIFO only has to hold one packet of each endpoint, whatever the size of its ring
+        self.rx_packet_words += size.div_ceil(4);
+        Ok(EndpointBuffer::with_slots(buffer, packets))
+    }
+
+    /// Allocates a buffer for an IN endpoint, used only in DMA mode
+    pub fn allocate_tx_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
+        self.allocate_buffer(size)
     }
 
-    /// Returns the size of memory allocated for OUT endpoints in words
+    /// Returns the sum of the largest packet sizes of the OUT endpoints in words, used to size the
+    /// RX FIFO (`GRXFSIZ`)
+    ///
+    /// This is not the memory allocated for OUT endpoints, which is larger for ring buffers.
     pub fn total_rx_buffer_size_words(&self) -> usize {
-        self.next_free_offset
+        self.rx_packet_words
     }
 
+    /// Returns the size of the largest packet of all endpoint buffers in words
     pub fn max_buffer_size_words(&self) -> usize {
         self.max_size_words
     }
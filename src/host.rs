@@ -0,0 +1,776 @@
+use core::cmp;
+use usb_device::endpoint::EndpointType;
+use crate::ral::{read_reg, write_reg, modify_reg, otg_global, otg_host, otg_pwrclk, host_channel};
+use crate::target::{fifo_write, UsbRegisters};
+use crate::bus::UsbSpeed;
+use crate::phy;
+use crate::{UsbPeripheral, PhyType};
+
+/// Maximum number of host channels supported by the Synopsys OTG core.
+const MAX_CHANNELS: usize = 16;
+
+/// Duration of the port reset signalling in milliseconds.
+const PORT_RESET_MS: u32 = 50;
+
+/// Reset recovery time in milliseconds.
+const RESET_RECOVERY_MS: u32 = 10;
+
+/// Time after which a transfer fails if the device keeps NAKing it, in (micro)frames.
+const TRANSFER_TIMEOUT_FRAMES: u16 = 5000;
+
+/// Number of retries for transactions that fail with a bus error.
+const ERROR_RETRIES: u32 = 3;
+
+/// Host transfer error
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HostError {
+    /// The device responded with STALL
+    Stall,
+    /// The device responded with NAK, only returned for interrupt transfers
+    Nak,
+    /// The device did not complete the transfer in time
+    Timeout,
+    /// The transaction failed after several retries (CRC, bit stuffing or response timeout)
+    TransactionError,
+    /// The device sent more data than the maximum packet size
+    Babble,
+    /// The device sent a packet with an unexpected data toggle
+    DataToggle,
+    /// No device is connected or the port is not enabled
+    NotConnected,
+    /// The channel is not allocated or does not support the transfer type
+    InvalidChannel,
+    /// All host channels are in use
+    NoFreeChannel,
+    /// The device sent more data than fits into the buffer
+    BufferOverflow,
+}
+
+/// Result for host operations
+pub type Result<T> = core::result::Result<T, HostError>;
+
+/// Port events reported by `UsbHost::poll`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HostEvent {
+    /// Nothing happened
+    None,
+    /// A device was connected to the port, it must be reset with `UsbHost::reset_port`
+    Connected,
+    /// The device was disconnected
+    Disconnected,
+    /// The port was enabled after a reset and the device can be enumerated
+    PortEnabled(UsbSpeed),
+    /// The port was disabled by the core
+    PortDisabled,
+    /// Overcurrent was detected on the port
+    Overcurrent,
+}
+
+/// Configuration of a host channel
+#[derive(Copy, Clone, Debug)]
+pub struct ChannelConfig {
+    /// Address of the device
+    pub device_address: u8,
+    /// Endpoint number
+    pub endpoint_number: u8,
+    /// Endpoint type
+    pub ep_type: EndpointType,
+    /// Maximum packet size of the endpoint
+    pub max_packet_size: u16,
+}
+
+/// Handle of an allocated host channel
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Channel(u8);
+
+#[derive(Copy, Clone)]
+struct ChannelState {
+    config: ChannelConfig,
+    low_speed: bool,
+    toggle_in: bool,
+    toggle_out: bool,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Pid {
+    Data0,
+    Data1,
+    Setup,
+}
+
+impl Pid {
+    fn from_toggle(toggle: bool) -> Pid {
+        if toggle { Pid::Data1 } else { Pid::Data0 }
+    }
+
+    fn next(self) -> Pid {
+        match self {
+            Pid::Data0 => Pid::Data1,
+            _ => Pid::Data0,
+        }
+    }
+
+    fn dpid(self) -> u32 {
+        match self {
+            Pid::Data0 => 0b00,
+            Pid::Data1 => 0b10,
+            Pid::Setup => 0b11,
+        }
+    }
+}
+
+/// Result of a single transaction
+enum Outcome {
+    Done(usize),
+    Nak,
+    Error(HostError),
+}
+
+/// USB host driver for the OTG core.
+///
+/// The driver supports a single device connected directly to the root port; transfers are
+/// performed synchronously in slave (FIFO) mode.
+pub struct UsbHost<USB> {
+    peripheral: Option<USB>,
+    regs: UsbRegisters,
+    channels: [Option<ChannelState>; MAX_CHANNELS],
+}
+
+impl<USB: UsbPeripheral> UsbHost<USB> {
+    /// Constructs a new USB host driver.
+    pub fn new(peripheral: USB) -> Self {
+        assert!(USB::HOST_CHANNEL_COUNT <= MAX_CHANNELS);
+
+        UsbHost {
+            peripheral: Some(peripheral),
+            regs: UsbRegisters::new::<USB>(),
+            channels: [None; MAX_CHANNELS],
+        }
+    }
+
+    /// Constructs a host driver for a dual-role core whose peripheral is owned by `UsbBus`.
+    pub(crate) fn new_dual_role() -> Self {
+        assert!(USB::HOST_CHANNEL_COUNT <= MAX_CHANNELS);
+
+        UsbHost {
+            peripheral: None,
+            regs: UsbRegisters::new::<USB>(),
+            channels: [None; MAX_CHANNELS],
+        }
+    }
+
+    /// Releases the peripheral.
+    ///
+    /// Panics if the driver was obtained from `UsbBus::host`.
+    pub fn free(self) -> USB {
+        self.peripheral.expect("the peripheral is owned by UsbBus")
+    }
+
+    /// Initializes the core in host mode and powers the port.
+    ///
+    /// VBUS must be switched on by the application, the core does not drive it.
+    pub fn enable(&mut self) {
+        // Enable USB_OTG in RCC
+        USB::enable();
+
+        let regs = self.regs;
+
+        // Wait for AHB ready
+        while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
+
+        // Select the PHY
+        phy::configure::<USB>(regs);
+
+        // Configure OTG as host, dual-role cores are already in host mode
+        modify_reg!(otg_global, regs.global(), GUSBCFG,
+            SRPCAP: (USB::DUAL_ROLE && USB::SRP_CAPABLE) as u32,
+            HNPCAP: (USB::DUAL_ROLE && USB::HNP_CAPABLE) as u32,
+            FDMOD: 0,
+            FHMOD: !USB::DUAL_ROLE as u32 // Force host mode
+        );
+        while read_reg!(otg_global, regs.global(), GINTSTS, CMOD) == 0 {}
+
+        // VBUS is provided by the application, disable VBUS sensing
+        if USB::VBUS_DETECTION_BLOCK {
+            modify_reg!(otg_global, regs.global(), GCCFG, VBDEN: 0);
+        } else {
+            modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, NOVBUSSENS: 1);
+        }
+
+        // Enable PHY clock
+        write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
+
+        if USB::PHY_TYPE == PhyType::InternalFullSpeed {
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
+
+            // FS/LS PHY clock is 48 MHz
+            write_reg!(otg_host, regs.host(), HCFG, FSLSPCS: 0b01, FSLSS: 1);
+        } else {
+            write_reg!(otg_host, regs.host(), HCFG, FSLSPCS: 0b00, FSLSS: 0);
+        }
+
+        // FIFOs
+        let (rx_fifo_size, np_fifo_size, p_fifo_size) = if USB::HIGH_SPEED {
+            (0x200, 0x100, 0xe0)
+        } else {
+            (0x80, 0x60, 0x40)
+        };
+        assert!(rx_fifo_size + np_fifo_size + p_fifo_size <= USB::FIFO_DEPTH_WORDS as u32,
+            "Host FIFO allocation exceeds FIFO_DEPTH_WORDS");
+
+        write_reg!(otg_global, regs.global(), GRXFSIZ, rx_fifo_size);
+        write_reg!(otg_global, regs.global(), DIEPTXF0,
+            NPTXFD: np_fifo_size,
+            NPTXFSA: rx_fifo_size
+        );
+        write_reg!(otg_global, regs.global(), HPTXFSIZ,
+            PTXFD: p_fifo_size,
+            PTXSA: rx_fifo_size + np_fifo_size
+        );
+
+        // Flush Rx & Tx FIFOs
+        modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+        while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+
+        // Clear channel interrupts, transfers are polled
+        for index in 0..USB::HOST_CHANNEL_COUNT {
+            let ch = regs.host_channel(index);
+            write_reg!(host_channel, ch, HCINTMSK, 0);
+            write_reg!(host_channel, ch, HCINT, 0xffffffff);
+        }
+
+        // unmask core interrupts
+        let otg = USB::DUAL_ROLE && (USB::SRP_CAPABLE || USB::HNP_CAPABLE);
+        write_reg!(otg_global, regs.global(), GINTMSK,
+            PRTIM: 1, DISCINT: 1,
+            // Role changes and OTG protocol events are handled by UsbBus
+            CIDSCHGM: USB::DUAL_ROLE as u32,
+            OTGINT: otg as u32, SRQIM: otg as u32
+        );
+
+        // clear pending interrupts
+        write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
+
+        // unmask global interrupt
+        modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
+
+        // Power the port
+        self.modify_port(|v| v | otg_host::HPRT::PPWR::mask);
+    }
+
+    /// Removes power from the port.
+    pub fn disable(&mut self) {
+        self.modify_port(|v| v & !otg_host::HPRT::PPWR::mask);
+    }
+
+    /// Gets port events, should be called periodically or from the USB interrupt handler.
+    pub fn poll(&mut self) -> HostEvent {
+        use otg_host::HPRT;
+
+        let regs = self.regs;
+        let (host_mode, port, disconnect) = read_reg!(otg_global, regs.global(), GINTSTS, CMOD, HPRTINT, DISCINT);
+
+        if host_mode == 0 {
+            // A dual-role core switched to the device role
+            return HostEvent::None;
+        }
+
+        if disconnect != 0 {
+            write_reg!(otg_global, regs.global(), GINTSTS, DISCINT: 1);
+
+            return HostEvent::Disconnected;
+        }
+
+        if port != 0 {
+            let hprt = read_reg!(otg_host, regs.host(), HPRT);
+
+            // Change bits are cleared one at a time, so that every event is reported
+            if hprt & HPRT::PCDET::mask != 0 {
+                self.modify_port(|v| v | HPRT::PCDET::mask);
+                return HostEvent::Connected;
+            }
+
+            if hprt & HPRT::PENCHNG::mask != 0 {
+                self.modify_port(|v| v | HPRT::PENCHNG::mask);
+
+                return match self.port_speed() {
+                    Some(speed) => {
+                        self.configure_frame_interval(speed);
+                        HostEvent::PortEnabled(speed)
+                    }
+                    None => HostEvent::PortDisabled,
+                };
+            }
+
+            if hprt & HPRT::POCCHNG::mask != 0 {
+                self.modify_port(|v| v | HPRT::POCCHNG::mask);
+                return HostEvent::Overcurrent;
+            }
+        }
+
+        HostEvent::None
+    }
+
+    /// Returns `true` if a device is connected to the port.
+    pub fn is_connected(&self) -> bool {
+        read_reg!(otg_host, self.regs.host(), HPRT, PCSTS) != 0
+    }
+
+    /// Returns the speed of the connected device, or `None` if the port is not enabled.
+    pub fn port_speed(&self) -> Option<UsbSpeed> {
+        let (enabled, speed) = read_reg!(otg_host, self.regs.host(), HPRT, PENA, PSPD);
+        if enabled == 0 {
+            return None;
+        }
+
+        match speed {
+            0b00 => Some(UsbSpeed::High),
+            0b10 => Some(UsbSpeed::Low),
+            _ => Some(UsbSpeed::Full),
+        }
+    }
+
+    /// Suspends the bus.
+    ///
+    /// With `UsbBus::set_host_hnp_enabled` this starts host negotiation on a dual-role core.
+    pub fn suspend_port(&mut self) {
+        self.modify_port(|v| v | otg_host::HPRT::PSUSP::mask);
+    }
+
+    /// Resets the connected device.
+    ///
+    /// `delay_ms` must block for the given number of milliseconds. The port is enabled once the
+    /// reset completes, which is reported by `poll` as `HostEvent::PortEnabled`.
+    pub fn reset_port(&mut self, mut delay_ms: impl FnMut(u32)) {
+        use otg_host::HPRT;
+
+        self.modify_port(|v| v | HPRT::PRST::mask);
+        delay_ms(PORT_RESET_MS);
+        self.modify_port(|v| v & !HPRT::PRST::mask);
+        delay_ms(RESET_RECOVERY_MS);
+    }
+
+    /// Returns the current (micro)frame number.
+    pub fn frame_number(&self) -> u16 {
+        read_reg!(otg_host, self.regs.host(), HFNUM, FRNUM) as u16
+    }
+
+    /// Allocates a host channel for an endpoint of the connected device.
+    pub fn alloc_channel(&mut self, config: ChannelConfig) -> Result<Channel> {
+        let index = self.channels[..USB::HOST_CHANNEL_COUNT].iter()
+            .position(Option::is_none)
+            .ok_or(HostError::NoFreeChannel)?;
+
+        self.channels[index] = Some(ChannelState {
+            config,
+            low_speed: self.port_speed() == Some(UsbSpeed::Low),
+            toggle_in: false,
+            toggle_out: false,
+        });
+
+        Ok(Channel(index as u8))
+    }
+
+    /// Releases a host channel.
+    pub fn free_channel(&mut self, channel: Channel) {
+        if let Some(slot) = self.channels.get_mut(channel.0 as usize) {
+            if slot.take().is_some() {
+                self.halt_channel(channel.0 as usize);
+            }
+        }
+    }
+
+    /// Changes the device address used by a channel, e.g. after SET_ADDRESS.
+    pub fn set_device_address(&mut self, channel: Channel, address: u8) -> Result<()> {
+        self.channel_state(channel)?.config.device_address = address;
+        Ok(())
+    }
+
+    /// Changes the maximum packet size used by a channel, e.g. after reading `bMaxPacketSize0`.
+    pub fn set_max_packet_size(&mut self, channel: Channel, max_packet_size: u16) -> Result<()> {
+        self.channel_state(channel)?.config.max_packet_size = max_packet_size;
+        Ok(())
+    }
+
+    /// Resets the data toggles of a channel to DATA0, e.g. after CLEAR_FEATURE(ENDPOINT_HALT).
+    pub fn reset_data_toggle(&mut self, channel: Channel) -> Result<()> {
+        let state = self.channel_state(channel)?;
+        state.toggle_in = false;
+        state.toggle_out = false;
+        Ok(())
+    }
+
+    /// Performs a control transfer with an IN data stage, returning the number of bytes received.
+    pub fn control_in(&mut self, channel: Channel, setup: &[u8; 8], buf: &mut [u8]) -> Result<usize> {
+        self.check_type(channel, EndpointType::Control)?;
+
+        let length = cmp::min(u16::from_le_bytes([setup[6], setup[7]]) as usize, buf.len());
+
+        self.send(channel, setup, Pid::Setup, true)?;
+        let received = if length > 0 {
+            self.receive(channel, &mut buf[..length], Pid::Data1, true)?.0
+        } else {
+            0
+        };
+        self.send(channel, &[], Pid::Data1, true)?;
+
+        Ok(received)
+    }
+
+    /// Performs a control transfer with an optional OUT data stage.
+    pub fn control_out(&mut self, channel: Channel, setup: &[u8; 8], data: &[u8]) -> Result<()> {
+        self.check_type(channel, EndpointType::Control)?;
+
+        self.send(channel, setup, Pid::Setup, true)?;
+        if !data.is_empty() {
+            self.send(channel, data, Pid::Data1, true)?;
+        }
+        self.receive(channel, &mut [], Pid::Data1, true)?;
+
+        Ok(())
+    }
+
+    /// Receives a bulk transfer, returning the number of bytes received.
+    ///
+    /// The transfer ends when `buf` is full or on a short packet, so the length of `buf` should be
+    /// a multiple of the maximum packet size.
+    pub fn bulk_in(&mut self, channel: Channel, buf: &mut [u8]) -> Result<usize> {
+        self.check_type(channel, EndpointType::Bulk)?;
+        self.data_in(channel, buf, true)
+    }
+
+    /// Sends a bulk transfer. An empty `data` sends a zero-length packet.
+    pub fn bulk_out(&mut self, channel: Channel, data: &[u8]) -> Result<()> {
+        self.check_type(channel, EndpointType::Bulk)?;
+        self.data_out(channel, data, true)
+    }
+
+    /// Polls an interrupt IN endpoint once, returning `HostError::Nak` if it has no data.
+    pub fn interrupt_in(&mut self, channel: Channel, buf: &mut [u8]) -> Result<usize> {
+        self.check_type(channel, EndpointType::Interrupt)?;
+        let max_packet_size = self.channel_state(channel)?.config.max_packet_size as usize;
+
+        let length = cmp::min(buf.len(), max_packet_size);
+        self.data_in(channel, &mut buf[..length], false)
+    }
+
+    /// Sends a single packet to an interrupt OUT endpoint, returning `HostError::Nak` if the
+    /// device is not ready to accept it.
+    pub fn interrupt_out(&mut self, channel: Channel, data: &[u8]) -> Result<()> {
+        self.check_type(channel, EndpointType::Interrupt)?;
+        let max_packet_size = self.channel_state(channel)?.config.max_packet_size as usize;
+
+        if data.len() > max_packet_size {
+            return Err(HostError::BufferOverflow);
+        }
+        self.data_out(channel, data, false)
+    }
+
+    fn data_in(&mut self, channel: Channel, buf: &mut [u8], retry_nak: bool) -> Result<usize> {
+        let pid = Pid::from_toggle(self.channel_state(channel)?.toggle_in);
+        let (received, pid) = self.receive(channel, buf, pid, retry_nak)?;
+        self.channel_state(channel)?.toggle_in = pid == Pid::Data1;
+        Ok(received)
+    }
+
+    fn data_out(&mut self, channel: Channel, data: &[u8], retry_nak: bool) -> Result<()> {
+        let pid = Pid::from_toggle(self.channel_state(channel)?.toggle_out);
+        let pid = self.send(channel, data, pid, retry_nak)?;
+        self.channel_state(channel)?.toggle_out = pid == Pid::Data1;
+        Ok(())
+    }
+
+    fn channel_state(&mut self, channel: Channel) -> Result<&mut ChannelState> {
+        self.channels.get_mut(channel.0 as usize)
+            .and_then(Option::as_mut)
+            .ok_or(HostError::InvalidChannel)
+    }
+
+    fn check_type(&mut self, channel: Channel, ep_type: EndpointType) -> Result<()> {
+        if self.channel_state(channel)?.config.ep_type != ep_type {
+            return Err(HostError::InvalidChannel);
+        }
+        if self.port_speed().is_none() {
+            return Err(HostError::NotConnected);
+        }
+        Ok(())
+    }
+
+    /// Sends `data` as a sequence of packets, returning the PID of the next packet.
+    fn send(&mut self, channel: Channel, data: &[u8], mut pid: Pid, retry_nak: bool) -> Result<Pid> {
+        let max_packet_size = self.channel_state(channel)?.config.max_packet_size as usize;
+
+        let mut offset = 0;
+        loop {
+            let size = cmp::min(max_packet_size, data.len() - offset);
+            self.transaction(channel, pid, TransactionData::Out(&data[offset..offset + size]), retry_nak)?;
+
+            offset += size;
+            pid = pid.next();
+            if offset == data.len() {
+                return Ok(pid);
+            }
+        }
+    }
+
+    /// Receives packets into `buf` until it is full or a short packet arrives, returning the number
+    /// of bytes received and the PID of the next packet.
+    fn receive(&mut self, channel: Channel, buf: &mut [u8], mut pid: Pid, retry_nak: bool) -> Result<(usize, Pid)> {
+        let max_packet_size = self.channel_state(channel)?.config.max_packet_size as usize;
+
+        let mut received = 0;
+        loop {
+            let size = self.transaction(channel, pid, TransactionData::In(&mut buf[received..]), retry_nak)?;
+
+            received += size;
+            pid = pid.next();
+            if size < max_packet_size || received == buf.len() {
+                return Ok((received, pid));
+            }
+        }
+    }
+
+    /// Performs a single transaction, retrying it on NAK and on bus errors.
+    fn transaction(&mut self, channel: Channel, pid: Pid, mut data: TransactionData, retry_nak: bool) -> Result<usize> {
+        let state = *self.channel_state(channel)?;
+        let index = channel.0 as usize;
+        let start = self.frame_number();
+        let mut errors = 0;
+
+        loop {
+            if self.port_speed().is_none() {
+                return Err(HostError::NotConnected);
+            }
+
+            let outcome = self.start_transaction(index, &state, pid, &mut data)
+                .and_then(|_| self.wait_transaction(index, &mut data, start))?;
+
+            match outcome {
+                Outcome::Done(size) => return Ok(size),
+                Outcome::Nak => {
+                    if !retry_nak {
+                        return Err(HostError::Nak);
+                    }
+                }
+                Outcome::Error(error) => {
+                    errors += 1;
+                    if errors > ERROR_RETRIES {
+                        return Err(error);
+                    }
+                }
+            }
+
+            if self.frames_since(start) > TRANSFER_TIMEOUT_FRAMES {
+                return Err(HostError::Timeout);
+            }
+        }
+    }
+
+    fn start_transaction(&self, index: usize, state: &ChannelState, pid: Pid, data: &mut TransactionData) -> Result<()> {
+        let regs = self.regs;
+        let ch = regs.host_channel(index);
+        let config = &state.config;
+
+        let periodic = matches!(config.ep_type, EndpointType::Interrupt | EndpointType::Isochronous);
+        let (is_in, size) = match data {
+            TransactionData::In(buf) => (true, cmp::min(buf.len(), config.max_packet_size as usize)),
+            TransactionData::Out(buf) => (false, buf.len()),
+        };
+
+        write_reg!(host_channel, ch, HCINT, 0xffffffff);
+        write_reg!(host_channel, ch, HCTSIZ,
+            DPID: pid.dpid(),
+            PKTCNT: 1,
+            XFRSIZ: if is_in { config.max_packet_size as u32 } else { size as u32 }
+        );
+
+        // Periodic transactions are scheduled for the next (micro)frame
+        let odd_frame = periodic && self.frame_number() & 1 == 0;
+        write_reg!(host_channel, ch, HCCHAR,
+            MPSIZ: config.max_packet_size as u32,
+            EPNUM: config.endpoint_number as u32,
+            EPDIR: is_in as u32,
+            LSDEV: state.low_speed as u32,
+            EPTYP: config.ep_type as u32,
+            MCNT: 1,
+            DAD: config.device_address as u32,
+            ODDFRM: odd_frame as u32,
+            CHENA: 1
+        );
+
+        if let TransactionData::Out(buf) = data {
+            if !buf.is_empty() {
+                // Wait for room in the TX FIFO
+                let words = buf.len().div_ceil(4) as u32;
+                let start = self.frame_number();
+                loop {
+                    let available = if periodic {
+                        read_reg!(otg_host, regs.host(), HPTXSTS, PTXFSAVL)
+                    } else {
+                        read_reg!(otg_global, regs.global(), GNPTXSTS, NPTXFSAV)
+                    };
+                    if available >= words {
+                        break;
+                    }
+                    if self.frames_since(start) > TRANSFER_TIMEOUT_FRAMES {
+                        self.halt_channel(index);
+                        return Err(HostError::Timeout);
+                    }
+                }
+
+                fifo_write(regs, index, buf);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn wait_transaction(&self, index: usize, data: &mut TransactionData, start: u16) -> Result<Outcome> {
+        use host_channel::HCINT;
+
+        let ch = self.regs.host_channel(index);
+        let mut received = 0;
+        let mut overflow = false;
+
+        loop {
+            if let TransactionData::In(buf) = data {
+                self.drain_rx_fifo(index, buf, &mut received, &mut overflow);
+            }
+
+            let hcint = read_reg!(host_channel, ch, HCINT);
+
+            if hcint & HCINT::XFRC::mask != 0 {
+                self.halt_channel(index);
+                if overflow {
+                    return Err(HostError::BufferOverflow);
+                }
+                let size = match data {
+                    TransactionData::In(_) => received,
+                    TransactionData::Out(buf) => buf.len(),
+                };
+                return Ok(Outcome::Done(size));
+            }
+            if hcint & HCINT::STALL::mask != 0 {
+                self.halt_channel(index);
+                return Err(HostError::Stall);
+            }
+            if hcint & HCINT::NAK::mask != 0 {
+                self.halt_channel(index);
+                return Ok(Outcome::Nak);
+            }
+            if hcint & HCINT::BBERR::mask != 0 {
+                self.halt_channel(index);
+                return Err(HostError::Babble);
+            }
+            if hcint & (HCINT::TXERR::mask | HCINT::FRMOR::mask) != 0 {
+                self.halt_channel(index);
+                return Ok(Outcome::Error(HostError::TransactionError));
+            }
+            if hcint & HCINT::DTERR::mask != 0 {
+                self.halt_channel(index);
+                return Ok(Outcome::Error(HostError::DataToggle));
+            }
+
+            if self.port_speed().is_none() {
+                self.halt_channel(index);
+                return Err(HostError::NotConnected);
+            }
+            if self.frames_since(start) > TRANSFER_TIMEOUT_FRAMES {
+                self.halt_channel(index);
+                return Err(HostError::Timeout);
+            }
+        }
+    }
+
+    /// Pops all entries from the RX FIFO, storing the IN data of channel `index` into `buf`.
+    fn drain_rx_fifo(&self, index: usize, buf: &mut [u8], received: &mut usize, overflow: &mut bool) {
+        let regs = self.regs;
+
+        while read_reg!(otg_global, regs.global(), GINTSTS, RXFLVL) != 0 {
+            let (chnum, data_size, status) = read_reg!(otg_global, regs.global(), GRXSTSP, CHNUM, BCNT, PKTSTS);
+            if status != 0x02 { // IN data packet received
+                continue;
+            }
+
+            let data_size = data_size as usize;
+            let fifo = regs.fifo(0);
+            for word in 0..data_size.div_ceil(4) {
+                let bytes = fifo.read().to_ne_bytes();
+                if chnum as usize != index {
+                    continue;
+                }
+
+                for (i, byte) in bytes.iter().enumerate() {
+                    let pos = word * 4 + i;
+                    if pos >= data_size {
+                        break;
+                    }
+                    match buf.get_mut(*received + pos) {
+                        Some(b) => *b = *byte,
+                        None => *overflow = true,
+                    }
+                }
+            }
+
+            if chnum as usize == index {
+                *received = cmp::min(*received + data_size, buf.len());
+            }
+        }
+    }
+
+    /// Disables a channel and waits until it is halted.
+    fn halt_channel(&self, index: usize) {
+        let ch = self.regs.host_channel(index);
+
+        if read_reg!(host_channel, ch, HCCHAR, CHENA) != 0 {
+            modify_reg!(host_channel, ch, HCCHAR, CHDIS: 1, CHENA: 1);
+
+            let start = self.frame_number();
+            while read_reg!(host_channel, ch, HCINT, CHH) == 0 && self.frames_since(start) < 3 {
+                // The channel halted status of IN channels must be popped from the RX FIFO
+                self.drain_rx_fifo(index, &mut [], &mut 0, &mut false);
+            }
+        }
+
+        write_reg!(host_channel, ch, HCINT, 0xffffffff);
+    }
+
+    fn frames_since(&self, start: u16) -> u16 {
+        self.frame_number().wrapping_sub(start) & 0x3fff
+    }
+
+    fn configure_frame_interval(&self, speed: UsbSpeed) {
+        if USB::PHY_TYPE != PhyType::InternalFullSpeed {
+            return;
+        }
+
+        let regs = self.regs;
+        match speed {
+            UsbSpeed::Low => {
+                // 6 MHz PHY clock, 1 ms frames
+                modify_reg!(otg_host, regs.host(), HCFG, FSLSPCS: 0b10);
+                write_reg!(otg_host, regs.host(), HFIR, FRIVL: 6000);
+            }
+            _ => {
+                // 48 MHz PHY clock, 1 ms frames
+                modify_reg!(otg_host, regs.host(), HCFG, FSLSPCS: 0b01);
+                write_reg!(otg_host, regs.host(), HFIR, FRIVL: 48000);
+            }
+        }
+    }
+
+    /// Modifies HPRT, keeping the port enabled and the change bits untouched unless set by `f`.
+    fn modify_port(&self, f: impl FnOnce(u32) -> u32) {
+        use otg_host::HPRT;
+
+        let w1c = HPRT::PENA::mask | HPRT::PCDET::mask | HPRT::PENCHNG::mask | HPRT::POCCHNG::mask;
+        let value = read_reg!(otg_host, self.regs.host(), HPRT) & !w1c;
+        write_reg!(otg_host, self.regs.host(), HPRT, f(value));
+    }
+}
+
+/// Data stage of a single transaction
+enum TransactionData<'a> {
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
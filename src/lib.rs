@@ -2,27 +2,88 @@
 
 #![no_std]
 
-#[cfg(all(feature = "fs", feature = "hs"))]
-compile_error!("choose only one USB mode");
+#[cfg(feature = "sim")]
+extern crate std;
 
-#[cfg(not(any(feature = "fs", feature ="hs")))]
-compile_error!("select USB mode feature (fs/hs)");
+#[cfg(all(feature = "sim", any(feature = "cortex-m", feature = "riscv")))]
+compile_error!("the `sim` feature is only meant for host-side tests and cannot be combined with `cortex-m` or `riscv`");
 
 mod endpoint;
 mod endpoint_memory;
 
 mod target;
 
+mod phy;
+
 /// USB peripheral driver.
 pub mod bus;
 
-pub use crate::bus::UsbBus;
+pub use crate::bus::{UsbBus, UsbSpeed, IsoIncomplete, OtgEvents, CableEvent};
+
+/// USB host driver.
+pub mod host;
+
+pub use crate::host::UsbHost;
 
 mod ral;
 
+/// Simulated OTG core for running the driver in host-side tests.
+///
+/// The `sim` feature replaces all register accesses of the driver with the simulated core, so it
+/// must only be enabled for tests and never by a HAL or firmware crate.
+#[cfg(feature = "sim")]
+pub mod sim;
+
+/// USB PHY type
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PhyType {
+    /// Internal Full-Speed PHY
+    ///
+    /// Available on most High-Speed peripherals.
+    InternalFullSpeed,
+    /// Internal (embedded UTMI+) High-Speed PHY
+    ///
+    /// Available on some High-Speed peripherals, e.g. on STM32F723/F733.
+    InternalHighSpeed,
+    /// External ULPI High-Speed PHY
+    ExternalHighSpeed,
+}
+
+/// VBUS sensing configuration
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VbusSense {
+    /// VBUS sensing is disabled, the device behaves as if VBUS was always present
+    ///
+    /// This sets `GCCFG.NOVBUSSENS`, which is the behavior of previous versions of the driver.
+    /// Cores with the newer `GCCFG` layout have no such bit, the B-session valid signal is
+    /// overridden through `GOTGCTL.BVALOEN` and `GOTGCTL.BVALOVAL` instead.
+    Disabled,
+    /// VBUS is sensed through the B-device session valid comparator (`GCCFG.VBUSBSEN`)
+    ///
+    /// Used by cores with the older `GCCFG` layout, e.g. on STM32F1/F2/F4x5/F4x7.
+    BSessionValid,
+    /// VBUS is sensed through the VBUS detection block (`GCCFG.VBDEN`)
+    ///
+    /// Used by cores with the newer `GCCFG` layout, e.g. on STM32F446/F7/L4.
+    VbusDetection,
+}
+
+/// Role of a dual-role peripheral, selected by the ID pin of the connector
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UsbRole {
+    /// B-device, the ID pin is floating (micro-B plug or no cable)
+    Device,
+    /// A-device, the ID pin is grounded (micro-A plug)
+    Host,
+}
+
 /// A trait for device-specific USB peripherals. Implement this to add support for a new hardware
-/// platform. Peripherals that have this trait must have the same register block as STM32 USB OTG
-/// peripherals.
+/// platform.
+///
+/// # Safety
+///
+/// Peripherals that have this trait must have the same register block as STM32 USB OTG
+/// peripherals, located at `REGISTERS`.
 pub unsafe trait UsbPeripheral: Send + Sync {
     /// Pointer to the register block
     const REGISTERS: *const ();
@@ -33,6 +94,59 @@ pub unsafe trait UsbPeripheral: Send + Sync {
     /// FIFO size in 32-bit words
     const FIFO_DEPTH_WORDS: usize;
 
+    /// Number of (bidirectional) endpoints, including EP0. Must be within 1..=16.
+    ///
+    /// Defaults to the endpoint count of the STM32F4 cores: 6 for High Speed variants of the
+    /// peripheral and 4 for Full Speed ones.
+    const ENDPOINT_COUNT: usize = if Self::HIGH_SPEED { 6 } else { 4 };
+
+    /// PHY used by the peripheral
+    ///
+    /// `InternalHighSpeed` and `ExternalHighSpeed` are only valid for High Speed variants of the
+    /// peripheral. For `ExternalHighSpeed`, `enable()` must also enable the ULPI clock.
+    const PHY_TYPE: PhyType = PhyType::InternalFullSpeed;
+
+    /// VBUS sensing mode
+    ///
+    /// With VBUS sensing enabled, the pull-up is only connected while VBUS is present, as required
+    /// for self-powered devices. The VBUS pin must be configured by the HAL.
+    const VBUS_SENSE: VbusSense = VbusSense::Disabled;
+
+    /// true for cores with the newer `GCCFG` layout, which has a VBUS detection block
+    /// (`GCCFG.VBDEN`) instead of the VBUS sensing comparators, e.g. on STM32F446/F7/L4
+    ///
+    /// Defaults to true only if `VBUS_SENSE` is `VbusSense::VbusDetection`.
+    const VBUS_DETECTION_BLOCK: bool = matches!(Self::VBUS_SENSE, VbusSense::VbusDetection);
+
+    /// Power down the internal Full-Speed transceiver (`GCCFG.PWRDWN`) while the bus is suspended
+    ///
+    /// Only used with `PhyType::InternalFullSpeed`. The PHY clock and HCLK are gated during suspend
+    /// regardless of this setting.
+    const SUSPEND_POWER_DOWN: bool = false;
+
+    /// Number of host channels. Must not exceed 16. Only used by the host driver.
+    const HOST_CHANNEL_COUNT: usize = 8;
+
+    /// Dual-role operation: the core is not forced into device mode and follows the ID pin
+    ///
+    /// The ID pin must be configured by the HAL. See `UsbBus::take_role_change` and
+    /// `UsbBus::host`.
+    const DUAL_ROLE: bool = false;
+
+    /// Session Request Protocol support (`GUSBCFG.SRPCAP`), see `UsbBus::request_session`
+    const SRP_CAPABLE: bool = false;
+
+    /// Host Negotiation Protocol support (`GUSBCFG.HNPCAP`), see `UsbBus::request_host_role`
+    ///
+    /// Requires `DUAL_ROLE`.
+    const HNP_CAPABLE: bool = false;
+
     /// Enables USB device on its peripheral bus
     fn enable();
+
+    /// Performs initial setup of the internal High-Speed PHY controller
+    ///
+    /// Called by the driver during `enable` when `PHY_TYPE` is `InternalHighSpeed`. This function
+    /// should turn on the PHY LDO and PLL and wait for the PHY clock to become stable.
+    fn setup_internal_hs_phy() {}
 }
@@ -0,0 +1,78 @@
+//! PHY and core initialization shared by the device and host drivers
+
+use crate::ral::{read_reg, modify_reg, otg_global};
+use crate::target::UsbRegisters;
+use crate::{UsbPeripheral, PhyType};
+
+/// Resets the core state machines, keeping the register configuration.
+pub fn core_soft_reset(regs: UsbRegisters) {
+    modify_reg!(otg_global, regs.global(), GRSTCTL, CSRST: 1);
+    while read_reg!(otg_global, regs.global(), GRSTCTL, CSRST) == 1 {}
+
+    // Wait for AHB ready
+    while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
+}
+
+/// Selects the PHY of the peripheral and sets up the timings that depend on it.
+pub fn configure<USB: UsbPeripheral>(regs: UsbRegisters) {
+    match USB::PHY_TYPE {
+        PhyType::InternalFullSpeed => {
+            if USB::HIGH_SPEED {
+                modify_reg!(otg_global, regs.global(), GUSBCFG,
+                    TRDT: 0x9, // ??? USB turnaround time
+                    TOCAL: 0x1,
+                    PHYSEL: 1
+                );
+            } else {
+                modify_reg!(otg_global, regs.global(), GUSBCFG,
+                    TRDT: 0x6 // ??? USB turnaround time
+                );
+            }
+        }
+        PhyType::InternalHighSpeed => {
+            // Keep the internal FS transceiver powered down
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+
+            // Select the UTMI+ interface with internal VBUS handling
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                TSDPS: 0,
+                ULPIFSLS: 0,
+                PHYSEL: 0,
+                ULPIEVBUSD: 0,
+                ULPIEVBUSI: 0,
+                ULPI_UTMI_SEL: 0
+            );
+
+            // Enable the embedded HS PHY and bring up its controller
+            modify_reg!(otg_global, regs.global(), GCCFG, PHYHSEN: 1);
+            USB::setup_internal_hs_phy();
+
+            // PHY selection requires a core soft reset
+            core_soft_reset(regs);
+
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                TRDT: 0x9 // USB turnaround time for HS operation
+            );
+        }
+        PhyType::ExternalHighSpeed => {
+            // Keep the internal FS transceiver powered down
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+
+            // Select the ULPI interface with internal VBUS handling
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                TSDPS: 0,
+                ULPIFSLS: 0,
+                PHYSEL: 0,
+                ULPIEVBUSD: 0,
+                ULPIEVBUSI: 0
+            );
+
+            // PHY selection requires a core soft reset
+            core_soft_reset(regs);
+
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                TRDT: 0x9 // USB turnaround time for HS operation
+            );
+        }
+    }
+}
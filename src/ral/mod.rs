@@ -1,52 +1,66 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
 #![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
 
-pub use stm32ral::{read_reg, write_reg, modify_reg};
-
-pub mod otg_global {
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_fs_global::*;
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_hs_global::*;
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_fs_global::OTG_FS_GLOBAL as OTG_GLOBAL;
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_hs_global::OTG_HS_GLOBAL as OTG_GLOBAL;
-}
+#[macro_use]
+mod register;
 
-pub mod otg_device {
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_fs_device::*;
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_hs_device::*;
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_fs_device::OTG_FS_DEVICE as OTG_DEVICE;
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_hs_device::OTG_HS_DEVICE as OTG_DEVICE;
-}
+pub(crate) use self::register::{read_reg, write_reg, modify_reg};
+pub use self::register::RWRegister;
 
-pub mod otg_pwrclk {
-    pub use stm32ral::otg_s_pwrclk::*;
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_s_pwrclk::OTG_FS_PWRCLK as OTG_PWRCLK;
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_s_pwrclk::OTG_HS_PWRCLK as OTG_PWRCLK;
-}
+pub mod otg_global;
+pub mod otg_device;
+pub mod otg_host;
+pub mod otg_pwrclk;
 
-pub mod otg_fifo {
-    use stm32ral::RWRegister;
+pub mod otg_global_dieptxfx {
+    use super::register::RWRegister;
+    use core::marker::PhantomData;
+
+    /// Device IN endpoint transmit FIFO size register
+    pub mod DIEPTXFx {
+        fields! {
+            /// IN endpoint FIFOx transmit RAM start address
+            INEPTXSA: 0, 16;
+            /// IN endpoint TxFIFO depth
+            INEPTXFD: 16, 16;
+        }
+    }
+
+    #[repr(C)]
+    pub struct RegisterBlock {
+        pub DIEPTXFx: RWRegister<u32>,
+    }
 
-    #[cfg(feature = "fs")]
-    pub const FIFO_DEPTH_WORDS: u32 = 320;
-    #[cfg(feature = "hs")]
-    pub const FIFO_DEPTH_WORDS: u32 = 1024;
+    pub struct Instance {
+        pub(crate) addr: usize,
+        pub(crate) _marker: PhantomData<*const RegisterBlock>,
+    }
+
+    impl ::core::ops::Deref for Instance {
+        type Target = RegisterBlock;
+        #[inline(always)]
+        fn deref(&self) -> &RegisterBlock {
+            unsafe { &*(self.addr as *const _) }
+        }
+    }
 
     #[inline(always)]
-    pub fn instance(channel: usize) -> &'static RWRegister<u32> {
-        #[cfg(feature = "fs")]
-        let base_address = 0x5000_0000;
-        #[cfg(feature = "hs")]
-        let base_address = 0x4004_0000;
+    pub fn instance(base_address: usize, index: usize) -> Instance {
+        assert!((1..=15).contains(&index));
+        Instance {
+            addr: base_address + 0x104 + 0x4 * (index - 1),
+            _marker: PhantomData,
+        }
+    }
+}
 
+pub mod otg_fifo {
+    use super::register::RWRegister;
+
+    #[inline(always)]
+    pub fn instance(base_address: usize, channel: usize) -> &'static RWRegister<u32> {
         assert!(channel <= 15);
         let address = base_address + 0x1000 + channel * 0x1000;
         unsafe { &*(address as *const RWRegister<u32>) }
@@ -54,38 +68,111 @@ pub mod otg_fifo {
 }
 
 pub mod endpoint_in {
-    use stm32ral::RWRegister;
+    use super::register::{RWRegister, RORegister};
     use core::marker::PhantomData;
 
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_fs_device::{
-        DIEPCTL1 as DIEPCTL,
-        DIEPINT1 as DIEPINT,
-        DIEPTSIZ1 as DIEPTSIZ,
-        DTXFSTS1 as DTXFSTS,
-    };
-
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_hs_device::{
-        DIEPCTL1 as DIEPCTL,
-        DIEPINT1 as DIEPINT,
-        DIEPTSIZ1 as DIEPTSIZ,
-        DTXFSTS1 as DTXFSTS,
-    };
+    /// Device IN endpoint control register
+    pub mod DIEPCTL {
+        fields! {
+            /// Maximum packet size
+            MPSIZ: 0, 11;
+            /// USB active endpoint
+            USBAEP: 15, 1;
+            /// Even/odd frame
+            EONUM_DPID: 16, 1;
+            /// NAK status
+            NAKSTS: 17, 1;
+            /// Endpoint type
+            EPTYP: 18, 2;
+            /// STALL handshake
+            STALL: 21, 1;
+            /// TxFIFO number
+            TXFNUM: 22, 4;
+            /// Clear NAK
+            CNAK: 26, 1;
+            /// Set NAK
+            SNAK: 27, 1;
+            /// Set DATA0 PID
+            SD0PID_SEVNFRM: 28, 1;
+            /// Set odd frame
+            SODDFRM: 29, 1;
+            /// Endpoint disable
+            EPDIS: 30, 1;
+            /// Endpoint enable
+            EPENA: 31, 1;
+        }
+    }
+
+    /// Device IN endpoint interrupt register
+    pub mod DIEPINT {
+        fields! {
+            /// Transfer completed interrupt
+            XFRC: 0, 1;
+            /// Endpoint disabled interrupt
+            EPDISD: 1, 1;
+            /// Timeout condition
+            TOC: 3, 1;
+            /// IN token received when TxFIFO is empty
+            ITTXFE: 4, 1;
+            /// IN endpoint NAK effective
+            INEPNE: 6, 1;
+            /// Transmit FIFO empty
+            TXFE: 7, 1;
+            /// Transmit Fifo Underrun
+            TXFIFOUDRN: 8, 1;
+            /// Buffer not available interrupt
+            BNA: 9, 1;
+            /// Packet dropped status
+            PKTDRPSTS: 11, 1;
+            /// Babble error interrupt
+            BERR: 12, 1;
+            /// NAK interrupt
+            NAK: 13, 1;
+        }
+    }
+
+    /// Device IN endpoint transfer size register
+    pub mod DIEPTSIZ {
+        fields! {
+            /// Transfer size
+            XFRSIZ: 0, 19;
+            /// Packet count
+            PKTCNT: 19, 10;
+            /// Multi count
+            MCNT: 29, 2;
+        }
+    }
+
+    /// Device IN endpoint DMA address register
+    pub mod DIEPDMA {
+        fields! {
+            /// DMA address
+            DMAADDR: 0, 32;
+        }
+    }
 
+    /// Device IN endpoint transmit FIFO status register
+    pub mod DTXFSTS {
+        fields! {
+            /// IN endpoint TxFIFO space avail
+            INEPTFSAV: 0, 16;
+        }
+    }
+
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DIEPCTL: RWRegister<u32>,
         _reserved0: u32,
         pub DIEPINT: RWRegister<u32>,
         _reserved1: u32,
         pub DIEPTSIZ: RWRegister<u32>,
-        _reserved2: u32,
-        pub DTXFSTS: RWRegister<u32>,
+        pub DIEPDMA: RWRegister<u32>,
+        pub DTXFSTS: RORegister<u32>,
         _reserved3: u32,
     }
 
     pub struct Instance {
-        pub(crate) addr: u32,
+        pub(crate) addr: usize,
         pub(crate) _marker: PhantomData<*const RegisterBlock>,
     }
 
@@ -98,48 +185,95 @@ pub mod endpoint_in {
     }
 
     #[inline(always)]
-    pub fn instance(index: usize) -> Instance {
-        #[cfg(feature = "fs")]
-        let base_address = 0x5000_0000;
-        #[cfg(feature = "hs")]
-        let base_address = 0x4004_0000;
-
+    pub fn instance(base_address: usize, index: usize) -> Instance {
         Instance {
-            addr: base_address + 0x900 + 0x20 * (index as u32),
+            addr: base_address + 0x900 + 0x20 * index,
             _marker: PhantomData,
         }
     }
 }
 
 pub mod endpoint0_out {
-    use stm32ral::RWRegister;
+    use super::register::{RWRegister};
     use core::marker::PhantomData;
 
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_fs_device::{
-        DOEPCTL0,
-        DOEPINT0,
-        DOEPTSIZ0,
-    };
+    /// Device OUT endpoint 0 control register
+    pub mod DOEPCTL0 {
+        fields! {
+            /// Maximum packet size
+            MPSIZ: 0, 2;
+            /// USB active endpoint
+            USBAEP: 15, 1;
+            /// NAK status
+            NAKSTS: 17, 1;
+            /// Endpoint type
+            EPTYP: 18, 2;
+            /// Snoop mode
+            SNPM: 20, 1;
+            /// STALL handshake
+            STALL: 21, 1;
+            /// Clear NAK
+            CNAK: 26, 1;
+            /// Set NAK
+            SNAK: 27, 1;
+            /// Endpoint disable
+            EPDIS: 30, 1;
+            /// Endpoint enable
+            EPENA: 31, 1;
+        }
+    }
 
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_hs_device::{
-        DOEPCTL0,
-        DOEPINT0,
-        DOEPTSIZ0,
-    };
+    /// Device OUT endpoint 0 interrupt register
+    pub mod DOEPINT0 {
+        fields! {
+            /// Transfer completed interrupt
+            XFRC: 0, 1;
+            /// Endpoint disabled interrupt
+            EPDISD: 1, 1;
+            /// SETUP phase done
+            STUP: 3, 1;
+            /// OUT token received when endpoint disabled
+            OTEPDIS: 4, 1;
+            /// Back-to-back SETUP packets received
+            B2BSTUP: 6, 1;
+            /// NYET interrupt
+            NYET: 14, 1;
+        }
+    }
+
+    /// Device OUT endpoint 0 transfer size register
+    pub mod DOEPTSIZ0 {
+        fields! {
+            /// Transfer size
+            XFRSIZ: 0, 7;
+            /// Packet count
+            PKTCNT: 19, 1;
+            /// SETUP packet count
+            STUPCNT: 29, 2;
+        }
+    }
 
+    /// Device OUT endpoint 0 DMA address register
+    pub mod DOEPDMA0 {
+        fields! {
+            /// DMA address
+            DMAADDR: 0, 32;
+        }
+    }
+
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DOEPCTL0: RWRegister<u32>,
         _reserved0: u32,
         pub DOEPINT0: RWRegister<u32>,
         _reserved1: u32,
         pub DOEPTSIZ0: RWRegister<u32>,
-        _reserved2: [u32; 3],
+        pub DOEPDMA0: RWRegister<u32>,
+        _reserved2: [u32; 2],
     }
 
     pub struct Instance {
-        pub(crate) addr: u32,
+        pub(crate) addr: usize,
         pub(crate) _marker: PhantomData<*const RegisterBlock>,
     }
 
@@ -152,12 +286,7 @@ pub mod endpoint0_out {
     }
 
     #[inline(always)]
-    pub fn instance() -> Instance {
-        #[cfg(feature = "fs")]
-        let base_address = 0x5000_0000;
-        #[cfg(feature = "hs")]
-        let base_address = 0x4004_0000;
-
+    pub fn instance(base_address: usize) -> Instance {
         Instance {
             addr: base_address + 0xb00,
             _marker: PhantomData,
@@ -166,34 +295,92 @@ pub mod endpoint0_out {
 }
 
 pub mod endpoint_out {
-    use stm32ral::RWRegister;
+    use super::register::{RWRegister};
     use core::marker::PhantomData;
 
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_fs_device::{
-        DOEPCTL1 as DOEPCTL,
-        DOEPINT1 as DOEPINT,
-        DOEPTSIZ1 as DOEPTSIZ,
-    };
+    /// Device OUT endpoint control register
+    pub mod DOEPCTL {
+        fields! {
+            /// Maximum packet size
+            MPSIZ: 0, 11;
+            /// USB active endpoint
+            USBAEP: 15, 1;
+            /// Even odd frame/Endpoint data PID
+            EONUM_DPID: 16, 1;
+            /// NAK status
+            NAKSTS: 17, 1;
+            /// Endpoint type
+            EPTYP: 18, 2;
+            /// Snoop mode
+            SNPM: 20, 1;
+            /// STALL handshake
+            STALL: 21, 1;
+            /// Clear NAK
+            CNAK: 26, 1;
+            /// Set NAK
+            SNAK: 27, 1;
+            /// Set DATA0 PID/Set even frame
+            SD0PID_SEVNFRM: 28, 1;
+            /// Set odd frame
+            SODDFRM: 29, 1;
+            /// Endpoint disable
+            EPDIS: 30, 1;
+            /// Endpoint enable
+            EPENA: 31, 1;
+        }
+    }
+
+    /// Device OUT endpoint interrupt register
+    pub mod DOEPINT {
+        fields! {
+            /// Transfer completed interrupt
+            XFRC: 0, 1;
+            /// Endpoint disabled interrupt
+            EPDISD: 1, 1;
+            /// SETUP phase done
+            STUP: 3, 1;
+            /// OUT token received when endpoint disabled
+            OTEPDIS: 4, 1;
+            /// Back-to-back SETUP packets received
+            B2BSTUP: 6, 1;
+            /// NYET interrupt
+            NYET: 14, 1;
+        }
+    }
+
+    /// Device OUT endpoint transfer size register
+    pub mod DOEPTSIZ {
+        fields! {
+            /// Transfer size
+            XFRSIZ: 0, 19;
+            /// Packet count
+            PKTCNT: 19, 10;
+            /// Received data PID/SETUP packet count
+            RXDPID_STUPCNT: 29, 2;
+        }
+    }
 
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_hs_device::{
-        DOEPCTL1 as DOEPCTL,
-        DOEPINT1 as DOEPINT,
-        DOEPTSIZ1 as DOEPTSIZ,
-    };
+    /// Device OUT endpoint DMA address register
+    pub mod DOEPDMA {
+        fields! {
+            /// DMA address
+            DMAADDR: 0, 32;
+        }
+    }
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DOEPCTL: RWRegister<u32>,
         _reserved0: u32,
         pub DOEPINT: RWRegister<u32>,
         _reserved1: u32,
         pub DOEPTSIZ: RWRegister<u32>,
-        _reserved2: [u32; 3],
+        pub DOEPDMA: RWRegister<u32>,
+        _reserved2: [u32; 2],
     }
 
     pub struct Instance {
-        pub(crate) addr: u32,
+        pub(crate) addr: usize,
         pub(crate) _marker: PhantomData<*const RegisterBlock>,
     }
 
@@ -206,14 +393,165 @@ pub mod endpoint_out {
     }
 
     #[inline(always)]
-    pub fn instance(index: usize) -> Instance {
-        #[cfg(feature = "fs")]
-        let base_address = 0x5000_0000;
-        #[cfg(feature = "hs")]
-        let base_address = 0x4004_0000;
+    pub fn instance(base_address: usize, index: usize) -> Instance {
+        Instance {
+            addr: base_address + 0xb00 + 0x20 * index,
+            _marker: PhantomData,
+        }
+    }
+}
 
+pub mod host_channel {
+    use super::register::{RWRegister};
+    use core::marker::PhantomData;
+
+    /// Host channel characteristics register
+    pub mod HCCHAR {
+        fields! {
+            /// Maximum packet size
+            MPSIZ: 0, 11;
+            /// Endpoint number
+            EPNUM: 11, 4;
+            /// Endpoint direction
+            EPDIR: 15, 1;
+            /// Low-speed device
+            LSDEV: 17, 1;
+            /// Endpoint type
+            EPTYP: 18, 2;
+            /// Multicount
+            MCNT: 20, 2;
+            /// Device address
+            DAD: 22, 7;
+            /// Odd frame
+            ODDFRM: 29, 1;
+            /// Channel disable
+            CHDIS: 30, 1;
+            /// Channel enable
+            CHENA: 31, 1;
+        }
+    }
+
+    /// Host channel split control register
+    pub mod HCSPLT {
+        fields! {
+            /// Port address
+            PRTADDR: 0, 7;
+            /// Hub address
+            HUBADDR: 7, 7;
+            /// Transaction position
+            XACTPOS: 14, 2;
+            /// Do complete split
+            COMPLSPLT: 16, 1;
+            /// Split enable
+            SPLITEN: 31, 1;
+        }
+    }
+
+    /// Host channel interrupt register
+    pub mod HCINT {
+        fields! {
+            /// Transfer completed
+            XFRC: 0, 1;
+            /// Channel halted
+            CHH: 1, 1;
+            /// AHB error
+            AHBERR: 2, 1;
+            /// STALL response received interrupt
+            STALL: 3, 1;
+            /// NAK response received interrupt
+            NAK: 4, 1;
+            /// ACK response received/transmitted interrupt
+            ACK: 5, 1;
+            /// Response received interrupt
+            NYET: 6, 1;
+            /// Transaction error
+            TXERR: 7, 1;
+            /// Babble error
+            BBERR: 8, 1;
+            /// Frame overrun
+            FRMOR: 9, 1;
+            /// Data toggle error
+            DTERR: 10, 1;
+        }
+    }
+
+    /// Host channel interrupt mask register
+    pub mod HCINTMSK {
+        fields! {
+            /// Transfer completed mask
+            XFRCM: 0, 1;
+            /// Channel halted mask
+            CHHM: 1, 1;
+            /// AHB error
+            AHBERR: 2, 1;
+            /// STALL response received interrupt mask
+            STALLM: 3, 1;
+            /// NAK response received interrupt mask
+            NAKM: 4, 1;
+            /// ACK response received/transmitted interrupt mask
+            ACKM: 5, 1;
+            /// Response received interrupt mask
+            NYET: 6, 1;
+            /// Transaction error mask
+            TXERRM: 7, 1;
+            /// Babble error mask
+            BBERRM: 8, 1;
+            /// Frame overrun mask
+            FRMORM: 9, 1;
+            /// Data toggle error mask
+            DTERRM: 10, 1;
+        }
+    }
+
+    /// Host channel transfer size register
+    pub mod HCTSIZ {
+        fields! {
+            /// Transfer size
+            XFRSIZ: 0, 19;
+            /// Packet count
+            PKTCNT: 19, 10;
+            /// Data PID
+            DPID: 29, 2;
+        }
+    }
+
+    /// Host channel DMA address register
+    pub mod HCDMA {
+        fields! {
+            /// DMA address
+            DMAADDR: 0, 32;
+        }
+    }
+
+    #[repr(C)]
+    pub struct RegisterBlock {
+        pub HCCHAR: RWRegister<u32>,
+        pub HCSPLT: RWRegister<u32>,
+        pub HCINT: RWRegister<u32>,
+        pub HCINTMSK: RWRegister<u32>,
+        pub HCTSIZ: RWRegister<u32>,
+        pub HCDMA: RWRegister<u32>,
+        _reserved0: [u32; 2],
+    }
+
+    pub struct Instance {
+        pub(crate) addr: usize,
+        pub(crate) _marker: PhantomData<*const RegisterBlock>,
+    }
+
+    impl ::core::ops::Deref for Instance {
+        type Target = RegisterBlock;
+        #[inline(always)]
+        fn deref(&self) -> &RegisterBlock {
+            unsafe { &*(self.addr as *const _) }
+        }
+    }
+
+    #[inline(always)]
+    pub fn instance(base_address: usize, index: usize) -> Instance {
+        assert!(index <= 15);
         Instance {
-            addr: base_address + 0xb00 + 0x20 * (index as u32),
+            addr: base_address + 0x500 + 0x20 * index,
             _marker: PhantomData,
         }
     }
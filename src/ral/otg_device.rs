@@ -0,0 +1,203 @@
+//! Device mode registers of the OTG core
+
+use super::register::{RWRegister, RORegister};
+
+/// Device configuration register
+pub mod DCFG {
+    fields! {
+        /// Device speed
+        DSPD: 0, 2;
+        /// Nonzero-length status OUT handshake
+        NZLSOHSK: 2, 1;
+        /// Device address
+        DAD: 4, 7;
+        /// Periodic (micro)frame interval
+        PFIVL: 11, 2;
+        /// Periodic scheduling interval
+        PERSCHIVL: 24, 2;
+    }
+}
+
+/// Device control register
+pub mod DCTL {
+    fields! {
+        /// Remote wakeup signaling
+        RWUSIG: 0, 1;
+        /// Soft disconnect
+        SDIS: 1, 1;
+        /// Global IN NAK status
+        GINSTS: 2, 1;
+        /// Global OUT NAK status
+        GONSTS: 3, 1;
+        /// Test control
+        TCTL: 4, 3;
+        /// Set global IN NAK
+        SGINAK: 7, 1;
+        /// Clear global IN NAK
+        CGINAK: 8, 1;
+        /// Set global OUT NAK
+        SGONAK: 9, 1;
+        /// Clear global OUT NAK
+        CGONAK: 10, 1;
+        /// Power-on programming done
+        POPRGDNE: 11, 1;
+    }
+}
+
+/// Device status register
+pub mod DSTS {
+    fields! {
+        /// Suspend status
+        SUSPSTS: 0, 1;
+        /// Enumerated speed
+        ENUMSPD: 1, 2;
+        /// Erratic error
+        EERR: 3, 1;
+        /// Frame number of the received SOF
+        FNSOF: 8, 14;
+    }
+}
+
+/// Device IN endpoint common interrupt mask register
+pub mod DIEPMSK {
+    fields! {
+        /// Transfer completed interrupt mask
+        XFRCM: 0, 1;
+        /// Endpoint disabled interrupt mask
+        EPDM: 1, 1;
+        /// Timeout condition mask (nonisochronous endpoints)
+        TOM: 3, 1;
+        /// IN token received when TxFIFO empty mask
+        ITTXFEMSK: 4, 1;
+        /// IN token received with EP mismatch mask
+        INEPNMM: 5, 1;
+        /// IN endpoint NAK effective mask
+        INEPNEM: 6, 1;
+        /// FIFO underrun mask
+        TXFURM: 8, 1;
+        /// BNA interrupt mask
+        BIM: 9, 1;
+    }
+}
+
+/// Device OUT endpoint common interrupt mask register
+pub mod DOEPMSK {
+    fields! {
+        /// Transfer completed interrupt mask
+        XFRCM: 0, 1;
+        /// Endpoint disabled interrupt mask
+        EPDM: 1, 1;
+        /// SETUP phase done mask
+        STUPM: 3, 1;
+        /// OUT token received when endpoint disabled mask
+        OTEPDM: 4, 1;
+        /// Back-to-back SETUP packets received mask
+        B2BSTUP: 6, 1;
+        /// OUT packet error mask
+        OPEM: 8, 1;
+        /// BNA interrupt mask
+        BOIM: 9, 1;
+    }
+}
+
+/// Device all endpoints interrupt register
+pub mod DAINT {
+    fields! {
+        /// IN endpoint interrupt bits
+        IEPINT: 0, 16;
+        /// OUT endpoint interrupt bits
+        OEPINT: 16, 16;
+    }
+}
+
+/// All endpoints interrupt mask register
+pub mod DAINTMSK {
+    fields! {
+        /// IN EP interrupt mask bits
+        IEPM: 0, 16;
+        /// OUT EP interrupt mask bits
+        OEPM: 16, 16;
+    }
+}
+
+/// Device VBUS discharge time register
+pub mod DVBUSDIS {
+    fields! {
+        /// Device VBUS discharge time
+        VBUSDT: 0, 16;
+    }
+}
+
+/// Device VBUS pulsing time register
+pub mod DVBUSPULSE {
+    fields! {
+        /// Device VBUS pulsing time
+        DVBUSP: 0, 12;
+    }
+}
+
+/// Device threshold control register
+pub mod DTHRCTL {
+    fields! {
+        /// Nonisochronous IN endpoints threshold enable
+        NONISOTHREN: 0, 1;
+        /// ISO IN endpoint threshold enable
+        ISOTHREN: 1, 1;
+        /// Transmit threshold length
+        TXTHRLEN: 2, 9;
+        /// Receive threshold enable
+        RXTHREN: 16, 1;
+        /// Receive threshold length
+        RXTHRLEN: 17, 9;
+        /// Arbiter parking enable
+        ARPEN: 27, 1;
+    }
+}
+
+/// Device IN endpoint FIFO empty interrupt mask register
+pub mod DIEPEMPMSK {
+    fields! {
+        /// IN EP Tx FIFO empty interrupt mask bits
+        INEPTXFEM: 0, 16;
+    }
+}
+
+#[repr(C)]
+pub struct RegisterBlock {
+    /// Device configuration register
+    pub DCFG: RWRegister<u32>,
+
+    /// Device control register
+    pub DCTL: RWRegister<u32>,
+
+    /// Device status register
+    pub DSTS: RORegister<u32>,
+
+    _reserved0: u32,
+
+    /// Device IN endpoint common interrupt mask register
+    pub DIEPMSK: RWRegister<u32>,
+
+    /// Device OUT endpoint common interrupt mask register
+    pub DOEPMSK: RWRegister<u32>,
+
+    /// Device all endpoints interrupt register
+    pub DAINT: RORegister<u32>,
+
+    /// All endpoints interrupt mask register
+    pub DAINTMSK: RWRegister<u32>,
+
+    _reserved1: [u32; 2],
+
+    /// Device VBUS discharge time register
+    pub DVBUSDIS: RWRegister<u32>,
+
+    /// Device VBUS pulsing time register
+    pub DVBUSPULSE: RWRegister<u32>,
+
+    /// Device threshold control register
+    pub DTHRCTL: RWRegister<u32>,
+
+    /// Device IN endpoint FIFO empty interrupt mask register
+    pub DIEPEMPMSK: RWRegister<u32>,
+}
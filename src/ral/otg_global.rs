@@ -0,0 +1,408 @@
+//! Global registers of the OTG core
+
+use super::register::{RWRegister, RORegister};
+
+/// Control and status register
+pub mod GOTGCTL {
+    fields! {
+        /// Session request success
+        SRQSCS: 0, 1;
+        /// Session request
+        SRQ: 1, 1;
+        /// B-peripheral session valid override enable (cores with the newer `GCCFG` layout)
+        BVALOEN: 6, 1;
+        /// B-peripheral session valid override value (cores with the newer `GCCFG` layout)
+        BVALOVAL: 7, 1;
+        /// Host negotiation success
+        HNGSCS: 8, 1;
+        /// HNP request
+        HNPRQ: 9, 1;
+        /// Host set HNP enable
+        HSHNPEN: 10, 1;
+        /// Device HNP enabled
+        DHNPEN: 11, 1;
+        /// Connector ID status
+        CIDSTS: 16, 1;
+        /// Long/short debounce time
+        DBCT: 17, 1;
+        /// A-session valid
+        ASVLD: 18, 1;
+        /// B-session valid
+        BSVLD: 19, 1;
+    }
+}
+
+/// Interrupt register
+pub mod GOTGINT {
+    fields! {
+        /// Session end detected
+        SEDET: 2, 1;
+        /// Session request success status change
+        SRSSCHG: 8, 1;
+        /// Host negotiation success status change
+        HNSSCHG: 9, 1;
+        /// Host negotiation detected
+        HNGDET: 17, 1;
+        /// A-device timeout change
+        ADTOCHG: 18, 1;
+        /// Debounce done
+        DBCDNE: 19, 1;
+    }
+}
+
+/// AHB configuration register
+pub mod GAHBCFG {
+    fields! {
+        /// Global interrupt mask
+        GINT: 0, 1;
+        /// Burst length/type
+        HBSTLEN: 1, 4;
+        /// DMA enable
+        DMAEN: 5, 1;
+        /// TxFIFO empty level
+        TXFELVL: 7, 1;
+        /// Periodic TxFIFO empty level
+        PTXFELVL: 8, 1;
+    }
+}
+
+/// USB configuration register
+pub mod GUSBCFG {
+    fields! {
+        /// FS timeout calibration
+        TOCAL: 0, 3;
+        /// ULPI or UTMI+ PHY select (embedded HS PHY cores only)
+        ULPI_UTMI_SEL: 4, 1;
+        /// USB 2.0 high-speed ULPI PHY or USB 1.1 full-speed serial transceiver select
+        PHYSEL: 6, 1;
+        /// SRP-capable
+        SRPCAP: 8, 1;
+        /// HNP-capable
+        HNPCAP: 9, 1;
+        /// USB turnaround time
+        TRDT: 10, 4;
+        /// PHY Low-power clock select
+        PHYLPCS: 15, 1;
+        /// ULPI FS/LS select
+        ULPIFSLS: 17, 1;
+        /// ULPI Auto-resume
+        ULPIAR: 18, 1;
+        /// ULPI Clock SuspendM
+        ULPICSM: 19, 1;
+        /// ULPI External VBUS Drive
+        ULPIEVBUSD: 20, 1;
+        /// ULPI external VBUS indicator
+        ULPIEVBUSI: 21, 1;
+        /// TermSel DLine pulsing selection
+        TSDPS: 22, 1;
+        /// Indicator complement
+        PCCI: 23, 1;
+        /// Indicator pass through
+        PTCI: 24, 1;
+        /// ULPI interface protect disable
+        ULPIIPD: 25, 1;
+        /// Forced host mode
+        FHMOD: 29, 1;
+        /// Forced peripheral mode
+        FDMOD: 30, 1;
+        /// Corrupt Tx packet
+        CTXPKT: 31, 1;
+    }
+}
+
+/// Reset register
+pub mod GRSTCTL {
+    fields! {
+        /// Core soft reset
+        CSRST: 0, 1;
+        /// HCLK soft reset
+        HSRST: 1, 1;
+        /// Host frame counter reset
+        FCRST: 2, 1;
+        /// RxFIFO flush
+        RXFFLSH: 4, 1;
+        /// TxFIFO flush
+        TXFFLSH: 5, 1;
+        /// TxFIFO number
+        TXFNUM: 6, 5;
+        /// DMA request signal
+        DMAREQ: 30, 1;
+        /// AHB master idle
+        AHBIDL: 31, 1;
+    }
+}
+
+/// Core interrupt register
+pub mod GINTSTS {
+    fields! {
+        /// Current mode of operation
+        CMOD: 0, 1;
+        /// Mode mismatch interrupt
+        MMIS: 1, 1;
+        /// OTG interrupt
+        OTGINT: 2, 1;
+        /// Start of frame
+        SOF: 3, 1;
+        /// RxFIFO nonempty
+        RXFLVL: 4, 1;
+        /// Nonperiodic TxFIFO empty
+        NPTXFE: 5, 1;
+        /// Global IN nonperiodic NAK effective
+        GINAKEFF: 6, 1;
+        /// Global OUT NAK effective
+        GONAKEFF: 7, 1;
+        /// Early suspend
+        ESUSP: 10, 1;
+        /// USB suspend
+        USBSUSP: 11, 1;
+        /// USB reset
+        USBRST: 12, 1;
+        /// Enumeration done
+        ENUMDNE: 13, 1;
+        /// Isochronous OUT packet dropped interrupt
+        ISOODRP: 14, 1;
+        /// End of periodic frame interrupt
+        EOPF: 15, 1;
+        /// IN endpoint interrupt
+        IEPINT: 18, 1;
+        /// OUT endpoint interrupt
+        OEPINT: 19, 1;
+        /// Incomplete isochronous IN transfer
+        IISOIXFR: 20, 1;
+        /// Incomplete periodic transfer
+        IPXFR_INCOMPISOOUT: 21, 1;
+        /// Data fetch suspended
+        DATAFSUSP: 22, 1;
+        /// Host port interrupt
+        HPRTINT: 24, 1;
+        /// Host channels interrupt
+        HCINT: 25, 1;
+        /// Periodic TxFIFO empty
+        PTXFE: 26, 1;
+        /// Connector ID status change
+        CIDSCHG: 28, 1;
+        /// Disconnect detected interrupt
+        DISCINT: 29, 1;
+        /// Session request/new session detected interrupt
+        SRQINT: 30, 1;
+        /// Resume/remote wakeup detected interrupt
+        WKUPINT: 31, 1;
+    }
+}
+
+/// Interrupt mask register
+pub mod GINTMSK {
+    fields! {
+        /// Mode mismatch interrupt mask
+        MMISM: 1, 1;
+        /// OTG interrupt mask
+        OTGINT: 2, 1;
+        /// Start of frame mask
+        SOFM: 3, 1;
+        /// Receive FIFO nonempty mask
+        RXFLVLM: 4, 1;
+        /// Nonperiodic TxFIFO empty mask
+        NPTXFEM: 5, 1;
+        /// Global nonperiodic IN NAK effective mask
+        GINAKEFFM: 6, 1;
+        /// Global OUT NAK effective mask
+        GONAKEFFM: 7, 1;
+        /// Early suspend mask
+        ESUSPM: 10, 1;
+        /// USB suspend mask
+        USBSUSPM: 11, 1;
+        /// USB reset mask
+        USBRST: 12, 1;
+        /// Enumeration done mask
+        ENUMDNEM: 13, 1;
+        /// Isochronous OUT packet dropped interrupt mask
+        ISOODRPM: 14, 1;
+        /// End of periodic frame interrupt mask
+        EOPFM: 15, 1;
+        /// Endpoint mismatch interrupt mask
+        EPMISM: 17, 1;
+        /// IN endpoints interrupt mask
+        IEPINT: 18, 1;
+        /// OUT endpoints interrupt mask
+        OEPINT: 19, 1;
+        /// Incomplete isochronous IN transfer mask
+        IISOIXFRM: 20, 1;
+        /// Incomplete periodic transfer mask
+        PXFRM_IISOOXFRM: 21, 1;
+        /// Data fetch suspended mask
+        FSUSPM: 22, 1;
+        /// Host port interrupt mask
+        PRTIM: 24, 1;
+        /// Host channels interrupt mask
+        HCIM: 25, 1;
+        /// Periodic TxFIFO empty mask
+        PTXFEM: 26, 1;
+        /// Connector ID status change mask
+        CIDSCHGM: 28, 1;
+        /// Disconnect detected interrupt mask
+        DISCINT: 29, 1;
+        /// Session request/new session detected interrupt mask
+        SRQIM: 30, 1;
+        /// Resume/remote wakeup detected interrupt mask
+        WUIM: 31, 1;
+    }
+}
+
+/// Receive status debug read register
+pub mod GRXSTSR {
+    fields! {
+        /// Channel number
+        CHNUM: 0, 4;
+        /// Endpoint number
+        EPNUM: 0, 4;
+        /// Byte count
+        BCNT: 4, 11;
+        /// Data PID
+        DPID: 15, 2;
+        /// Packet status
+        PKTSTS: 17, 4;
+        /// Frame number
+        FRMNUM: 21, 4;
+    }
+}
+
+/// Receive FIFO size register
+pub mod GRXFSIZ {
+    fields! {
+        /// RxFIFO depth
+        RXFD: 0, 16;
+    }
+}
+
+/// Endpoint 0 transmit FIFO size register (nonperiodic transmit FIFO size in host mode)
+pub mod DIEPTXF0 {
+    fields! {
+        /// Nonperiodic transmit RAM start address
+        NPTXFSA: 0, 16;
+        /// Endpoint 0 transmit RAM start address
+        TX0FSA: 0, 16;
+        /// Nonperiodic TxFIFO depth
+        NPTXFD: 16, 16;
+        /// Endpoint 0 TxFIFO depth
+        TX0FD: 16, 16;
+    }
+}
+
+/// Nonperiodic transmit FIFO/queue status register
+pub mod GNPTXSTS {
+    fields! {
+        /// Nonperiodic TxFIFO space available
+        NPTXFSAV: 0, 16;
+        /// Nonperiodic transmit request queue space available
+        NPTQXSAV: 16, 8;
+        /// Top of the nonperiodic transmit request queue
+        NPTXQTOP: 24, 7;
+    }
+}
+
+/// General core configuration register
+///
+/// Only `PWRDWN` is common to both layouts of the register, see
+/// `UsbPeripheral::VBUS_DETECTION_BLOCK`. Fields of the two layouts overlap, e.g. `NOVBUSSENS`
+/// and `VBDEN`, and must only be used on the matching cores.
+pub mod GCCFG {
+    fields! {
+        /// Power down
+        PWRDWN: 16, 1;
+
+        // Older layout, e.g. on STM32F1/F2/F4x5/F4x7
+
+        /// Enable I2C bus connection for the external I2C PHY interface
+        I2CPADEN: 17, 1;
+        /// Enable the VBUS sensing device
+        VBUSASEN: 18, 1;
+        /// Enable the VBUS sensing device
+        VBUSBSEN: 19, 1;
+        /// SOF output enable
+        SOFOUTEN: 20, 1;
+        /// VBUS sensing disable option
+        NOVBUSSENS: 21, 1;
+
+        // Newer layout, e.g. on STM32F446/F7/L4
+
+        /// USB VBUS detection enable
+        VBDEN: 21, 1;
+        /// USB high-speed PHY enable (embedded HS PHY cores only)
+        PHYHSEN: 23, 1;
+    }
+}
+
+/// Core ID register
+pub mod CID {
+    fields! {
+        /// Product ID field
+        PRODUCT_ID: 0, 32;
+    }
+}
+
+/// Host periodic transmit FIFO size register
+pub mod HPTXFSIZ {
+    fields! {
+        /// Host periodic TxFIFO start address
+        PTXSA: 0, 16;
+        /// Host periodic TxFIFO depth
+        PTXFD: 16, 16;
+    }
+}
+
+/// Receive status read and pop register
+pub mod GRXSTSP {
+    pub use super::GRXSTSR::*;
+}
+
+#[repr(C)]
+pub struct RegisterBlock {
+    /// Control and status register
+    pub GOTGCTL: RWRegister<u32>,
+
+    /// Interrupt register
+    pub GOTGINT: RWRegister<u32>,
+
+    /// AHB configuration register
+    pub GAHBCFG: RWRegister<u32>,
+
+    /// USB configuration register
+    pub GUSBCFG: RWRegister<u32>,
+
+    /// Reset register
+    pub GRSTCTL: RWRegister<u32>,
+
+    /// Core interrupt register
+    pub GINTSTS: RWRegister<u32>,
+
+    /// Interrupt mask register
+    pub GINTMSK: RWRegister<u32>,
+
+    /// Receive status debug read register
+    pub GRXSTSR: RORegister<u32>,
+
+    /// Receive status read and pop register
+    pub GRXSTSP: RORegister<u32>,
+
+    /// Receive FIFO size register
+    pub GRXFSIZ: RWRegister<u32>,
+
+    /// Endpoint 0 transmit FIFO size register
+    pub DIEPTXF0: RWRegister<u32>,
+
+    /// Nonperiodic transmit FIFO/queue status register
+    pub GNPTXSTS: RORegister<u32>,
+
+    _reserved0: [u32; 2],
+
+    /// General core configuration register
+    pub GCCFG: RWRegister<u32>,
+
+    /// Core ID register
+    pub CID: RWRegister<u32>,
+
+    _reserved1: [u32; 48],
+
+    /// Host periodic transmit FIFO size register
+    pub HPTXFSIZ: RWRegister<u32>,
+}
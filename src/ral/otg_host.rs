@@ -0,0 +1,119 @@
+//! Host mode registers of the OTG core
+
+use super::register::{RWRegister, RORegister};
+
+/// Host configuration register
+pub mod HCFG {
+    fields! {
+        /// FS/LS PHY clock select
+        FSLSPCS: 0, 2;
+        /// FS- and LS-only support
+        FSLSS: 2, 1;
+    }
+}
+
+/// Host frame interval register
+pub mod HFIR {
+    fields! {
+        /// Frame interval
+        FRIVL: 0, 16;
+    }
+}
+
+/// Host frame number/frame time remaining register
+pub mod HFNUM {
+    fields! {
+        /// Frame number
+        FRNUM: 0, 16;
+        /// Frame time remaining
+        FTREM: 16, 16;
+    }
+}
+
+/// Host periodic transmit FIFO/queue status register
+pub mod HPTXSTS {
+    fields! {
+        /// Periodic transmit data FIFO space available
+        PTXFSAVL: 0, 16;
+        /// Periodic transmit request queue space available
+        PTXQSAV: 16, 8;
+        /// Top of the periodic transmit request queue
+        PTXQTOP: 24, 8;
+    }
+}
+
+/// Host all channels interrupt register
+pub mod HAINT {
+    fields! {
+        /// Channel interrupts
+        HAINT: 0, 16;
+    }
+}
+
+/// Host all channels interrupt mask register
+pub mod HAINTMSK {
+    fields! {
+        /// Channel interrupt mask
+        HAINTM: 0, 16;
+    }
+}
+
+/// Host port control and status register
+pub mod HPRT {
+    fields! {
+        /// Port connect status
+        PCSTS: 0, 1;
+        /// Port connect detected
+        PCDET: 1, 1;
+        /// Port enable
+        PENA: 2, 1;
+        /// Port enable/disable change
+        PENCHNG: 3, 1;
+        /// Port overcurrent active
+        POCA: 4, 1;
+        /// Port overcurrent change
+        POCCHNG: 5, 1;
+        /// Port resume
+        PRES: 6, 1;
+        /// Port suspend
+        PSUSP: 7, 1;
+        /// Port reset
+        PRST: 8, 1;
+        /// Port line status
+        PLSTS: 10, 2;
+        /// Port power
+        PPWR: 12, 1;
+        /// Port test control
+        PTCTL: 13, 4;
+        /// Port speed
+        PSPD: 17, 2;
+    }
+}
+
+#[repr(C)]
+pub struct RegisterBlock {
+    /// Host configuration register
+    pub HCFG: RWRegister<u32>,
+
+    /// Host frame interval register
+    pub HFIR: RWRegister<u32>,
+
+    /// Host frame number/frame time remaining register
+    pub HFNUM: RORegister<u32>,
+
+    _reserved0: u32,
+
+    /// Host periodic transmit FIFO/queue status register
+    pub HPTXSTS: RORegister<u32>,
+
+    /// Host all channels interrupt register
+    pub HAINT: RORegister<u32>,
+
+    /// Host all channels interrupt mask register
+    pub HAINTMSK: RWRegister<u32>,
+
+    _reserved1: [u32; 9],
+
+    /// Host port control and status register
+    pub HPRT: RWRegister<u32>,
+}
@@ -0,0 +1,21 @@
+//! Power and clock gating registers of the OTG core
+
+use super::register::{RWRegister};
+
+/// Power and clock gating control register
+pub mod PCGCCTL {
+    fields! {
+        /// Stop PHY clock
+        STPPCLK: 0, 1;
+        /// Gate HCLK
+        GATEHCLK: 1, 1;
+        /// PHY suspended
+        PHYSUSP: 4, 1;
+    }
+}
+
+#[repr(C)]
+pub struct RegisterBlock {
+    /// Power and clock gating control register
+    pub PCGCCTL: RWRegister<u32>,
+}
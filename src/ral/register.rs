@@ -0,0 +1,116 @@
+//! Register types and access macros.
+//!
+//! This is a trimmed-down version of the `stm32ral` register API. It is kept inside the crate
+//! so that the register layout does not depend on any particular device crate and can describe
+//! both FS and HS flavours of the core at the same time.
+
+use core::cell::UnsafeCell;
+
+/// A read-write register of type T.
+pub struct RWRegister<T> {
+    register: UnsafeCell<T>,
+}
+
+impl<T: Copy> RWRegister<T> {
+    /// Reads the value of the register.
+    #[inline(always)]
+    pub fn read(&self) -> T {
+        #[cfg(not(feature = "sim"))]
+        unsafe { ::core::ptr::read_volatile(self.register.get()) }
+        #[cfg(feature = "sim")]
+        crate::sim::read(self.register.get())
+    }
+
+    /// Writes a new value to the register.
+    #[inline(always)]
+    pub fn write(&self, val: T) {
+        #[cfg(not(feature = "sim"))]
+        unsafe { ::core::ptr::write_volatile(self.register.get(), val) }
+        #[cfg(feature = "sim")]
+        crate::sim::write(self.register.get(), val)
+    }
+}
+
+/// A read-only register of type T.
+pub struct RORegister<T> {
+    register: UnsafeCell<T>,
+}
+
+impl<T: Copy> RORegister<T> {
+    /// Reads the value of the register.
+    #[inline(always)]
+    pub fn read(&self) -> T {
+        #[cfg(not(feature = "sim"))]
+        unsafe { ::core::ptr::read_volatile(self.register.get()) }
+        #[cfg(feature = "sim")]
+        crate::sim::read(self.register.get())
+    }
+}
+
+/// Defines register fields as `offset`/`mask` modules used by the access macros.
+macro_rules! fields {
+    ( $( $(#[$attr:meta])* $field:ident: $offset:expr, $width:expr; )* ) => {
+        $(
+            $(#[$attr])*
+            pub mod $field {
+                /// Offset of the field in bits
+                pub const offset: u32 = $offset;
+                /// Mask of the field
+                pub const mask: u32 = (((1u64 << $width) - 1) as u32) << offset;
+            }
+        )*
+    };
+}
+
+/// Write to a register, either the whole value or a set of fields.
+///
+/// Fields that are not specified are written as zero.
+macro_rules! write_reg {
+    ( $periph:path, $instance:expr, $reg:ident, $( $field:ident : $value:expr ),+ ) => {{
+        #[allow(unused_imports)]
+        (*$instance).$reg.write(
+            $({ use $periph::{$reg::$field::{mask, offset}}; (($value) << offset) & mask }) | *
+        );
+    }};
+    ( $periph:path, $instance:expr, $reg:ident, $value:expr ) => {{
+        #[allow(unused_imports)]
+        use $periph::{*};
+        (*$instance).$reg.write($value);
+    }};
+}
+
+/// Modify a register, either with a closure or by updating a set of fields.
+///
+/// Fields that are not specified are left unchanged.
+macro_rules! modify_reg {
+    ( $periph:path, $instance:expr, $reg:ident, $( $field:ident : $value:expr ),+ ) => {{
+        #[allow(unused_imports)]
+        (*$instance).$reg.write(
+            ((*$instance).$reg.read() & !( $({ use $periph::{$reg::$field::mask}; mask }) | * ))
+            | $({ use $periph::{$reg::$field::{mask, offset}}; (($value) << offset) & mask }) | *);
+    }};
+    ( $periph:path, $instance:expr, $reg:ident, $fn:expr ) => {{
+        #[allow(unused_imports)]
+        use $periph::{*};
+        (*$instance).$reg.write($fn((*$instance).$reg.read()));
+    }};
+}
+
+/// Read a register, either the whole value or a tuple of fields.
+macro_rules! read_reg {
+    ( $periph:path, $instance:expr, $reg:ident, $( $field:ident ),+ ) => {{
+        let val = ((*$instance).$reg.read());
+        ( $({
+            #[allow(unused_imports)]
+            use $periph::{$reg::$field::{mask, offset}};
+            (val & mask) >> offset
+        }) , *)
+    }};
+    ( $periph:path, $instance:expr, $reg:ident ) => {{
+        #[allow(unused_imports)]
+        use $periph::{*};
+        ((*$instance).$reg.read())
+    }};
+}
+
+pub(crate) use {write_reg, modify_reg, read_reg};
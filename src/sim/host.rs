@@ -0,0 +1,317 @@
+//! Scriptable USB host for the simulated core
+
+use std::vec::Vec;
+use super::{Handshake, SimCore};
+
+/// Number of times a NAKed token is retried before giving up
+const NAK_RETRIES: usize = 16;
+
+/// Maximum number of device polls needed to handle the pending interrupts
+const MAX_POLLS: usize = 64;
+
+/// Device side of the simulation, polled by `VirtualHost` after every bus event.
+///
+/// This is implemented for closures, so a device can be simply given as
+/// `|| { usb_dev.poll(&mut [&mut class]); }`.
+pub trait SimDevice {
+    /// Polls the `UsbDevice` and its classes.
+    fn poll(&mut self);
+}
+
+impl<F: FnMut()> SimDevice for F {
+    fn poll(&mut self) {
+        self()
+    }
+}
+
+/// A control request, without the `wLength` field
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Request {
+    /// `bmRequestType`
+    pub request_type: u8,
+    /// `bRequest`
+    pub request: u8,
+    /// `wValue`
+    pub value: u16,
+    /// `wIndex`
+    pub index: u16,
+}
+
+impl Request {
+    /// GET_DESCRIPTOR request for a descriptor of the device
+    pub fn get_descriptor(descriptor_type: u8, index: u8, language_id: u16) -> Request {
+        Request {
+            request_type: 0x80,
+            request: 0x06,
+            value: ((descriptor_type as u16) << 8) | index as u16,
+            index: language_id,
+        }
+    }
+
+    /// SET_ADDRESS request
+    pub fn set_address(address: u8) -> Request {
+        Request { request_type: 0x00, request: 0x05, value: address as u16, index: 0 }
+    }
+
+    /// SET_CONFIGURATION request
+    pub fn set_configuration(configuration: u8) -> Request {
+        Request { request_type: 0x00, request: 0x09, value: configuration as u16, index: 0 }
+    }
+
+    /// Encodes the SETUP packet for a transfer of `length` bytes.
+    pub fn to_bytes(&self, length: u16) -> [u8; 8] {
+        let value = self.value.to_le_bytes();
+        let index = self.index.to_le_bytes();
+        let length = length.to_le_bytes();
+        [self.request_type, self.request, value[0], value[1], index[0], index[1], length[0], length[1]]
+    }
+}
+
+/// Descriptors read during enumeration
+#[derive(Clone, Debug)]
+pub struct Enumeration {
+    /// The device descriptor
+    pub device_descriptor: Vec<u8>,
+    /// The first configuration descriptor, including its interface, endpoint and class descriptors
+    pub configuration_descriptor: Vec<u8>,
+}
+
+impl Enumeration {
+    /// Splits the configuration descriptor into the descriptors it contains.
+    pub fn descriptors(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        let mut rest = &self.configuration_descriptor[..];
+        core::iter::from_fn(move || {
+            let length = *rest.first()? as usize;
+            if length < 2 || length > rest.len() {
+                return None;
+            }
+            let (descriptor, tail) = rest.split_at(length);
+            rest = tail;
+            Some(descriptor)
+        })
+    }
+
+    /// Returns the maximum packet size of an endpoint, given its address including the direction
+    /// bit.
+    pub fn max_packet_size(&self, endpoint_address: u8) -> Option<usize> {
+        self.descriptors()
+            .find(|d| d[1] == 0x05 && d.len() >= 7 && d[2] == endpoint_address)
+            .map(|d| u16::from_le_bytes([d[4], d[5]]) as usize & 0x7ff)
+    }
+}
+
+/// Virtual USB host driving a device through the simulated core.
+///
+/// Every token is followed by polling the device until it has handled all pending interrupts, and
+/// NAKed tokens are retried a limited number of times, after which `Handshake::Nak` is returned as
+/// an error.
+pub struct VirtualHost<D> {
+    core: SimCore,
+    device: D,
+    address: u8,
+    ep0_size: usize,
+}
+
+impl<D: SimDevice> VirtualHost<D> {
+    /// Creates a virtual host attached to `core`.
+    ///
+    /// `UsbBus` must be created after `core`, which resets the simulated core.
+    pub fn new(core: SimCore, device: D) -> VirtualHost<D> {
+        VirtualHost { core, device, address: 0, ep0_size: 8 }
+    }
+
+    /// Returns the simulated core.
+    pub fn core(&self) -> &SimCore {
+        &self.core
+    }
+
+    /// Returns the device.
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Returns the address the host sends tokens to.
+    ///
+    /// This is reset to 0 by `bus_reset` and updated by a successful SET_ADDRESS request.
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Releases the simulated core and the device.
+    pub fn free(self) -> (SimCore, D) {
+        (self.core, self.device)
+    }
+
+    /// Polls the device until it has handled all pending interrupts.
+    ///
+    /// Panics if the driver misused the simulated core.
+    pub fn poll(&mut self) {
+        for _ in 0..MAX_POLLS {
+            self.device.poll();
+            if !self.core.interrupt_pending() {
+                break;
+            }
+        }
+
+        let errors = self.core.take_errors();
+        assert!(errors.is_empty(), "the driver misused the core: {:?}", errors);
+    }
+
+    /// Resets the bus, which returns the device to address 0.
+    pub fn bus_reset(&mut self) {
+        self.core.bus_reset();
+        self.address = 0;
+        self.poll();
+    }
+
+    /// Suspends the bus.
+    pub fn suspend(&mut self) {
+        self.core.suspend();
+        self.poll();
+    }
+
+    /// Resumes the bus.
+    pub fn resume(&mut self) {
+        self.core.resume();
+        self.poll();
+    }
+
+    /// Sends a SETUP packet to endpoint 0.
+    pub fn setup(&mut self, packet: &[u8; 8]) -> Result<(), Handshake> {
+        let result = self.core.setup_to(self.address, 0, packet);
+        self.poll();
+        match result {
+            Handshake::Ack => Ok(()),
+            error => Err(error),
+        }
+    }
+
+    /// Sends an IN token, retrying while the endpoint NAKs, and returns the data packet.
+    pub fn in_packet(&mut self, ep: u8) -> Result<Vec<u8>, Handshake> {
+        for _ in 0..NAK_RETRIES {
+            let result = self.core.in_token_to(self.address, ep);
+            self.poll();
+            if result != Err(Handshake::Nak) {
+                return result;
+            }
+        }
+        Err(Handshake::Nak)
+    }
+
+    /// Sends an OUT data packet, retrying while the endpoint NAKs.
+    pub fn out_packet(&mut self, ep: u8, data: &[u8]) -> Result<(), Handshake> {
+        for _ in 0..NAK_RETRIES {
+            let result = self.core.out_to(self.address, ep, data);
+            self.poll();
+            match result {
+                Handshake::Ack => return Ok(()),
+                Handshake::Nak => {}
+                error => return Err(error),
+            }
+        }
+        Err(Handshake::Nak)
+    }
+
+    /// Receives a bulk or interrupt IN transfer, ending with a short packet or after `max_length`
+    /// bytes.
+    pub fn in_transfer(&mut self, ep: u8, max_packet_size: usize, max_length: usize) -> Result<Vec<u8>, Handshake> {
+        let mut data = Vec::new();
+        loop {
+            let packet = self.in_packet(ep)?;
+            data.extend_from_slice(&packet);
+            if packet.len() < max_packet_size || data.len() >= max_length {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// Sends a bulk or interrupt OUT transfer, split into packets of `max_packet_size` bytes and
+    /// terminated with a zero-length packet if the last packet is full.
+    pub fn out_transfer(&mut self, ep: u8, max_packet_size: usize, data: &[u8]) -> Result<(), Handshake> {
+        for packet in data.chunks(max_packet_size) {
+            self.out_packet(ep, packet)?;
+        }
+        if data.len() % max_packet_size == 0 {
+            self.out_packet(ep, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Performs a control transfer with an IN data stage of up to `length` bytes.
+    pub fn control_in(&mut self, request: Request, length: u16) -> Result<Vec<u8>, Handshake> {
+        self.setup(&request.to_bytes(length))?;
+
+        if length == 0 {
+            // No data stage, the status stage is an IN transaction
+            return self.control_out_status().map(|()| Vec::new());
+        }
+
+        let data = self.in_transfer(0, self.ep0_size, length as usize)?;
+        self.out_packet(0, &[])?;
+
+        Ok(data)
+    }
+
+    /// Performs a control transfer with an optional OUT data stage.
+    pub fn control_out(&mut self, request: Request, data: &[u8]) -> Result<(), Handshake> {
+        self.setup(&request.to_bytes(data.len() as u16))?;
+
+        for packet in data.chunks(self.ep0_size) {
+            self.out_packet(0, packet)?;
+        }
+        self.control_out_status()?;
+
+        if request == Request::set_address(request.value as u8) {
+            self.address = request.value as u8;
+        }
+        Ok(())
+    }
+
+    fn control_out_status(&mut self) -> Result<(), Handshake> {
+        match self.in_packet(0)?.len() {
+            0 => Ok(()),
+            _ => panic!("non-empty status stage"),
+        }
+    }
+
+    /// Reads a descriptor with GET_DESCRIPTOR.
+    pub fn get_descriptor(&mut self, descriptor_type: u8, index: u8, language_id: u16, length: u16) -> Result<Vec<u8>, Handshake> {
+        self.control_in(Request::get_descriptor(descriptor_type, index, language_id), length)
+    }
+
+    /// Reads a string descriptor and decodes it.
+    pub fn get_string(&mut self, index: u8, language_id: u16) -> Result<std::string::String, Handshake> {
+        let descriptor = self.get_descriptor(0x03, index, language_id, 255)?;
+        let units: Vec<u16> = descriptor[2..].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        Ok(std::string::String::from_utf16_lossy(&units))
+    }
+
+    /// Enumerates the device the way common hosts do and selects its first configuration.
+    ///
+    /// The device is reset, the maximum packet size of endpoint 0 is read from the first 8 bytes
+    /// of the device descriptor, the device is reset again and given `address`, and the device and
+    /// configuration descriptors are read in full.
+    pub fn enumerate(&mut self, address: u8) -> Result<Enumeration, Handshake> {
+        self.poll();
+        if !self.core.is_connected() {
+            return Err(Handshake::NoResponse);
+        }
+
+        self.bus_reset();
+        self.ep0_size = 8;
+        let header = self.get_descriptor(0x01, 0, 0, 8)?;
+        self.ep0_size = header[7] as usize;
+
+        self.bus_reset();
+        self.control_out(Request::set_address(address), &[])?;
+
+        let device_descriptor = self.get_descriptor(0x01, 0, 0, 18)?;
+        let header = self.get_descriptor(0x02, 0, 0, 9)?;
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let configuration_descriptor = self.get_descriptor(0x02, 0, 0, total_length)?;
+
+        self.control_out(Request::set_configuration(configuration_descriptor[5]), &[])?;
+
+        Ok(Enumeration { device_descriptor, configuration_descriptor })
+    }
+}
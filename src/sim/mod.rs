@@ -0,0 +1,938 @@
+//! With the `sim` feature all register accesses are routed to a software model of a Full Speed
+//! core in device mode instead of MMIO. The model covers what `UsbBus` uses in slave (non-DMA)
+//! mode: the core interrupt register, the RX status queue and RX FIFO, the TX FIFOs and the
+//! endpoint control, interrupt and transfer size registers, and it checks that no register but
+//! `PCGCCTL` is accessed while HCLK is gated. DMA is not modeled.
+//!
+//! In host mode, the root port and the host channels of the core are modeled for `UsbHost`. The
+//! test connects a device implementing `AttachedDevice` to the port with `SimCore::attach`. The
+//! mode of a dual-role core follows the ID pin, which is set with `SimCore::set_id_pin`, and the
+//! test answers the session and host negotiation requests of the device as the A-device.
+//!
+//! The test plays the USB host through `SimCore`, which injects bus events and tokens, or through
+//! `VirtualHost`, which performs complete transfers and enumeration on top of it. There is a single
+//! simulated core, so `SimCore::new` blocks until any other `SimCore` is dropped and tests that run
+//! in parallel are serialized.
+
+use core::cmp;
+use core::mem::{size_of, transmute_copy};
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::sync::{Mutex as StdMutex, MutexGuard};
+use std::vec::Vec;
+use crate::ral::{otg_global, otg_device, otg_pwrclk, endpoint_in, endpoint_out, endpoint0_out};
+use crate::UsbPeripheral;
+
+mod host;
+mod port;
+
+pub use self::host::{SimDevice, Request, Enumeration, VirtualHost};
+pub use self::port::AttachedDevice;
+
+/// Size of the register block including the FIFO windows
+const MEMORY_SIZE: usize = 0x11000;
+
+/// Backing memory of the register block, never accessed directly
+static MEMORY: [u32; MEMORY_SIZE / 4] = [0; MEMORY_SIZE / 4];
+
+/// Base address of the simulated core, for use as `UsbPeripheral::REGISTERS`.
+pub const REGISTERS: *const () = &MEMORY as *const [u32; MEMORY_SIZE / 4] as *const ();
+
+/// Simulated Full Speed peripheral with the FIFO size and endpoint count of the STM32F4 OTG_FS.
+pub struct SimUsb;
+
+unsafe impl UsbPeripheral for SimUsb {
+    const REGISTERS: *const () = REGISTERS;
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+    const ENDPOINT_COUNT: usize = 4;
+
+    fn enable() {}
+}
+
+const HOST: usize = 0x400;
+const CHANNELS: usize = 0x500;
+const DEVICE: usize = 0x800;
+const EP_IN: usize = 0x900;
+const EP_OUT: usize = 0xb00;
+const PWRCLK: usize = 0xe00;
+const FIFO: usize = 0x1000;
+
+const GOTGCTL: usize = 0x00;
+const GOTGINT: usize = 0x04;
+const GAHBCFG: usize = 0x08;
+const GUSBCFG: usize = 0x0c;
+const GRSTCTL: usize = 0x10;
+const GINTSTS: usize = 0x14;
+const GINTMSK: usize = 0x18;
+const GRXSTSR: usize = 0x1c;
+const GRXSTSP: usize = 0x20;
+const GRXFSIZ: usize = 0x24;
+const DIEPTXF0: usize = 0x28;
+const DIEPTXF1: usize = 0x104;
+const GNPTXSTS: usize = 0x2c;
+const HPTXFSIZ: usize = 0x100;
+
+const HFNUM: usize = HOST + 0x08;
+const HPTXSTS: usize = HOST + 0x10;
+const HPRT: usize = HOST + 0x40;
+
+const DCFG: usize = DEVICE;
+const DCTL: usize = DEVICE + 0x04;
+const DSTS: usize = DEVICE + 0x08;
+const DIEPMSK: usize = DEVICE + 0x10;
+const DOEPMSK: usize = DEVICE + 0x14;
+const DAINT: usize = DEVICE + 0x18;
+const DAINTMSK: usize = DEVICE + 0x1c;
+const DIEPEMPMSK: usize = DEVICE + 0x34;
+
+const PCGCCTL: usize = PWRCLK;
+
+const HCCHAR: usize = 0x00;
+const HCINT: usize = 0x08;
+const HCTSIZ: usize = 0x10;
+
+const DIEPCTL: usize = 0x00;
+const DIEPINT: usize = 0x08;
+const DIEPTSIZ: usize = 0x10;
+const DTXFSTS: usize = 0x18;
+const DOEPCTL: usize = 0x00;
+const DOEPINT: usize = 0x08;
+const DOEPTSIZ: usize = 0x10;
+
+/// RX FIFO packet status values
+const PKTSTS_OUT_DATA: u32 = 0x02;
+const PKTSTS_OUT_COMPLETE: u32 = 0x03;
+const PKTSTS_SETUP_COMPLETE: u32 = 0x04;
+const PKTSTS_SETUP_DATA: u32 = 0x06;
+
+/// Handshake returned by the simulated device
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Handshake {
+    /// The packet was accepted
+    Ack,
+    /// The endpoint is not ready
+    Nak,
+    /// The endpoint is halted
+    Stall,
+    /// The device is not connected to the bus (soft disconnect) or uses another address
+    NoResponse,
+}
+
+/// Misuse of the core by the driver, detected by the model
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SimError {
+    /// `GRXSTSP` was popped while the RX status queue was empty
+    RxStatusUnderflow,
+    /// The RX FIFO was read past the data of the last popped packet
+    RxFifoUnderflow,
+    /// `GRXSTSP` was popped before the data of the previous packet was read from the RX FIFO
+    RxDataNotRead,
+    /// A TX FIFO was written while it was full
+    TxFifoOverflow,
+    /// A register other than `PCGCCTL` was accessed while HCLK was gated
+    HclkGated,
+}
+
+struct Model {
+    regs: [u32; FIFO / 4],
+    rx_status: VecDeque<u32>,
+    rx_data: VecDeque<u32>,
+    /// Words of the last popped packet that are still in the RX FIFO
+    rx_unread: usize,
+    tx_fifos: [VecDeque<u32>; 16],
+    /// Address the device responds to, which follows DCFG.DAD after the status stage
+    address: u8,
+    /// Device connected to the root port in host mode
+    device: Option<Box<dyn AttachedDevice>>,
+    /// Enabled host channels whose transaction has not been performed yet
+    pending_channels: u16,
+    /// Mode of the core unless forced by `GUSBCFG`, which follows the ID pin with a delay
+    mode_host: bool,
+    errors: Vec<SimError>,
+}
+
+static MODEL: StdMutex<Model> = StdMutex::new(Model::new());
+static SESSION: StdMutex<()> = StdMutex::new(());
+
+fn model() -> MutexGuard<'static, Model> {
+    MODEL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn offset(address: usize) -> usize {
+    let base = REGISTERS as usize;
+    assert!((base..base + MEMORY_SIZE).contains(&address), "register access outside of the simulated core");
+    address - base
+}
+
+/// Reads a register of the simulated core.
+pub(crate) fn read<T: Copy>(register: *mut T) -> T {
+    assert_eq!(size_of::<T>(), 4);
+    let value = model().read(offset(register as usize));
+    unsafe { transmute_copy(&value) }
+}
+
+/// Writes a register of the simulated core.
+pub(crate) fn write<T: Copy>(register: *mut T, value: T) {
+    assert_eq!(size_of::<T>(), 4);
+    let value: u32 = unsafe { transmute_copy(&value) };
+    model().write(offset(register as usize), value);
+}
+
+fn packet_status(ep: usize, data_size: usize, status: u32) -> u32 {
+    use otg_global::GRXSTSR::{EPNUM, BCNT, PKTSTS};
+
+    ((ep as u32) << EPNUM::offset) | ((data_size as u32) << BCNT::offset) | (status << PKTSTS::offset)
+}
+
+fn to_words(data: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    data.chunks(4).map(|chunk| {
+        let mut bytes = [0u8; 4];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        u32::from_ne_bytes(bytes)
+    })
+}
+
+/// Decodes the maximum packet size of an endpoint control register.
+fn max_packet_size(ep: usize, ctl: u32) -> usize {
+    if ep == 0 {
+        match ctl & endpoint0_out::DOEPCTL0::MPSIZ::mask {
+            0b00 => 64,
+            0b01 => 32,
+            0b10 => 16,
+            _ => 8,
+        }
+    } else {
+        (ctl & endpoint_in::DIEPCTL::MPSIZ::mask) as usize
+    }
+}
+
+impl Model {
+    const EMPTY_FIFO: VecDeque<u32> = VecDeque::new();
+
+    const fn new() -> Model {
+        Model {
+            regs: [0; FIFO / 4],
+            rx_status: VecDeque::new(),
+            rx_data: VecDeque::new(),
+            rx_unread: 0,
+            tx_fifos: [Self::EMPTY_FIFO; 16],
+            address: 0,
+            device: None,
+            pending_channels: 0,
+            mode_host: false,
+            errors: Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Model::new();
+
+        // The ID pin is floating, which selects the B-device
+        self.set_bits(GOTGCTL, otg_global::GOTGCTL::CIDSTS::mask);
+    }
+
+    fn reg(&self, offset: usize) -> u32 {
+        self.regs[offset / 4]
+    }
+
+    fn set_reg(&mut self, offset: usize, value: u32) {
+        self.regs[offset / 4] = value;
+    }
+
+    fn set_bits(&mut self, offset: usize, bits: u32) {
+        self.regs[offset / 4] |= bits;
+    }
+
+    fn clear_bits(&mut self, offset: usize, bits: u32) {
+        self.regs[offset / 4] &= !bits;
+    }
+
+    fn ep_in(ep: usize, reg: usize) -> usize {
+        EP_IN + 0x20 * ep + reg
+    }
+
+    fn ep_out(ep: usize, reg: usize) -> usize {
+        EP_OUT + 0x20 * ep + reg
+    }
+
+    fn tx_fifo_depth(&self, ep: usize) -> usize {
+        if ep == 0 {
+            (self.reg(DIEPTXF0) >> otg_global::DIEPTXF0::TX0FD::offset) as usize
+        } else {
+            (self.reg(DIEPTXF1 + 4 * (ep - 1)) >> 16) as usize
+        }
+    }
+
+    fn rx_fifo_free(&self) -> usize {
+        let depth = (self.reg(GRXFSIZ) & otg_global::GRXFSIZ::RXFD::mask) as usize;
+        depth.saturating_sub(self.rx_status.len() + self.rx_data.len())
+    }
+
+    fn diepint(&self, ep: usize) -> u32 {
+        let mut value = self.reg(Self::ep_in(ep, DIEPINT));
+        if self.tx_fifos[ep].is_empty() {
+            value |= endpoint_in::DIEPINT::TXFE::mask;
+        }
+        value
+    }
+
+    fn daint(&self) -> u32 {
+        let mut daint = 0;
+        for ep in 0..16 {
+            let mut in_mask = self.reg(DIEPMSK);
+            if self.reg(DIEPEMPMSK) & (1 << ep) != 0 {
+                in_mask |= endpoint_in::DIEPINT::TXFE::mask;
+            }
+            if self.diepint(ep) & in_mask != 0 {
+                daint |= 1 << ep;
+            }
+            if self.reg(Self::ep_out(ep, DOEPINT)) & self.reg(DOEPMSK) != 0 {
+                daint |= 1 << (16 + ep);
+            }
+        }
+        daint
+    }
+
+    fn gintsts(&self) -> u32 {
+        use otg_global::GINTSTS;
+
+        let mut value = self.reg(GINTSTS);
+        if self.host_mode() {
+            value |= GINTSTS::CMOD::mask;
+        }
+        if !self.rx_status.is_empty() {
+            value |= GINTSTS::RXFLVL::mask;
+        }
+        if self.reg(GOTGINT) != 0 {
+            value |= GINTSTS::OTGINT::mask;
+        }
+        if self.port_interrupt() {
+            value |= GINTSTS::HPRTINT::mask;
+        }
+        let daint = self.daint() & self.reg(DAINTMSK);
+        if daint & 0xffff != 0 {
+            value |= GINTSTS::IEPINT::mask;
+        }
+        if daint >> 16 != 0 {
+            value |= GINTSTS::OEPINT::mask;
+        }
+        value
+    }
+
+    /// Returns `true` and records an error if `offset` is not accessible because HCLK is gated.
+    fn hclk_gated(&mut self, offset: usize) -> bool {
+        let gated = offset != PCGCCTL && self.reg(PCGCCTL) & otg_pwrclk::PCGCCTL::GATEHCLK::mask != 0;
+        if gated {
+            self.errors.push(SimError::HclkGated);
+        }
+        gated
+    }
+
+    fn read(&mut self, offset: usize) -> u32 {
+        if self.hclk_gated(offset) {
+            return 0;
+        }
+
+        if self.host_mode() && (offset == GINTSTS || Self::channel_register(offset) == Some(HCINT)) {
+            self.run_channels();
+        }
+
+        if offset >= FIFO {
+            if self.rx_unread == 0 {
+                self.errors.push(SimError::RxFifoUnderflow);
+                return 0;
+            }
+            self.rx_unread -= 1;
+            return self.rx_data.pop_front().unwrap_or(0);
+        }
+
+        match offset {
+            GINTSTS => self.gintsts(),
+            GRXSTSR => self.rx_status.front().copied().unwrap_or(0),
+            GRXSTSP => self.pop_rx_status(),
+            GRSTCTL => self.reg(GRSTCTL) | otg_global::GRSTCTL::AHBIDL::mask,
+            DAINT => self.daint(),
+            GNPTXSTS => self.host_tx_fifo_free(false) as u32,
+            HPTXSTS => self.host_tx_fifo_free(true) as u32,
+            HFNUM => self.next_frame(),
+            _ if (EP_IN..EP_OUT).contains(&offset) => {
+                let ep = (offset - EP_IN) / 0x20;
+                match (offset - EP_IN) % 0x20 {
+                    DIEPINT => self.diepint(ep),
+                    DTXFSTS => self.tx_fifo_depth(ep).saturating_sub(self.tx_fifos[ep].len()) as u32,
+                    _ => self.reg(offset),
+                }
+            }
+            _ => self.reg(offset),
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        if self.hclk_gated(offset) {
+            return;
+        }
+
+        if offset >= FIFO && self.host_mode() {
+            self.write_channel_fifo(offset / FIFO - 1, value);
+            return;
+        }
+
+        if offset >= FIFO {
+            let channel = offset / FIFO - 1;
+            if self.tx_fifos[channel].len() >= self.tx_fifo_depth(channel) {
+                self.errors.push(SimError::TxFifoOverflow);
+            } else {
+                self.tx_fifos[channel].push_back(value);
+            }
+            return;
+        }
+
+        match offset {
+            // Write 1 to clear
+            GINTSTS | GOTGINT => self.clear_bits(offset, value),
+            GOTGCTL => {
+                use otg_global::GOTGCTL::{SRQSCS, HNGSCS, CIDSTS, ASVLD, BSVLD};
+
+                let status = SRQSCS::mask | HNGSCS::mask | CIDSTS::mask | ASVLD::mask | BSVLD::mask;
+                self.set_reg(offset, (value & !status) | (self.reg(offset) & status));
+            }
+            GRSTCTL => {
+                use otg_global::GRSTCTL::{CSRST, RXFFLSH, TXFFLSH, TXFNUM};
+
+                if value & RXFFLSH::mask != 0 {
+                    self.rx_status.clear();
+                    self.rx_data.clear();
+                    self.rx_unread = 0;
+                }
+                if value & TXFFLSH::mask != 0 {
+                    let fifo = ((value & TXFNUM::mask) >> TXFNUM::offset) as usize;
+                    if fifo == 0x10 {
+                        self.tx_fifos.iter_mut().for_each(VecDeque::clear);
+                    } else if let Some(fifo) = self.tx_fifos.get_mut(fifo) {
+                        fifo.clear();
+                    }
+                }
+                // Self-clearing bits
+                self.set_reg(offset, value & !(CSRST::mask | RXFFLSH::mask | TXFFLSH::mask));
+            }
+            HPRT => self.write_hprt(value),
+            _ if Self::channel_register(offset).is_some() => {
+                let ch = (offset - CHANNELS) / 0x20;
+                match (offset - CHANNELS) % 0x20 {
+                    HCCHAR => self.write_hcchar(ch, value),
+                    HCINT => self.clear_bits(offset, value),
+                    _ => self.set_reg(offset, value),
+                }
+            }
+            DCTL => {
+                use otg_device::DCTL::{SGINAK, CGINAK, SGONAK, CGONAK};
+
+                self.set_reg(offset, value & !(SGINAK::mask | CGINAK::mask | SGONAK::mask | CGONAK::mask));
+            }
+            _ if (EP_IN..EP_OUT).contains(&offset) => {
+                let ep = (offset - EP_IN) / 0x20;
+                match (offset - EP_IN) % 0x20 {
+                    DIEPCTL => self.write_epctl(offset, Self::ep_in(ep, DIEPINT), value),
+                    DIEPINT => self.clear_bits(offset, value),
+                    DTXFSTS => {}
+                    _ => self.set_reg(offset, value),
+                }
+            }
+            _ if (EP_OUT..EP_OUT + 0x200).contains(&offset) => {
+                let ep = (offset - EP_OUT) / 0x20;
+                match (offset - EP_OUT) % 0x20 {
+                    DOEPCTL => self.write_epctl(offset, Self::ep_out(ep, DOEPINT), value),
+                    DOEPINT => self.clear_bits(offset, value),
+                    _ => self.set_reg(offset, value),
+                }
+            }
+            _ => self.set_reg(offset, value),
+        }
+    }
+
+    fn pop_rx_status(&mut self) -> u32 {
+        use otg_global::GRXSTSR::BCNT;
+
+        let status = match self.rx_status.pop_front() {
+            Some(status) => status,
+            None => {
+                self.errors.push(SimError::RxStatusUnderflow);
+                return 0;
+            }
+        };
+
+        if self.rx_unread != 0 {
+            // Drop the rest of the previous packet to keep the FIFO in sync with the status queue
+            self.errors.push(SimError::RxDataNotRead);
+            self.rx_data.drain(..self.rx_unread);
+        }
+        let data_size = (status & BCNT::mask) >> BCNT::offset;
+        self.rx_unread = (data_size as usize).div_ceil(4);
+
+        status
+    }
+
+    fn push_rx(&mut self, ep: usize, status: u32, data: &[u8]) {
+        self.rx_status.push_back(packet_status(ep, data.len(), status));
+        self.rx_data.extend(to_words(data));
+    }
+
+    /// Handles a write to DIEPCTL/DOEPCTL, which share the layout of their control bits.
+    fn write_epctl(&mut self, offset: usize, epint: usize, value: u32) {
+        use endpoint_in::DIEPCTL::{EONUM_DPID, NAKSTS, CNAK, SNAK, SD0PID_SEVNFRM, SODDFRM, EPDIS, EPENA};
+
+        let old = self.reg(offset);
+        let actions = CNAK::mask | SNAK::mask | SD0PID_SEVNFRM::mask | SODDFRM::mask | EPDIS::mask;
+        let status = EONUM_DPID::mask | NAKSTS::mask | EPENA::mask;
+
+        let mut new = (value & !(actions | status)) | (old & (EONUM_DPID::mask | NAKSTS::mask));
+        // EPENA can only be cleared by the core
+        let mut enabled = (old | value) & EPENA::mask != 0;
+
+        if value & SNAK::mask != 0 {
+            new |= NAKSTS::mask;
+        }
+        if value & CNAK::mask != 0 {
+            new &= !NAKSTS::mask;
+        }
+        if value & SD0PID_SEVNFRM::mask != 0 {
+            new &= !EONUM_DPID::mask;
+        }
+        if value & SODDFRM::mask != 0 {
+            new |= EONUM_DPID::mask;
+        }
+        if value & EPDIS::mask != 0 && enabled {
+            enabled = false;
+            self.set_bits(epint, endpoint_in::DIEPINT::EPDISD::mask);
+        }
+        if enabled {
+            new |= EPENA::mask;
+        }
+
+        self.set_reg(offset, new);
+    }
+
+    fn is_connected(&self) -> bool {
+        self.reg(DCTL) & otg_device::DCTL::SDIS::mask == 0
+    }
+
+    fn responds_to(&self, address: u8) -> bool {
+        self.is_connected() && address == self.address
+    }
+
+    fn programmed_address(&self) -> u8 {
+        ((self.reg(DCFG) & otg_device::DCFG::DAD::mask) >> otg_device::DCFG::DAD::offset) as u8
+    }
+
+    fn bus_reset(&mut self) {
+        use otg_global::GINTSTS::{USBRST, ENUMDNE, WKUPINT};
+        use otg_device::DSTS::{SUSPSTS, ENUMSPD};
+
+        // A reset also wakes up a suspended core
+        if self.reg(DSTS) & SUSPSTS::mask != 0 {
+            self.set_bits(GINTSTS, WKUPINT::mask);
+        }
+
+        let speed = self.reg(DCFG) & otg_device::DCFG::DSPD::mask;
+        let dsts = (self.reg(DSTS) & !(SUSPSTS::mask | ENUMSPD::mask)) | (speed << ENUMSPD::offset);
+        self.set_reg(DSTS, dsts);
+        self.set_bits(GINTSTS, USBRST::mask | ENUMDNE::mask);
+        self.address = 0;
+    }
+
+    fn set_vbus(&mut self, present: bool) {
+        if present {
+            self.set_bits(GOTGCTL, otg_global::GOTGCTL::BSVLD::mask);
+            self.set_bits(GINTSTS, otg_global::GINTSTS::SRQINT::mask);
+        } else {
+            self.clear_bits(GOTGCTL, otg_global::GOTGCTL::BSVLD::mask);
+            self.set_bits(GOTGINT, otg_global::GOTGINT::SEDET::mask);
+        }
+    }
+
+    fn setup(&mut self, address: u8, ep: usize, packet: &[u8; 8]) -> Handshake {
+        if !self.responds_to(address) {
+            return Handshake::NoResponse;
+        }
+
+        self.push_rx(ep, PKTSTS_SETUP_DATA, packet);
+        self.push_rx(ep, PKTSTS_SETUP_COMPLETE, &[]);
+
+        // SETUP clears the STALL condition of the control endpoint
+        self.clear_bits(Self::ep_in(ep, DIEPCTL), endpoint_in::DIEPCTL::STALL::mask);
+        self.clear_bits(Self::ep_out(ep, DOEPCTL), endpoint_out::DOEPCTL::STALL::mask);
+        self.set_bits(Self::ep_out(ep, DOEPINT), endpoint_out::DOEPINT::STUP::mask);
+
+        Handshake::Ack
+    }
+
+    fn out(&mut self, address: u8, ep: usize, data: &[u8]) -> Handshake {
+        use endpoint_out::DOEPCTL::{STALL, NAKSTS, EPENA};
+        use endpoint_out::DOEPTSIZ::{PKTCNT, XFRSIZ};
+
+        if !self.responds_to(address) {
+            return Handshake::NoResponse;
+        }
+
+        let ctl = self.reg(Self::ep_out(ep, DOEPCTL));
+        if ctl & STALL::mask != 0 {
+            return Handshake::Stall;
+        }
+        if ctl & EPENA::mask == 0 || ctl & NAKSTS::mask != 0 {
+            return Handshake::Nak;
+        }
+        if self.rx_fifo_free() < 1 + data.len().div_ceil(4) {
+            return Handshake::Nak;
+        }
+
+        self.push_rx(ep, PKTSTS_OUT_DATA, data);
+
+        let tsiz = self.reg(Self::ep_out(ep, DOEPTSIZ));
+        let packets = ((tsiz & PKTCNT::mask) >> PKTCNT::offset).saturating_sub(1);
+        let size = (tsiz & XFRSIZ::mask).saturating_sub(data.len() as u32);
+        let tsiz = (tsiz & !(PKTCNT::mask | XFRSIZ::mask)) | (packets << PKTCNT::offset) | size;
+        self.set_reg(Self::ep_out(ep, DOEPTSIZ), tsiz);
+
+        // The transfer ends when all packets were received or on a short packet
+        if packets == 0 || data.len() < max_packet_size(ep, ctl) {
+            self.push_rx(ep, PKTSTS_OUT_COMPLETE, &[]);
+            self.clear_bits(Self::ep_out(ep, DOEPCTL), EPENA::mask);
+            self.set_bits(Self::ep_out(ep, DOEPINT), endpoint_out::DOEPINT::XFRC::mask);
+        }
+
+        Handshake::Ack
+    }
+
+    fn in_token(&mut self, address: u8, ep: usize) -> Result<Vec<u8>, Handshake> {
+        use endpoint_in::DIEPCTL::{STALL, NAKSTS, EPENA};
+        use endpoint_in::DIEPTSIZ::{PKTCNT, XFRSIZ};
+
+        if !self.responds_to(address) {
+            return Err(Handshake::NoResponse);
+        }
+
+        let ctl = self.reg(Self::ep_in(ep, DIEPCTL));
+        if ctl & STALL::mask != 0 {
+            return Err(Handshake::Stall);
+        }
+        if ctl & EPENA::mask == 0 || ctl & NAKSTS::mask != 0 {
+            return Err(Handshake::Nak);
+        }
+
+        let tsiz = self.reg(Self::ep_in(ep, DIEPTSIZ));
+        let remaining = (tsiz & XFRSIZ::mask) as usize;
+        let size = cmp::min(max_packet_size(ep, ctl), remaining);
+        if self.tx_fifos[ep].len() < size.div_ceil(4) {
+            // The packet is not in the FIFO yet
+            self.set_bits(Self::ep_in(ep, DIEPINT), endpoint_in::DIEPINT::ITTXFE::mask);
+            return Err(Handshake::Nak);
+        }
+
+        let mut data = Vec::with_capacity(size);
+        for _ in 0..size.div_ceil(4) {
+            let word = self.tx_fifos[ep].pop_front().unwrap();
+            data.extend_from_slice(&word.to_ne_bytes());
+        }
+        data.truncate(size);
+
+        let packets = ((tsiz & PKTCNT::mask) >> PKTCNT::offset).saturating_sub(1);
+        let tsiz = (tsiz & !(PKTCNT::mask | XFRSIZ::mask)) | (packets << PKTCNT::offset) | (remaining - size) as u32;
+        self.set_reg(Self::ep_in(ep, DIEPTSIZ), tsiz);
+
+        if packets == 0 {
+            self.clear_bits(Self::ep_in(ep, DIEPCTL), EPENA::mask);
+            self.set_bits(Self::ep_in(ep, DIEPINT), endpoint_in::DIEPINT::XFRC::mask);
+
+            // A new address set while handling SET_ADDRESS takes effect after its status stage
+            if ep == 0 {
+                self.address = self.programmed_address();
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+/// Host side of the simulated core.
+///
+/// Creating a `SimCore` resets the model to its power-on state.
+pub struct SimCore {
+    _session: MutexGuard<'static, ()>,
+}
+
+impl SimCore {
+    /// Takes exclusive access to the simulated core, waiting for other users to finish.
+    pub fn new() -> SimCore {
+        let session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+        model().reset();
+
+        SimCore { _session: session }
+    }
+
+    /// Returns `true` if the device pull-up is connected.
+    pub fn is_connected(&self) -> bool {
+        model().is_connected()
+    }
+
+    /// Returns `true` if an unmasked interrupt is pending and `poll` should be called.
+    pub fn interrupt_pending(&self) -> bool {
+        let model = model();
+        model.reg(GAHBCFG) & otg_global::GAHBCFG::GINT::mask != 0
+            && model.gintsts() & model.reg(GINTMSK) != 0
+    }
+
+    /// Returns the device address programmed by the driver.
+    ///
+    /// The device responds to a new address only after the status stage of the control transfer
+    /// in which it was programmed, tokens must be sent to `active_address` instead.
+    pub fn device_address(&self) -> u8 {
+        model().programmed_address()
+    }
+
+    /// Returns the address the device currently responds to.
+    pub fn active_address(&self) -> u8 {
+        model().address
+    }
+
+    /// Signals a bus reset, followed by enumeration at the speed configured by the driver.
+    pub fn bus_reset(&self) {
+        model().bus_reset();
+    }
+
+    /// Suspends the bus.
+    pub fn suspend(&self) {
+        let mut model = model();
+        model.set_bits(DSTS, otg_device::DSTS::SUSPSTS::mask);
+        model.set_bits(GINTSTS, otg_global::GINTSTS::USBSUSP::mask);
+    }
+
+    /// Resumes the bus.
+    pub fn resume(&self) {
+        let mut model = model();
+        model.clear_bits(DSTS, otg_device::DSTS::SUSPSTS::mask);
+        model.set_bits(GINTSTS, otg_global::GINTSTS::WKUPINT::mask);
+    }
+
+    /// Sends a start-of-frame packet with the next frame number.
+    ///
+    /// Full Speed frame numbers are 11 bits wide and roll over to 0 after 2047.
+    pub fn sof(&self) {
+        use otg_device::DSTS::FNSOF;
+
+        let mut model = model();
+        let dsts = model.reg(DSTS);
+        let frame = (((dsts & FNSOF::mask) >> FNSOF::offset) + 1) & 0x7ff;
+        model.set_reg(DSTS, (dsts & !FNSOF::mask) | (frame << FNSOF::offset));
+        model.set_bits(GINTSTS, otg_global::GINTSTS::SOF::mask);
+    }
+
+    /// Plugs or unplugs the cable, as seen by the VBUS sensing of the core.
+    pub fn set_vbus(&self, present: bool) {
+        model().set_vbus(present);
+    }
+
+    /// Returns `true` if the device requested a session with the Session Request Protocol.
+    pub fn session_requested(&self) -> bool {
+        model().reg(GOTGCTL) & otg_global::GOTGCTL::SRQ::mask != 0
+    }
+
+    /// Answers the pending session request of the device as the A-device, which switches VBUS on
+    /// if `success` is set.
+    pub fn answer_session_request(&self, success: bool) {
+        use otg_global::GOTGCTL::{SRQ, SRQSCS};
+
+        let mut model = model();
+        if model.reg(GOTGCTL) & SRQ::mask == 0 {
+            return;
+        }
+
+        if success {
+            model.set_bits(GOTGCTL, SRQSCS::mask);
+            model.set_vbus(true);
+        } else {
+            model.clear_bits(GOTGCTL, SRQSCS::mask);
+        }
+        model.set_bits(GOTGINT, otg_global::GOTGINT::SRSSCHG::mask);
+    }
+
+    /// Returns `true` if the device requested the host role with the Host Negotiation Protocol.
+    pub fn host_negotiation_requested(&self) -> bool {
+        model().reg(GOTGCTL) & otg_global::GOTGCTL::HNPRQ::mask != 0
+    }
+
+    /// Answers the pending host negotiation request of the device as the A-device. On success the
+    /// core switches to host mode.
+    pub fn answer_host_negotiation(&self, success: bool) {
+        use otg_global::GOTGCTL::{HNPRQ, HNGSCS};
+
+        let mut model = model();
+        if model.reg(GOTGCTL) & HNPRQ::mask == 0 {
+            return;
+        }
+
+        if success {
+            model.set_bits(GOTGCTL, HNGSCS::mask);
+            model.mode_host = true;
+        } else {
+            model.clear_bits(GOTGCTL, HNGSCS::mask);
+        }
+        model.set_bits(GOTGINT, otg_global::GOTGINT::HNSSCHG::mask);
+    }
+
+    /// Grounds or floats the ID pin, which selects the A-device (host) or the B-device role.
+    ///
+    /// Like a real core, the simulated core switches its mode only some time after the ID pin
+    /// changed, here when `finish_mode_switch` is called.
+    pub fn set_id_pin(&self, grounded: bool) {
+        let mut model = model();
+        if grounded {
+            model.clear_bits(GOTGCTL, otg_global::GOTGCTL::CIDSTS::mask);
+        } else {
+            model.set_bits(GOTGCTL, otg_global::GOTGCTL::CIDSTS::mask);
+        }
+        model.set_bits(GINTSTS, otg_global::GINTSTS::CIDSCHG::mask);
+    }
+
+    /// Switches the mode of the core to the role selected by the ID pin.
+    pub fn finish_mode_switch(&self) {
+        let mut model = model();
+        model.mode_host = model.reg(GOTGCTL) & otg_global::GOTGCTL::CIDSTS::mask == 0;
+    }
+
+    /// Connects a device to the root port of the core in host mode.
+    pub fn attach(&self, device: impl AttachedDevice + 'static) {
+        model().attach(Box::new(device));
+    }
+
+    /// Disconnects the device from the root port.
+    pub fn detach(&self) {
+        model().detach();
+    }
+
+    /// Pushes a raw entry into the RX FIFO, bypassing the endpoint state.
+    ///
+    /// `status` is the `PKTSTS` value of the entry, and `data` follows it in the FIFO. Returns
+    /// `false` if the RX FIFO has no room for the entry.
+    pub fn push_rx(&self, ep: u8, status: u8, data: &[u8]) -> bool {
+        let mut model = model();
+        if model.rx_fifo_free() < 1 + data.len().div_ceil(4) {
+            return false;
+        }
+        model.push_rx(ep as usize & 0xf, status as u32 & 0xf, data);
+        true
+    }
+
+    /// Returns `true` if the RX FIFO is empty.
+    pub fn rx_fifo_empty(&self) -> bool {
+        model().rx_status.is_empty()
+    }
+
+    /// Sets raw interrupt flags in `GINTSTS`.
+    pub fn raise_interrupts(&self, gintsts: u32) {
+        model().set_bits(GINTSTS, gintsts);
+    }
+
+    /// Returns the misuses of the core detected since the last call.
+    pub fn take_errors(&self) -> Vec<SimError> {
+        core::mem::take(&mut model().errors)
+    }
+
+    /// Sends a SETUP packet to a control endpoint of the device.
+    ///
+    /// The packet is sent to the address the device currently responds to, see `setup_to`.
+    pub fn setup(&self, ep: u8, packet: &[u8; 8]) -> Handshake {
+        self.setup_to(self.active_address(), ep, packet)
+    }
+
+    /// Sends an OUT data packet to an endpoint of the device.
+    pub fn out(&self, ep: u8, data: &[u8]) -> Handshake {
+        self.out_to(self.active_address(), ep, data)
+    }
+
+    /// Sends an IN token to an endpoint of the device, returning the data packet of the device.
+    pub fn in_token(&self, ep: u8) -> Result<Vec<u8>, Handshake> {
+        self.in_token_to(self.active_address(), ep)
+    }
+
+    /// Sends a SETUP packet to a control endpoint of the device at `address`.
+    pub fn setup_to(&self, address: u8, ep: u8, packet: &[u8; 8]) -> Handshake {
+        model().setup(address, ep as usize, packet)
+    }
+
+    /// Sends an OUT data packet to an endpoint of the device at `address`.
+    pub fn out_to(&self, address: u8, ep: u8, data: &[u8]) -> Handshake {
+        model().out(address, ep as usize, data)
+    }
+
+    /// Sends an IN token to an endpoint of the device at `address`, returning the data packet of
+    /// the device.
+    pub fn in_token_to(&self, address: u8, ep: u8) -> Result<Vec<u8>, Handshake> {
+        model().in_token(address, ep as usize)
+    }
+}
+
+impl Default for SimCore {
+    fn default() -> Self {
+        SimCore::new()
+    }
+}
+
+/// Critical sections on the simulated target
+///
+/// A global lock provides the exclusion that disabling interrupts provides on the real target.
+pub mod interrupt {
+    use core::cell::{Cell, RefCell};
+    use std::sync::{Mutex as StdMutex, MutexGuard};
+
+    /// Critical section token
+    pub struct CriticalSection {
+        _private: (),
+    }
+
+    /// A mutex that grants access to its data within a critical section
+    pub struct Mutex<T> {
+        inner: T,
+    }
+
+    impl<T> Mutex<T> {
+        /// Creates a new mutex.
+        pub const fn new(value: T) -> Self {
+            Mutex { inner: value }
+        }
+
+        /// Borrows the data for the duration of the critical section.
+        pub fn borrow<'cs>(&'cs self, _cs: &'cs CriticalSection) -> &'cs T {
+            &self.inner
+        }
+    }
+
+    unsafe impl<T: Send> Sync for Mutex<T> {}
+
+    static LOCK: StdMutex<()> = StdMutex::new(());
+
+    std::thread_local! {
+        static DEPTH: Cell<usize> = const { Cell::new(0) };
+        static GUARD: RefCell<Option<MutexGuard<'static, ()>>> = const { RefCell::new(None) };
+    }
+
+    /// Executes the closure in a critical section. Critical sections can be nested.
+    pub fn free<F, R>(f: F) -> R
+        where F: FnOnce(&CriticalSection) -> R
+    {
+        if DEPTH.get() == 0 {
+            let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            GUARD.with(|g| *g.borrow_mut() = Some(guard));
+        }
+        DEPTH.set(DEPTH.get() + 1);
+
+        let result = f(&CriticalSection { _private: () });
+
+        DEPTH.set(DEPTH.get() - 1);
+        if DEPTH.get() == 0 {
+            GUARD.with(|g| g.borrow_mut().take());
+        }
+
+        result
+    }
+}
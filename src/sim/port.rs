@@ -0,0 +1,290 @@
+//! Host mode of the simulated core: the root port and the host channels.
+//!
+//! The host driver waits for its transactions by polling the core, so a channel enabled by the
+//! driver performs its transaction with the attached device the next time `GINTSTS` or `HCINT`
+//! is read, once the data of an OUT transaction has been written to the TX FIFO.
+
+use core::convert::TryFrom;
+use std::boxed::Box;
+use std::vec::Vec;
+use crate::ral::{otg_global, otg_host, host_channel};
+use crate::bus::UsbSpeed;
+use super::{Handshake, Model, SimError};
+use super::{GINTSTS, DIEPTXF0, HPTXFSIZ, HPRT, CHANNELS, HCCHAR, HCINT, HCTSIZ};
+
+/// RX FIFO packet status values in host mode
+const PKTSTS_IN_DATA: u32 = 0x02;
+const PKTSTS_IN_COMPLETE: u32 = 0x03;
+
+/// `HCTSIZ.DPID` value of SETUP packets
+const DPID_SETUP: u32 = 0b11;
+
+/// USB device connected to the root port of the simulated core in host mode
+///
+/// The device sees every token sent by the host and must ignore the ones that are not sent to
+/// its address by returning `Handshake::NoResponse`.
+pub trait AttachedDevice: Send {
+    /// Speed of the device, reported by the port after a reset
+    fn speed(&self) -> UsbSpeed {
+        UsbSpeed::Full
+    }
+
+    /// Handles a port reset, which returns the device to address 0.
+    fn reset(&mut self);
+
+    /// Handles a SETUP packet sent to a control endpoint.
+    fn setup(&mut self, address: u8, ep: u8, packet: &[u8; 8]) -> Handshake;
+
+    /// Handles an OUT data packet.
+    fn out(&mut self, address: u8, ep: u8, data: &[u8]) -> Handshake;
+
+    /// Handles an IN token, returning the data packet of the device.
+    fn in_token(&mut self, address: u8, ep: u8) -> Result<Vec<u8>, Handshake>;
+}
+
+fn field(value: u32, mask: u32, offset: u32) -> u32 {
+    (value & mask) >> offset
+}
+
+impl Model {
+    pub(super) fn host_mode(&self) -> bool {
+        use otg_global::GUSBCFG::{FHMOD, FDMOD};
+
+        let usbcfg = self.reg(super::GUSBCFG);
+        usbcfg & FHMOD::mask != 0 || (usbcfg & FDMOD::mask == 0 && self.mode_host)
+    }
+
+    pub(super) fn channel(ch: usize, reg: usize) -> usize {
+        CHANNELS + 0x20 * ch + reg
+    }
+
+    /// Returns the offset of a host channel register within the registers of its channel.
+    pub(super) fn channel_register(offset: usize) -> Option<usize> {
+        (CHANNELS..CHANNELS + 0x200).contains(&offset).then(|| (offset - CHANNELS) % 0x20)
+    }
+
+    fn is_periodic(&self, ch: usize) -> bool {
+        use host_channel::HCCHAR::EPTYP;
+
+        // Isochronous or interrupt
+        field(self.reg(Self::channel(ch, HCCHAR)), EPTYP::mask, EPTYP::offset) & 1 != 0
+    }
+
+    /// Returns the free space of the periodic or non-periodic TX FIFO in words.
+    pub(super) fn host_tx_fifo_free(&self, periodic: bool) -> usize {
+        let depth = if periodic {
+            field(self.reg(HPTXFSIZ), otg_global::HPTXFSIZ::PTXFD::mask, otg_global::HPTXFSIZ::PTXFD::offset)
+        } else {
+            field(self.reg(DIEPTXF0), otg_global::DIEPTXF0::NPTXFD::mask, otg_global::DIEPTXF0::NPTXFD::offset)
+        };
+        let used: usize = (0..16)
+            .filter(|&ch| self.is_periodic(ch) == periodic)
+            .map(|ch| self.tx_fifos[ch].len())
+            .sum();
+        (depth as usize).saturating_sub(used)
+    }
+
+    /// Handles a write to the data FIFO of a host channel.
+    pub(super) fn write_channel_fifo(&mut self, ch: usize, value: u32) {
+        if self.host_tx_fifo_free(self.is_periodic(ch)) == 0 {
+            self.errors.push(SimError::TxFifoOverflow);
+        } else {
+            self.tx_fifos[ch].push_back(value);
+        }
+    }
+
+    /// Advances the frame number, so that the timeouts of the driver expire.
+    pub(super) fn next_frame(&mut self) -> u32 {
+        let offset = super::HFNUM;
+        let frame = (self.reg(offset) + 1) & 0x3fff;
+        self.set_reg(offset, frame);
+        frame
+    }
+
+    pub(super) fn port_interrupt(&self) -> bool {
+        use otg_host::HPRT::{PCDET, PENCHNG, POCCHNG};
+
+        self.reg(HPRT) & (PCDET::mask | PENCHNG::mask | POCCHNG::mask) != 0
+    }
+
+    pub(super) fn write_hprt(&mut self, value: u32) {
+        use otg_host::HPRT::{PCSTS, PCDET, PENA, PENCHNG, POCA, POCCHNG, PRST, PLSTS, PSPD};
+
+        let old = self.reg(HPRT);
+        let w1c = PCDET::mask | PENCHNG::mask | POCCHNG::mask;
+        let status = PCSTS::mask | PENA::mask | POCA::mask | PLSTS::mask | PSPD::mask;
+
+        let mut new = (old & (status | w1c) & !(value & w1c)) | (value & !(status | w1c));
+        // Writing 1 disables the port
+        if value & PENA::mask != 0 {
+            new &= !PENA::mask;
+        }
+
+        if value & PRST::mask != 0 {
+            new &= !PENA::mask;
+        } else if old & PRST::mask != 0 && new & PCSTS::mask != 0 {
+            // The port is enabled at the speed of the device once the reset is released
+            if let Some(device) = self.device.as_mut() {
+                device.reset();
+                let speed = match device.speed() {
+                    UsbSpeed::High => 0b00,
+                    UsbSpeed::Full => 0b01,
+                    UsbSpeed::Low => 0b10,
+                };
+                new = (new & !PSPD::mask) | (speed << PSPD::offset) | PENA::mask | PENCHNG::mask;
+            }
+        }
+
+        self.set_reg(HPRT, new);
+    }
+
+    pub(super) fn attach(&mut self, device: Box<dyn AttachedDevice>) {
+        use otg_host::HPRT::{PCSTS, PCDET};
+
+        self.device = Some(device);
+        self.set_bits(HPRT, PCSTS::mask | PCDET::mask);
+    }
+
+    pub(super) fn detach(&mut self) {
+        use otg_host::HPRT::{PCSTS, PENA};
+
+        self.device = None;
+        self.clear_bits(HPRT, PCSTS::mask | PENA::mask);
+        self.set_bits(GINTSTS, otg_global::GINTSTS::DISCINT::mask);
+    }
+
+    /// Handles a write to HCCHAR, which enables or halts the channel.
+    pub(super) fn write_hcchar(&mut self, ch: usize, value: u32) {
+        use host_channel::HCCHAR::{CHDIS, CHENA};
+
+        let offset = Self::channel(ch, HCCHAR);
+        let enabled = self.reg(offset) & CHENA::mask != 0;
+
+        if value & CHDIS::mask != 0 {
+            if enabled {
+                self.halt_channel(ch);
+                self.set_bits(Self::channel(ch, HCINT), host_channel::HCINT::CHH::mask);
+            }
+            return;
+        }
+
+        self.set_reg(offset, value);
+        if value & CHENA::mask != 0 {
+            self.pending_channels |= 1 << ch;
+        }
+    }
+
+    fn halt_channel(&mut self, ch: usize) {
+        self.clear_bits(Self::channel(ch, HCCHAR), host_channel::HCCHAR::CHENA::mask);
+        self.pending_channels &= !(1 << ch);
+        self.tx_fifos[ch].clear();
+    }
+
+    /// Performs the transactions of the enabled channels.
+    pub(super) fn run_channels(&mut self) {
+        for ch in 0..16 {
+            if self.pending_channels & (1 << ch) != 0 {
+                self.run_channel(ch);
+            }
+        }
+    }
+
+    fn run_channel(&mut self, ch: usize) {
+        use host_channel::HCCHAR::{MPSIZ, EPNUM, EPDIR, DAD, CHENA};
+        use host_channel::HCTSIZ::{XFRSIZ, PKTCNT, DPID};
+        use host_channel::HCINT::{XFRC, ACK, TXERR, BBERR};
+
+        let hcchar = self.reg(Self::channel(ch, HCCHAR));
+        let hctsiz = self.reg(Self::channel(ch, HCTSIZ));
+        let address = field(hcchar, DAD::mask, DAD::offset) as u8;
+        let ep = field(hcchar, EPNUM::mask, EPNUM::offset) as u8;
+        let max_packet_size = field(hcchar, MPSIZ::mask, MPSIZ::offset) as usize;
+        let size = field(hctsiz, XFRSIZ::mask, XFRSIZ::offset) as usize;
+        let is_in = hcchar & EPDIR::mask != 0;
+
+        let port_enabled = self.reg(HPRT) & otg_host::HPRT::PENA::mask != 0;
+        let mut device = match self.device.take() {
+            Some(device) if port_enabled => device,
+            device => {
+                // Nobody answers the token
+                self.device = device;
+                self.pending_channels &= !(1 << ch);
+                self.set_bits(Self::channel(ch, HCINT), TXERR::mask);
+                return;
+            }
+        };
+
+        let result = if is_in {
+            // Room for the largest packet and the status entries
+            if self.rx_fifo_free() < 2 + max_packet_size.div_ceil(4) {
+                self.device = Some(device);
+                return;
+            }
+
+            match device.in_token(address, ep) {
+                Ok(data) if data.len() > max_packet_size => Err(BBERR::mask),
+                Ok(data) => {
+                    self.push_rx(ch, PKTSTS_IN_DATA, &data);
+                    self.push_rx(ch, PKTSTS_IN_COMPLETE, &[]);
+                    Ok(size.saturating_sub(data.len()))
+                }
+                Err(handshake) => Err(Self::handshake_interrupt(handshake)),
+            }
+        } else {
+            // The packet is not in the FIFO yet
+            if self.tx_fifos[ch].len() < size.div_ceil(4) {
+                self.device = Some(device);
+                return;
+            }
+
+            let mut data = Vec::with_capacity(size);
+            for _ in 0..size.div_ceil(4) {
+                let word = self.tx_fifos[ch].pop_front().unwrap();
+                data.extend_from_slice(&word.to_ne_bytes());
+            }
+            data.truncate(size);
+
+            let handshake = if field(hctsiz, DPID::mask, DPID::offset) == DPID_SETUP {
+                match <&[u8; 8]>::try_from(data.as_slice()) {
+                    Ok(packet) => device.setup(address, ep, packet),
+                    Err(_) => Handshake::NoResponse,
+                }
+            } else {
+                device.out(address, ep, &data)
+            };
+
+            match handshake {
+                Handshake::Ack => Ok(0),
+                handshake => Err(Self::handshake_interrupt(handshake)),
+            }
+        };
+
+        self.device = Some(device);
+        self.pending_channels &= !(1 << ch);
+
+        match result {
+            Ok(remaining) => {
+                let hctsiz = (hctsiz & !(PKTCNT::mask | XFRSIZ::mask)) | remaining as u32;
+                self.set_reg(Self::channel(ch, HCTSIZ), hctsiz);
+                self.clear_bits(Self::channel(ch, HCCHAR), CHENA::mask);
+                self.set_bits(Self::channel(ch, HCINT), XFRC::mask | ACK::mask);
+            }
+            Err(hcint) => {
+                // The channel stays enabled until it is halted by the driver
+                self.tx_fifos[ch].clear();
+                self.set_bits(Self::channel(ch, HCINT), hcint);
+            }
+        }
+    }
+
+    fn handshake_interrupt(handshake: Handshake) -> u32 {
+        use host_channel::HCINT::{NAK, STALL, TXERR};
+
+        match handshake {
+            Handshake::Nak => NAK::mask,
+            Handshake::Stall => STALL::mask,
+            // A response timeout is a transaction error
+            Handshake::Ack | Handshake::NoResponse => TXERR::mask,
+        }
+    }
+}
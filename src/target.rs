@@ -3,18 +3,20 @@
 //! Target-specific definitions
 
 use vcell::VolatileCell;
-use core::marker::PhantomData;
+use crate::ral::RWRegister;
 
-#[cfg(feature = "cortex-m")]
+#[cfg(all(feature = "cortex-m", not(feature = "sim")))]
 pub use cortex_m::interrupt;
-#[cfg(feature = "riscv")]
+#[cfg(all(feature = "riscv", not(feature = "sim")))]
 pub use riscv::interrupt;
+#[cfg(feature = "sim")]
+pub use crate::sim::interrupt;
 
-use crate::ral::{otg_global, otg_device, otg_pwrclk, otg_fifo};
+use crate::ral::{otg_global, otg_global_dieptxfx, otg_device, otg_host, otg_pwrclk, otg_fifo, endpoint_in, endpoint_out, endpoint0_out, host_channel};
 use crate::UsbPeripheral;
 
-pub fn fifo_write(channel: impl Into<usize>, mut buf: &[u8]) {
-    let fifo = otg_fifo::instance(channel.into());
+pub fn fifo_write(usb: UsbRegisters, channel: impl Into<usize>, mut buf: &[u8]) {
+    let fifo = usb.fifo(channel.into());
 
     while buf.len() >= 4 {
         let mut u32_bytes = [0u8; 4];
@@ -22,15 +24,23 @@ pub fn fifo_write(channel: impl Into<usize>, mut buf: &[u8]) {
         buf = &buf[4..];
         fifo.write(u32::from_ne_bytes(u32_bytes));
     }
-    if buf.len() > 0 {
+    if !buf.is_empty() {
         let mut u32_bytes = [0u8; 4];
         u32_bytes[..buf.len()].copy_from_slice(buf);
         fifo.write(u32::from_ne_bytes(u32_bytes));
     }
 }
 
-pub fn fifo_read(mut buf: &mut [u8]) {
-    let fifo = otg_fifo::instance(0);
+pub fn fifo_write_from(usb: UsbRegisters, channel: impl Into<usize>, buf: &[VolatileCell<u32>]) {
+    let fifo = usb.fifo(channel.into());
+
+    for p in buf {
+        fifo.write(p.get());
+    }
+}
+
+pub fn fifo_read(usb: UsbRegisters, mut buf: &mut [u8]) {
+    let fifo = usb.fifo(0);
 
     while buf.len() >= 4 {
         let word = fifo.read();
@@ -38,15 +48,15 @@ pub fn fifo_read(mut buf: &mut [u8]) {
         buf[..4].copy_from_slice(&bytes);
         buf = &mut buf[4..];
     }
-    if buf.len() > 0 {
+    if !buf.is_empty() {
         let word = fifo.read();
         let bytes = word.to_ne_bytes();
         buf.copy_from_slice(&bytes[..buf.len()]);
     }
 }
 
-pub fn fifo_read_into(buf: &[VolatileCell<u32>]) {
-    let fifo = otg_fifo::instance(0);
+pub fn fifo_read_into(usb: UsbRegisters, buf: &[VolatileCell<u32>]) {
+    let fifo = usb.fifo(0);
 
     for p in buf {
         let word = fifo.read();
@@ -55,22 +65,62 @@ pub fn fifo_read_into(buf: &[VolatileCell<u32>]) {
 }
 
 /// Wrapper around device-specific peripheral that provides unified register interface
-pub struct UsbRegisters<USB> {
-    pub global: otg_global::Instance,
-    pub device: otg_device::Instance,
-    pub pwrclk: otg_pwrclk::Instance,
-    _marker: PhantomData<USB>,
-}
+#[derive(Clone, Copy)]
+pub struct UsbRegisters(usize);
 
-unsafe impl<USB> Send for UsbRegisters<USB> {}
+impl UsbRegisters {
+    #[inline(always)]
+    pub fn new<USB: UsbPeripheral>() -> Self {
+        Self(USB::REGISTERS as usize)
+    }
+
+    #[inline(always)]
+    pub fn global(&self) -> &'static otg_global::RegisterBlock {
+        unsafe { &*(self.0 as *const _) }
+    }
+
+    #[inline(always)]
+    pub fn dieptxf(&self, index: usize) -> otg_global_dieptxfx::Instance {
+        otg_global_dieptxfx::instance(self.0, index)
+    }
+
+    #[inline(always)]
+    pub fn device(&self) -> &'static otg_device::RegisterBlock {
+        unsafe { &*((self.0 + 0x800) as *const _) }
+    }
+
+    #[inline(always)]
+    pub fn host(&self) -> &'static otg_host::RegisterBlock {
+        unsafe { &*((self.0 + 0x400) as *const _) }
+    }
+
+    #[inline(always)]
+    pub fn host_channel(&self, index: usize) -> host_channel::Instance {
+        host_channel::instance(self.0, index)
+    }
+
+    #[inline(always)]
+    pub fn pwrclk(&self) -> &'static otg_pwrclk::RegisterBlock {
+        unsafe { &*((self.0 + 0xe00) as *const _) }
+    }
+
+    #[inline(always)]
+    pub fn endpoint_in(&self, index: usize) -> endpoint_in::Instance {
+        endpoint_in::instance(self.0, index)
+    }
+
+    #[inline(always)]
+    pub fn endpoint0_out(&self) -> endpoint0_out::Instance {
+        endpoint0_out::instance(self.0)
+    }
+
+    #[inline(always)]
+    pub fn endpoint_out(&self, index: usize) -> endpoint_out::Instance {
+        endpoint_out::instance(self.0, index)
+    }
 
-impl<USB: UsbPeripheral> UsbRegisters<USB> {
-    pub fn new() -> Self {
-        Self {
-            global: unsafe { otg_global::OTG_GLOBAL::steal() },
-            device: unsafe { otg_device::OTG_DEVICE::steal() },
-            pwrclk: unsafe { otg_pwrclk::OTG_PWRCLK::steal() },
-            _marker: PhantomData,
-        }
+    #[inline(always)]
+    pub fn fifo(&self, channel: usize) -> &'static RWRegister<u32> {
+        otg_fifo::instance(self.0, channel)
     }
 }
@@ -0,0 +1,261 @@
+#![cfg(feature = "sim")]
+
+//! USB 2.0 Chapter 9 standard requests, executed against `UsbBus` on the simulated core
+
+use synopsys_usb_otg::sim::{Handshake, Request, VirtualHost};
+use usb_device::device::UsbDeviceState;
+use usb_device::test_class;
+
+mod common;
+
+use common::{with_host, TestDevice};
+
+// Full Speed packet sizes used by `TestClass`
+const CONTROL_PACKET_SIZE: usize = 8;
+const BULK_PACKET_SIZE: usize = 64;
+
+const BULK_IN: u8 = 0x81;
+const BULK_OUT: u8 = 0x01;
+
+const DEVICE_DESCRIPTOR: u8 = 0x01;
+const CONFIGURATION_DESCRIPTOR: u8 = 0x02;
+const STRING_DESCRIPTOR: u8 = 0x03;
+
+const ENDPOINT_HALT: u16 = 0;
+
+fn state(host: &mut VirtualHost<TestDevice>) -> UsbDeviceState {
+    host.device().device.state()
+}
+
+fn get_status(recipient: u8, index: u16) -> Request {
+    Request { request_type: 0x80 | recipient, request: 0x00, value: 0, index }
+}
+
+fn clear_feature(recipient: u8, feature: u16, index: u16) -> Request {
+    Request { request_type: recipient, request: 0x01, value: feature, index }
+}
+
+fn set_feature(recipient: u8, feature: u16, index: u16) -> Request {
+    Request { request_type: recipient, request: 0x03, value: feature, index }
+}
+
+fn get_configuration() -> Request {
+    Request { request_type: 0x80, request: 0x08, value: 0, index: 0 }
+}
+
+fn endpoint_halted(host: &mut VirtualHost<TestDevice>, ep: u8) -> bool {
+    let status = host.control_in(get_status(0x02, ep as u16), 2).unwrap();
+    u16::from_le_bytes([status[0], status[1]]) & 1 != 0
+}
+
+fn bulk_loopback(host: &mut VirtualHost<TestDevice>, data: &[u8]) {
+    host.out_transfer(BULK_OUT, BULK_PACKET_SIZE, data).unwrap();
+    let echoed = host.in_transfer(BULK_IN & 0x7f, BULK_PACKET_SIZE, data.len()).unwrap();
+    assert_eq!(echoed, data);
+}
+
+#[test]
+fn get_device_descriptor_lengths() {
+    with_host(|host| {
+        let descriptor = host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).unwrap();
+        assert_eq!(descriptor.len(), 18);
+        assert_eq!(descriptor[0], 18);
+
+        for length in [1, 2, 7, 8, 9, 15, 16, 17, 19, 64, 255, 0xffff] {
+            let partial = host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, length).unwrap();
+            assert_eq!(partial, descriptor[..descriptor.len().min(length as usize)], "wLength {}", length);
+        }
+    });
+}
+
+#[test]
+fn get_configuration_descriptor_lengths() {
+    with_host(|host| {
+        let header = host.get_descriptor(CONFIGURATION_DESCRIPTOR, 0, 0, 9).unwrap();
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let descriptor = host.get_descriptor(CONFIGURATION_DESCRIPTOR, 0, 0, total_length).unwrap();
+        assert_eq!(descriptor.len(), total_length as usize);
+        assert_eq!(descriptor[..9], header[..]);
+
+        let lengths = [1, 4, 8, 16, total_length - 1, total_length + 1, total_length + 8, 255, 0xffff];
+        for length in lengths {
+            let partial = host.get_descriptor(CONFIGURATION_DESCRIPTOR, 0, 0, length).unwrap();
+            assert_eq!(partial, descriptor[..descriptor.len().min(length as usize)], "wLength {}", length);
+        }
+    });
+}
+
+#[test]
+fn get_descriptor_zero_length() {
+    with_host(|host| {
+        assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 0).unwrap(), []);
+        assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).unwrap().len(), 18);
+    });
+}
+
+#[test]
+fn get_descriptor_ends_with_zero_length_packet() {
+    with_host(|host| {
+        // The custom string of TestClass (index 4) is 48 bytes long, a multiple of the packet size
+        // of endpoint 0, so the device must end the data stage with a zero-length packet
+        let string = host.get_descriptor(STRING_DESCRIPTOR, 4, 0x0409, 255).unwrap();
+        assert_eq!(string.len(), 2 + 2 * test_class::CUSTOM_STRING.len());
+        assert_eq!(string.len() % CONTROL_PACKET_SIZE, 0);
+        assert_eq!(host.get_string(4, 0x0409).unwrap(), test_class::CUSTOM_STRING);
+    });
+}
+
+#[test]
+fn get_unknown_descriptor_stalls() {
+    with_host(|host| {
+        assert_eq!(host.get_descriptor(0x21, 0, 0, 255), Err(Handshake::Stall));
+        assert_eq!(host.get_descriptor(STRING_DESCRIPTOR, 100, 0x0409, 255), Err(Handshake::Stall));
+
+        assert!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).is_ok());
+    });
+}
+
+#[test]
+fn setup_while_in_data_is_pending() {
+    with_host(|host| {
+        let device_descriptor = host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).unwrap();
+
+        // Abort a control read after the first packet of the data stage
+        host.setup(&Request::get_descriptor(CONFIGURATION_DESCRIPTOR, 0, 0).to_bytes(255)).unwrap();
+        assert_eq!(host.in_packet(0).unwrap().len(), CONTROL_PACKET_SIZE);
+        assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).unwrap(), device_descriptor);
+
+        // Abort a control read before its data stage has started
+        host.setup(&Request::get_descriptor(CONFIGURATION_DESCRIPTOR, 0, 0).to_bytes(255)).unwrap();
+        assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).unwrap(), device_descriptor);
+
+        // Abort the status stage of a control read
+        host.setup(&Request::get_descriptor(DEVICE_DESCRIPTOR, 0, 0).to_bytes(18)).unwrap();
+        assert_eq!(host.in_transfer(0, CONTROL_PACKET_SIZE, 18).unwrap(), device_descriptor);
+        assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).unwrap(), device_descriptor);
+    });
+}
+
+#[test]
+fn set_address_takes_effect_after_status_stage() {
+    with_host(|host| {
+        host.setup(&Request::set_address(12).to_bytes(0)).unwrap();
+
+        // The status stage is still addressed to the default address
+        assert_eq!(host.core().active_address(), 0);
+        assert_eq!(host.in_packet(0).unwrap(), []);
+        assert_eq!(host.core().active_address(), 12);
+        assert_eq!(state(host), UsbDeviceState::Addressed);
+
+        let packet = Request::get_descriptor(DEVICE_DESCRIPTOR, 0, 0).to_bytes(18);
+        assert_eq!(host.core().setup_to(0, 0, &packet), Handshake::NoResponse);
+        assert_eq!(host.core().setup_to(12, 0, &packet), Handshake::Ack);
+        host.poll();
+        assert_eq!(host.core().in_token_to(12, 0).unwrap().len(), CONTROL_PACKET_SIZE);
+    });
+}
+
+#[test]
+fn set_address_again() {
+    with_host(|host| {
+        host.control_out(Request::set_address(12), &[]).unwrap();
+        assert_eq!(host.core().active_address(), 12);
+
+        host.control_out(Request::set_address(34), &[]).unwrap();
+        assert_eq!(host.core().active_address(), 34);
+        assert_eq!(state(host), UsbDeviceState::Addressed);
+        assert!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).is_ok());
+
+        // A bus reset returns the device to the default address
+        host.bus_reset();
+        assert_eq!(host.core().active_address(), 0);
+        assert_eq!(state(host), UsbDeviceState::Default);
+        assert!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).is_ok());
+    });
+}
+
+#[test]
+fn get_status_of_device_interface_and_endpoints() {
+    with_host(|host| {
+        host.enumerate(5).unwrap();
+
+        assert_eq!(host.control_in(get_status(0x00, 0), 2).unwrap().len(), 2);
+        assert_eq!(host.control_in(get_status(0x01, 0), 2).unwrap(), [0, 0]);
+        assert_eq!(host.control_in(get_status(0x02, BULK_IN as u16), 2).unwrap(), [0, 0]);
+        assert_eq!(host.control_in(get_status(0x02, BULK_OUT as u16), 2).unwrap(), [0, 0]);
+    });
+}
+
+#[test]
+fn endpoint_halt() {
+    with_host(|host| {
+        host.enumerate(5).unwrap();
+
+        for ep in [BULK_IN, BULK_OUT] {
+            host.control_out(set_feature(0x02, ENDPOINT_HALT, ep as u16), &[]).unwrap();
+            assert!(endpoint_halted(host, ep));
+        }
+
+        assert_eq!(host.in_packet(BULK_IN & 0x7f), Err(Handshake::Stall));
+        assert_eq!(host.out_packet(BULK_OUT, &[1, 2, 3]), Err(Handshake::Stall));
+
+        // Setting the feature again keeps the endpoint halted
+        host.control_out(set_feature(0x02, ENDPOINT_HALT, BULK_IN as u16), &[]).unwrap();
+        assert!(endpoint_halted(host, BULK_IN));
+
+        for ep in [BULK_IN, BULK_OUT] {
+            host.control_out(clear_feature(0x02, ENDPOINT_HALT, ep as u16), &[]).unwrap();
+            assert!(!endpoint_halted(host, ep));
+        }
+
+        bulk_loopback(host, &[1, 2, 3]);
+
+        // Clearing the feature of an endpoint that is not halted is allowed
+        host.control_out(clear_feature(0x02, ENDPOINT_HALT, BULK_IN as u16), &[]).unwrap();
+        assert!(!endpoint_halted(host, BULK_IN));
+        bulk_loopback(host, &[4, 5, 6]);
+    });
+}
+
+#[test]
+fn set_configuration_again() {
+    with_host(|host| {
+        host.enumerate(5).unwrap();
+        assert_eq!(host.control_in(get_configuration(), 1).unwrap(), [1]);
+        bulk_loopback(host, &[1; 10]);
+
+        // Selecting the current configuration again keeps the endpoints working
+        host.control_out(Request::set_configuration(1), &[]).unwrap();
+        assert_eq!(state(host), UsbDeviceState::Configured);
+        bulk_loopback(host, &[2; BULK_PACKET_SIZE + 1]);
+
+        // Configuration 0 returns the device to the Addressed state
+        host.control_out(Request::set_configuration(0), &[]).unwrap();
+        assert_eq!(state(host), UsbDeviceState::Addressed);
+        assert_eq!(host.control_in(get_configuration(), 1).unwrap(), [0]);
+
+        host.control_out(Request::set_configuration(1), &[]).unwrap();
+        assert_eq!(state(host), UsbDeviceState::Configured);
+        assert_eq!(host.control_in(get_configuration(), 1).unwrap(), [1]);
+        bulk_loopback(host, &[3; 100]);
+    });
+}
+
+#[test]
+fn set_configuration_in_default_state_stalls() {
+    with_host(|host| {
+        assert_eq!(host.control_out(Request::set_configuration(0), &[]), Err(Handshake::Stall));
+        assert_eq!(state(host), UsbDeviceState::Default);
+    });
+}
+
+#[test]
+fn set_invalid_configuration_stalls() {
+    with_host(|host| {
+        host.enumerate(5).unwrap();
+
+        assert_eq!(host.control_out(Request::set_configuration(2), &[]), Err(Handshake::Stall));
+        assert_eq!(state(host), UsbDeviceState::Configured);
+        assert_eq!(host.control_in(get_configuration(), 1).unwrap(), [1]);
+    });
+}
@@ -0,0 +1,43 @@
+//! Test class device on the simulated core, shared by the tests driving it through the virtual host
+
+use synopsys_usb_otg::sim::{SimCore, SimDevice, SimUsb, VirtualHost};
+use synopsys_usb_otg::UsbBus;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDevice, UsbDeviceState};
+use usb_device::test_class::TestClass;
+
+pub type Bus = UsbBus<SimUsb>;
+
+pub fn allocator() -> UsbBusAllocator<Bus> {
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    UsbBus::new(SimUsb, ep_memory)
+}
+
+pub struct TestDevice<'a> {
+    pub device: UsbDevice<'a, Bus>,
+    pub class: TestClass<'a, Bus>,
+}
+
+impl SimDevice for TestDevice<'_> {
+    fn poll(&mut self) {
+        self.device.poll(&mut [&mut self.class]);
+        self.class.poll();
+    }
+}
+
+/// Runs `f` with a `TestClass` device that is connected and reset by the virtual host.
+pub fn with_host(f: impl FnOnce(&mut VirtualHost<TestDevice>)) {
+    let sim = SimCore::new();
+    let alloc = allocator();
+    let class = TestClass::new(&alloc);
+    let device = class.make_device(&alloc);
+
+    let mut host = VirtualHost::new(sim, TestDevice { device, class });
+    host.poll();
+    assert!(host.core().is_connected());
+
+    host.bus_reset();
+    assert_eq!(host.device().device.state(), UsbDeviceState::Default);
+
+    f(&mut host);
+}
@@ -0,0 +1,52 @@
+#![cfg(feature = "sim")]
+
+//! Runs the fuzzing harness of `fuzz/` on fixed inputs, so that it is exercised by `cargo test`
+
+#[path = "../fuzz/harness.rs"]
+mod harness;
+
+/// xorshift32, for reproducible pseudo-random inputs
+fn random_input(seed: u32, length: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..length)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn empty_input() {
+    harness::run(&[]);
+}
+
+#[test]
+fn setup_while_endpoint_0_is_full() {
+    // SETUP, poll without reading, OUT on endpoint 0, SETUP again, poll without reading
+    harness::run(&[
+        1, 0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00,
+        7, 0,
+        2, 4, 1, 2, 3, 4,
+        1, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        7, 0, 7, 0, 7, 0,
+    ]);
+}
+
+#[test]
+fn oversized_packet() {
+    // 200 bytes of OUT data for a bulk endpoint with a packet size of 64, then a SETUP packet
+    let mut input = vec![3, 0, 1, 200];
+    input.extend_from_slice(&[0x55; 200]);
+    input.extend_from_slice(&[7, 0, 7, 0, 1, 0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00, 7, 1]);
+    harness::run(&input);
+}
+
+#[test]
+fn random_inputs() {
+    for seed in 1..=200 {
+        harness::run(&random_input(seed, 512));
+    }
+}
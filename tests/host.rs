@@ -0,0 +1,200 @@
+#![cfg(feature = "sim")]
+
+use std::collections::VecDeque;
+use synopsys_usb_otg::host::{Channel, ChannelConfig, HostError, HostEvent};
+use synopsys_usb_otg::sim::{AttachedDevice, Handshake, SimCore, SimUsb};
+use synopsys_usb_otg::{UsbHost, UsbSpeed};
+use usb_device::endpoint::EndpointType;
+
+const DEVICE_DESCRIPTOR: [u8; 18] = [
+    18, 1, 0x00, 0x02, 0xff, 0x00, 0x00, 8,
+    0x09, 0x12, 0x78, 0x56, 0x00, 0x01, 0, 0, 0, 1,
+];
+
+/// Full Speed device with an 8 byte control endpoint 0 and a bulk loopback endpoint 1
+#[derive(Default)]
+struct LoopbackDevice {
+    address: u8,
+    pending_address: Option<u8>,
+    control_in: VecDeque<u8>,
+    control_stalled: bool,
+    bulk: VecDeque<Vec<u8>>,
+}
+
+impl AttachedDevice for LoopbackDevice {
+    fn reset(&mut self) {
+        *self = LoopbackDevice::default();
+    }
+
+    fn setup(&mut self, address: u8, ep: u8, packet: &[u8; 8]) -> Handshake {
+        if address != self.address || ep != 0 {
+            return Handshake::NoResponse;
+        }
+
+        let length = u16::from_le_bytes([packet[6], packet[7]]) as usize;
+        self.control_in.clear();
+        self.control_stalled = false;
+        match (packet[0], packet[1], packet[3]) {
+            // GET_DESCRIPTOR(DEVICE)
+            (0x80, 0x06, 0x01) => self.control_in.extend(&DEVICE_DESCRIPTOR[..length.min(18)]),
+            // SET_ADDRESS
+            (0x00, 0x05, _) => self.pending_address = Some(packet[2]),
+            _ => self.control_stalled = true,
+        }
+        Handshake::Ack
+    }
+
+    fn out(&mut self, address: u8, ep: u8, data: &[u8]) -> Handshake {
+        if address != self.address {
+            return Handshake::NoResponse;
+        }
+
+        match ep {
+            // Status stage of a control read
+            0 => Handshake::Ack,
+            1 => {
+                self.bulk.push_back(data.to_vec());
+                Handshake::Ack
+            }
+            _ => Handshake::Stall,
+        }
+    }
+
+    fn in_token(&mut self, address: u8, ep: u8) -> Result<Vec<u8>, Handshake> {
+        if address != self.address {
+            return Err(Handshake::NoResponse);
+        }
+
+        match ep {
+            0 if self.control_stalled => Err(Handshake::Stall),
+            0 => {
+                let size = self.control_in.len().min(8);
+                if size == 0 {
+                    // Status stage of a control write
+                    if let Some(address) = self.pending_address.take() {
+                        self.address = address;
+                    }
+                }
+                Ok(self.control_in.drain(..size).collect())
+            }
+            1 => self.bulk.pop_front().ok_or(Handshake::Nak),
+            _ => Err(Handshake::Stall),
+        }
+    }
+}
+
+/// Runs `f` with an enabled host whose port has a `LoopbackDevice` connected and reset.
+fn with_device(f: impl FnOnce(&mut UsbHost<SimUsb>)) {
+    let core = SimCore::new();
+    let mut host = UsbHost::new(SimUsb);
+    host.enable();
+
+    core.attach(LoopbackDevice::default());
+    assert_eq!(host.poll(), HostEvent::Connected);
+    host.reset_port(|_| {});
+    assert_eq!(host.poll(), HostEvent::PortEnabled(UsbSpeed::Full));
+
+    f(&mut host);
+
+    let errors = core.take_errors();
+    assert!(errors.is_empty(), "the driver misused the core: {:?}", errors);
+}
+
+fn control_channel(host: &mut UsbHost<SimUsb>) -> Channel {
+    host.alloc_channel(ChannelConfig {
+        device_address: 0,
+        endpoint_number: 0,
+        ep_type: EndpointType::Control,
+        max_packet_size: 8,
+    }).unwrap()
+}
+
+fn get_device_descriptor(length: u16) -> [u8; 8] {
+    let [low, high] = length.to_le_bytes();
+    [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, low, high]
+}
+
+#[test]
+fn port_reset_enables_port() {
+    let core = SimCore::new();
+    let mut host = UsbHost::new(SimUsb);
+    host.enable();
+
+    assert_eq!(host.poll(), HostEvent::None);
+    assert!(!host.is_connected());
+
+    core.attach(LoopbackDevice::default());
+    assert_eq!(host.poll(), HostEvent::Connected);
+    assert!(host.is_connected());
+    assert_eq!(host.port_speed(), None);
+
+    let mut delays = Vec::new();
+    host.reset_port(|ms| delays.push(ms));
+    assert!(delays[0] >= 10, "port reset must last at least 10 ms");
+
+    assert_eq!(host.poll(), HostEvent::PortEnabled(UsbSpeed::Full));
+    assert_eq!(host.poll(), HostEvent::None);
+    assert_eq!(host.port_speed(), Some(UsbSpeed::Full));
+
+    core.detach();
+    assert_eq!(host.poll(), HostEvent::Disconnected);
+    assert!(!host.is_connected());
+
+    assert!(core.take_errors().is_empty());
+}
+
+#[test]
+fn control_transfers() {
+    with_device(|host| {
+        let channel = control_channel(host);
+
+        // Multi-packet data stage
+        let mut buf = [0; 18];
+        assert_eq!(host.control_in(channel, &get_device_descriptor(18), &mut buf), Ok(18));
+        assert_eq!(buf, DEVICE_DESCRIPTOR);
+
+        host.control_out(channel, &[0x00, 0x05, 7, 0, 0, 0, 0, 0], &[]).unwrap();
+
+        // The device no longer responds to address 0
+        let mut buf = [0; 8];
+        assert_eq!(host.control_in(channel, &get_device_descriptor(8), &mut buf), Err(HostError::TransactionError));
+
+        host.set_device_address(channel, 7).unwrap();
+        assert_eq!(host.control_in(channel, &get_device_descriptor(8), &mut buf), Ok(8));
+        assert_eq!(buf, DEVICE_DESCRIPTOR[..8]);
+    });
+}
+
+#[test]
+fn unknown_request_stalls() {
+    with_device(|host| {
+        let channel = control_channel(host);
+
+        let mut buf = [0; 2];
+        assert_eq!(host.control_in(channel, &[0xc0, 0x42, 0, 0, 0, 0, 2, 0], &mut buf), Err(HostError::Stall));
+    });
+}
+
+#[test]
+fn bulk_transfers() {
+    with_device(|host| {
+        let config = |endpoint_number| ChannelConfig {
+            device_address: 0,
+            endpoint_number,
+            ep_type: EndpointType::Bulk,
+            max_packet_size: 64,
+        };
+        let out_channel = host.alloc_channel(config(1)).unwrap();
+        let in_channel = host.alloc_channel(config(1)).unwrap();
+
+        let data: Vec<u8> = (0..100).collect();
+        host.bulk_out(out_channel, &data).unwrap();
+
+        let mut buf = [0; 128];
+        assert_eq!(host.bulk_in(in_channel, &mut buf), Ok(100));
+        assert_eq!(buf[..100], data[..]);
+
+        // The device NAKs until the timeout when it has nothing to send
+        assert_eq!(host.bulk_in(in_channel, &mut buf), Err(HostError::Timeout));
+    });
+}
@@ -0,0 +1,663 @@
+#![cfg(feature = "sim")]
+
+use synopsys_usb_otg::sim::{self, Handshake, Request, SimCore, SimDevice, SimUsb, VirtualHost};
+use synopsys_usb_otg::{CableEvent, UsbBus, UsbPeripheral, UsbRole, UsbSpeed, VbusSense};
+use usb_device::bus::{UsbBus as _, UsbBusAllocator};
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+use usb_device::endpoint::{EndpointAddress, EndpointIn, EndpointOut};
+use usb_device::test_class;
+use usb_device::UsbError;
+
+mod common;
+
+use common::{allocator, with_host, Bus};
+
+// Full Speed packet size used by `TestClass`
+const BULK_PACKET_SIZE: usize = 64;
+
+const GET_DEVICE_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0];
+
+#[test]
+fn get_device_descriptor() {
+    with_host(|host| {
+        let descriptor = host.get_descriptor(0x01, 0, 0, 18).unwrap();
+
+        assert_eq!(descriptor.len(), 18);
+        assert_eq!(descriptor[1], 0x01);
+        assert_eq!(u16::from_le_bytes([descriptor[8], descriptor[9]]), test_class::VID);
+        assert_eq!(u16::from_le_bytes([descriptor[10], descriptor[11]]), test_class::PID);
+    });
+}
+
+#[test]
+fn set_address_and_configuration() {
+    with_host(|host| {
+        host.control_out(Request::set_address(5), &[]).unwrap();
+        assert_eq!(host.core().device_address(), 5);
+        assert_eq!(host.device().device.state(), UsbDeviceState::Addressed);
+
+        host.control_out(Request::set_configuration(1), &[]).unwrap();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Configured);
+    });
+}
+
+#[test]
+fn bulk_loopback() {
+    with_host(|host| {
+        host.control_out(Request::set_address(5), &[]).unwrap();
+        host.control_out(Request::set_configuration(1), &[]).unwrap();
+
+        // TestClass allocates bulk IN/OUT as endpoint 1 and echoes OUT data back on IN
+        let data: Vec<u8> = (0..100).collect();
+        for packet in data.chunks(BULK_PACKET_SIZE) {
+            host.out_packet(1, packet).unwrap();
+        }
+
+        let echoed = host.in_transfer(1, BULK_PACKET_SIZE, 1024).unwrap();
+        assert_eq!(echoed, data);
+    });
+}
+
+#[test]
+fn unknown_request_stalls() {
+    with_host(|host| {
+        let request = Request { request_type: 0xc0, request: test_class::REQ_UNKNOWN, value: 0, index: 0 };
+        assert_eq!(host.control_in(request, 8), Err(Handshake::Stall));
+
+        // The next SETUP packet clears the STALL condition
+        assert!(host.get_descriptor(0x01, 0, 0, 18).is_ok());
+    });
+}
+
+/// Simulated peripheral that senses VBUS with the B-session valid comparator
+struct VbusSimUsb;
+
+unsafe impl UsbPeripheral for VbusSimUsb {
+    const REGISTERS: *const () = sim::REGISTERS;
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+    const ENDPOINT_COUNT: usize = 4;
+    const VBUS_SENSE: VbusSense = VbusSense::BSessionValid;
+    const SRP_CAPABLE: bool = true;
+
+    fn enable() {}
+}
+
+fn vbus_allocator() -> UsbBusAllocator<UsbBus<VbusSimUsb>> {
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    UsbBus::new(VbusSimUsb, ep_memory)
+}
+
+/// Simulated dual-role peripheral with a micro-AB connector
+struct OtgSimUsb;
+
+unsafe impl UsbPeripheral for OtgSimUsb {
+    const REGISTERS: *const () = sim::REGISTERS;
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+    const ENDPOINT_COUNT: usize = 4;
+    const VBUS_SENSE: VbusSense = VbusSense::BSessionValid;
+    const DUAL_ROLE: bool = true;
+    const SRP_CAPABLE: bool = true;
+    const HNP_CAPABLE: bool = true;
+
+    fn enable() {}
+}
+
+fn otg_allocator() -> UsbBusAllocator<UsbBus<OtgSimUsb>> {
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    UsbBus::new(OtgSimUsb, ep_memory)
+}
+
+/// Device with a bulk OUT endpoint 1, which it does not read on its own
+struct BulkOutDevice<'a, USB: UsbPeripheral = SimUsb> {
+    device: UsbDevice<'a, UsbBus<USB>>,
+}
+
+impl<USB: UsbPeripheral> BulkOutDevice<'_, USB> {
+    fn bus(&self) -> &UsbBus<USB> {
+        self.device.bus()
+    }
+}
+
+impl<USB: UsbPeripheral> SimDevice for BulkOutDevice<'_, USB> {
+    fn poll(&mut self) {
+        self.device.poll(&mut []);
+    }
+}
+
+/// Runs `f` with an enumerated `BulkOutDevice` on the bus of `alloc`, with the cable plugged in.
+fn with_bulk_out_on<USB: UsbPeripheral>(
+    alloc: UsbBusAllocator<UsbBus<USB>>,
+    f: impl FnOnce(&mut VirtualHost<BulkOutDevice<USB>>, &EndpointOut<UsbBus<USB>>),
+) {
+    let sim = SimCore::new();
+    sim.set_vbus(true);
+    let bulk_out: EndpointOut<UsbBus<USB>> = alloc.bulk(BULK_PACKET_SIZE as u16);
+    let device = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x05dc)).build();
+
+    let mut host = VirtualHost::new(sim, BulkOutDevice { device });
+    host.poll();
+    host.enumerate(5).unwrap();
+
+    f(&mut host, &bulk_out);
+}
+
+/// Runs `f` with an enumerated `BulkOutDevice`.
+fn with_bulk_out(f: impl FnOnce(&mut VirtualHost<BulkOutDevice>, &EndpointOut<Bus>)) {
+    with_bulk_out_on(allocator(), f);
+}
+
+#[test]
+fn oversized_packet_is_dropped() {
+    with_bulk_out(|host, bulk_out| {
+        // The packet does not fit into the buffer of the endpoint
+        assert!(host.core().push_rx(1, 0x02, &[0x55; 2 * BULK_PACKET_SIZE]));
+        host.poll();
+        assert!(host.core().rx_fifo_empty());
+
+        let mut buf = [0; 2 * BULK_PACKET_SIZE];
+        assert!(bulk_out.read(&mut buf).is_err());
+        assert!(host.get_descriptor(0x01, 0, 0, 18).is_ok());
+    });
+}
+
+#[test]
+fn bus_reset_discards_received_packets() {
+    with_bulk_out(|host, bulk_out| {
+        host.out_packet(1, &[1; 8]).unwrap();
+        host.enumerate(5).unwrap();
+
+        // The packet was sent before the reset and must not be mistaken for a new one
+        let mut buf = [0; BULK_PACKET_SIZE];
+        assert!(bulk_out.read(&mut buf).is_err());
+    });
+}
+
+#[test]
+fn out_completed_status_of_unused_endpoint_is_ignored() {
+    with_bulk_out(|host, _| {
+        // Endpoint 2 is not allocated and must stay disabled
+        assert!(host.core().push_rx(2, 0x03, &[]));
+        host.poll();
+        assert!(host.core().rx_fifo_empty());
+
+        assert_eq!(host.out_packet(2, &[1]), Err(Handshake::Nak));
+    });
+}
+
+#[test]
+fn bus_reset_flushes_pending_packets() {
+    const USBRST: u32 = 1 << 12;
+
+    with_bulk_out(|host, _| {
+        // The reset is reported while a packet is waiting in the RX FIFO, the end of the
+        // enumeration follows later
+        assert!(host.core().push_rx(1, 0x02, &[1; 8]));
+        host.core().raise_interrupts(USBRST);
+        host.poll();
+        assert!(host.core().rx_fifo_empty());
+
+        host.enumerate(5).unwrap();
+    });
+}
+
+#[test]
+fn incomplete_isochronous_interrupts_are_masked_without_isochronous_endpoints() {
+    const IISOIXFR: u32 = 1 << 20;
+    const IPXFR_INCOMPISOOUT: u32 = 1 << 21;
+
+    with_bulk_out(|host, _| {
+        host.core().raise_interrupts(IISOIXFR | IPXFR_INCOMPISOOUT);
+        assert!(!host.core().interrupt_pending());
+    });
+}
+
+#[test]
+fn full_out_endpoint_does_not_block_other_endpoints() {
+    with_bulk_out(|host, bulk_out| {
+        host.out_packet(1, &[1; BULK_PACKET_SIZE]).unwrap();
+        assert_eq!(host.out_packet(1, &[2; BULK_PACKET_SIZE]), Err(Handshake::Nak));
+        assert!(host.core().rx_fifo_empty());
+        assert!(host.get_descriptor(0x01, 0, 0, 18).is_ok());
+
+        let mut buf = [0; BULK_PACKET_SIZE];
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), BULK_PACKET_SIZE);
+        assert_eq!(buf, [1; BULK_PACKET_SIZE]);
+
+        host.out_packet(1, &[2; BULK_PACKET_SIZE]).unwrap();
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), BULK_PACKET_SIZE);
+        assert_eq!(buf, [2; BULK_PACKET_SIZE]);
+    });
+}
+
+#[test]
+fn out_queue_buffers_back_to_back_packets() {
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    let alloc = UsbBus::new_with_out_queues(SimUsb, ep_memory, &[1, 3]);
+
+    with_bulk_out_on(alloc, |host, bulk_out| {
+        host.out_packet(1, &[1; BULK_PACKET_SIZE]).unwrap();
+        host.out_packet(1, &[2; 10]).unwrap();
+        host.out_packet(1, &[]).unwrap();
+        assert_eq!(host.out_packet(1, &[4; 5]), Err(Handshake::Nak));
+        assert!(host.get_descriptor(0x01, 0, 0, 18).is_ok());
+
+        let mut buf = [0; BULK_PACKET_SIZE];
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), BULK_PACKET_SIZE);
+        assert_eq!(buf, [1; BULK_PACKET_SIZE]);
+
+        // Reading a packet makes room for the next one
+        host.out_packet(1, &[4; 5]).unwrap();
+
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], [2; 10]);
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), 0);
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), 5);
+        assert_eq!(buf[..5], [4; 5]);
+        assert!(bulk_out.read(&mut buf).is_err());
+    });
+}
+
+#[test]
+#[should_panic(expected = "endpoint 0 buffers a single packet")]
+fn out_queue_depth_of_endpoint_0_is_rejected() {
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    let _ = UsbBus::new_with_out_queues(SimUsb, ep_memory, &[2, 2]);
+}
+
+#[test]
+#[should_panic(expected = "more entries than ENDPOINT_COUNT")]
+fn out_queue_depths_beyond_endpoint_count_are_rejected() {
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    let _ = UsbBus::new_with_out_queues(SimUsb, ep_memory, &[1, 2, 2, 2, 2]);
+}
+
+#[test]
+fn setup_replaces_unread_endpoint_0_data() {
+    with_bulk_out(|host, _| {
+        // The device is polled without reading the data stage before the host starts over
+        assert_eq!(host.core().out(0, &[1, 2, 3, 4]), Handshake::Ack);
+        assert_eq!(host.core().setup(0, &GET_DEVICE_DESCRIPTOR), Handshake::Ack);
+        for _ in 0..4 {
+            host.device().device.bus().poll();
+        }
+        assert!(host.core().rx_fifo_empty());
+
+        let mut buf = [0; 8];
+        assert_eq!(host.device().device.bus().read(EndpointAddress::from(0x00), &mut buf).unwrap(), 8);
+        assert_eq!(buf, GET_DEVICE_DESCRIPTOR);
+    });
+}
+
+fn set_out_nak(host: &mut VirtualHost<BulkOutDevice>, ep_addr: EndpointAddress, nak: bool) -> usb_device::Result<()> {
+    host.device().device.bus().set_out_nak(ep_addr, nak)
+}
+
+#[test]
+fn out_nak_holds_endpoint() {
+    with_bulk_out(|host, bulk_out| {
+        let mut buf = [0; BULK_PACKET_SIZE];
+
+        set_out_nak(host, bulk_out.address(), true).unwrap();
+        assert_eq!(host.out_packet(1, &[1; 8]), Err(Handshake::Nak));
+        assert!(host.get_descriptor(0x01, 0, 0, 18).is_ok());
+
+        set_out_nak(host, bulk_out.address(), false).unwrap();
+        host.out_packet(1, &[1; 8]).unwrap();
+
+        // The hold is kept while the endpoint is re-enabled
+        set_out_nak(host, bulk_out.address(), true).unwrap();
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), 8);
+        assert_eq!(host.out_packet(1, &[2; 8]), Err(Handshake::Nak));
+
+        set_out_nak(host, bulk_out.address(), false).unwrap();
+        host.out_packet(1, &[2; 8]).unwrap();
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), 8);
+        assert_eq!(buf[..8], [2; 8]);
+
+        assert!(set_out_nak(host, EndpointAddress::from(0x00), true).is_err());
+    });
+}
+
+fn remote_wakeup(host: &mut VirtualHost<BulkOutDevice>, delays: &mut Vec<u32>) -> usb_device::Result<()> {
+    host.device().device.bus().remote_wakeup(true, |ms| delays.push(ms))
+}
+
+#[test]
+fn remote_wakeup_requires_suspended_bus() {
+    with_bulk_out(|host, _| {
+        let mut delays = Vec::new();
+        assert!(matches!(remote_wakeup(host, &mut delays), Err(UsbError::InvalidState)));
+        assert!(delays.is_empty());
+
+        host.suspend();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Suspend);
+
+        remote_wakeup(host, &mut delays).unwrap();
+        assert_eq!(delays.len(), 1);
+        assert!((1..=15).contains(&delays[0]));
+    });
+}
+
+#[test]
+fn suspended_core_is_only_accessed_after_wakeup() {
+    with_bulk_out(|host, ep| {
+        host.suspend();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Suspend);
+
+        // Polling without a wakeup leaves the clocks gated
+        host.poll();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Suspend);
+
+        host.resume();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Configured);
+
+        host.out_packet(1, &[1, 2, 3]).unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(ep.read(&mut buf).unwrap(), 3);
+
+        host.suspend();
+        host.bus_reset();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Default);
+    });
+}
+
+#[test]
+fn suspended_core_is_accessible_without_waking_it_up() {
+    with_bulk_out_on(vbus_allocator(), |host, bulk_out| {
+        host.suspend();
+
+        let bus = host.device().bus();
+        assert_eq!(bus.speed(), UsbSpeed::Full);
+        assert_eq!(bus.role(), UsbRole::Device);
+        assert_eq!(bus.frame_number(), 0);
+        assert!(bus.is_vbus_present());
+        assert!(matches!(bus.request_session(), Err(UsbError::InvalidState)));
+        bus.set_sof_enabled(false);
+        bus.set_out_nak(bulk_out.address(), true).unwrap();
+        bus.set_out_nak(bulk_out.address(), false).unwrap();
+        bus.set_stalled(bulk_out.address(), true);
+        assert!(bus.is_stalled(bulk_out.address()));
+        assert_eq!(bus.write(EndpointAddress::from(0x80), &[]).unwrap(), 0);
+        let mut buf = [0; BULK_PACKET_SIZE];
+        assert!(matches!(bulk_out.read(&mut buf), Err(UsbError::WouldBlock)));
+
+        // The clocks are gated again, polling without a wakeup leaves the device suspended
+        host.poll();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Suspend);
+
+        host.resume();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Configured);
+        assert_eq!(host.out_packet(1, &[1; 8]), Err(Handshake::Stall));
+        assert_eq!(host.core().in_token(0), Ok(Vec::new()));
+    });
+}
+
+#[test]
+fn unplugging_suspended_device_resets_it() {
+    with_bulk_out_on(vbus_allocator(), |host, _| {
+        host.suspend();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Suspend);
+
+        host.core().set_vbus(false);
+        host.poll();
+        assert_eq!(host.device().bus().take_cable_event(), Some(CableEvent::Detached));
+        assert_eq!(host.device().device.state(), UsbDeviceState::Default);
+        assert!(!host.core().is_connected());
+    });
+}
+
+#[test]
+fn unplugging_cable_resets_device() {
+    with_bulk_out_on(vbus_allocator(), |host, bulk_out| {
+        assert!(host.device().bus().is_vbus_present());
+        assert_eq!(host.device().bus().take_cable_event(), None);
+        host.out_packet(1, &[1; 8]).unwrap();
+
+        host.core().set_vbus(false);
+        host.poll();
+        assert_eq!(host.device().bus().take_cable_event(), Some(CableEvent::Detached));
+        assert_eq!(host.device().device.state(), UsbDeviceState::Default);
+        assert!(!host.device().bus().is_vbus_present());
+        assert!(!host.core().is_connected());
+
+        // Packets received before the cable was unplugged are dropped
+        let mut buf = [0; BULK_PACKET_SIZE];
+        assert!(bulk_out.read(&mut buf).is_err());
+    });
+}
+
+#[test]
+fn plugging_cable_connects_device() {
+    with_bulk_out_on(vbus_allocator(), |host, _| {
+        host.core().set_vbus(false);
+        host.poll();
+        assert_eq!(host.device().bus().take_cable_event(), Some(CableEvent::Detached));
+
+        host.core().set_vbus(true);
+        host.poll();
+        assert_eq!(host.device().bus().take_cable_event(), Some(CableEvent::Attached));
+        assert_eq!(host.device().bus().take_cable_event(), None);
+        assert!(host.core().is_connected());
+
+        host.enumerate(5).unwrap();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Configured);
+    });
+}
+
+#[test]
+fn sof_events_report_frame_numbers() {
+    with_bulk_out(|host, _| {
+        host.device().bus().set_sof_enabled(true);
+        host.core().sof();
+        host.poll();
+        let frame = host.device().bus().take_sof().unwrap();
+        assert_eq!(host.device().bus().frame_number(), frame);
+        assert_eq!(host.device().bus().take_sof(), None);
+
+        // SOFs that arrive before the previous one was taken are merged
+        for _ in frame..0x7ff {
+            host.core().sof();
+        }
+        host.poll();
+        assert_eq!(host.device().bus().take_sof(), Some(0x7ff));
+
+        host.core().sof();
+        host.poll();
+        assert_eq!(host.device().bus().take_sof(), Some(0));
+
+        host.device().bus().set_sof_enabled(false);
+        host.core().sof();
+        assert!(!host.core().interrupt_pending());
+        host.poll();
+        assert_eq!(host.device().bus().take_sof(), None);
+        assert_eq!(host.device().bus().frame_number(), 1);
+    });
+}
+
+/// Size of the transfer buffers of `with_transfer_buffers`, four bulk packets
+const TRANSFER_BUFFER_SIZE: usize = 4 * BULK_PACKET_SIZE;
+
+/// Runs `f` with an enumerated device whose bulk endpoints 1 have transfer buffers.
+fn with_transfer_buffers(f: impl FnOnce(&mut VirtualHost<BulkOutDevice>, &EndpointIn<Bus>, &EndpointOut<Bus>)) {
+    let sim = SimCore::new();
+    let alloc = allocator();
+    let bulk_out: EndpointOut<Bus> = alloc.bulk(BULK_PACKET_SIZE as u16);
+    let bulk_in: EndpointIn<Bus> = alloc.bulk(BULK_PACKET_SIZE as u16);
+    let device = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x05dc)).build();
+
+    let in_buffer = Box::leak(Box::new([0u32; TRANSFER_BUFFER_SIZE / 4]));
+    let out_buffer = Box::leak(Box::new([0u32; TRANSFER_BUFFER_SIZE / 4]));
+    device.bus().set_in_transfer_buffer(bulk_in.address(), in_buffer).unwrap();
+    device.bus().set_out_transfer_buffer(bulk_out.address(), out_buffer).unwrap();
+
+    let mut host = VirtualHost::new(sim, BulkOutDevice { device });
+    host.poll();
+    host.enumerate(5).unwrap();
+
+    f(&mut host, &bulk_in, &bulk_out);
+}
+
+#[test]
+fn in_transfer_is_sent_in_packets() {
+    with_transfer_buffers(|host, bulk_in, _| {
+        let data: Vec<u8> = (0..150).collect();
+        assert_eq!(bulk_in.write(&data).unwrap(), data.len());
+        assert!(matches!(bulk_in.write(&data), Err(UsbError::WouldBlock)));
+        assert_eq!(host.in_transfer(1, BULK_PACKET_SIZE, TRANSFER_BUFFER_SIZE).unwrap(), data);
+
+        // A new transfer can be started once the previous one completed, a payload that is a
+        // multiple of the packet size is not terminated with a zero-length packet
+        let data = [7; 2 * BULK_PACKET_SIZE];
+        assert_eq!(bulk_in.write(&data).unwrap(), data.len());
+        assert_eq!(host.in_transfer(1, BULK_PACKET_SIZE, data.len()).unwrap(), data);
+        assert_eq!(host.in_packet(1), Err(Handshake::Nak));
+    });
+}
+
+#[test]
+fn in_transfer_larger_than_buffer_is_rejected() {
+    with_transfer_buffers(|host, bulk_in, _| {
+        let data = [1; TRANSFER_BUFFER_SIZE + 1];
+        assert!(matches!(bulk_in.write(&data), Err(UsbError::BufferOverflow)));
+        assert_eq!(host.in_packet(1), Err(Handshake::Nak));
+    });
+}
+
+#[test]
+fn out_transfer_ends_on_short_packet() {
+    with_transfer_buffers(|host, _, bulk_out| {
+        let mut buf = [0; TRANSFER_BUFFER_SIZE];
+
+        // The endpoint NAKs until a transfer is started
+        assert_eq!(host.out_packet(1, &[1; 8]), Err(Handshake::Nak));
+
+        host.device().bus().start_out_transfer(bulk_out.address(), 3 * BULK_PACKET_SIZE).unwrap();
+        host.out_packet(1, &[1; BULK_PACKET_SIZE]).unwrap();
+        assert!(matches!(bulk_out.read(&mut buf), Err(UsbError::WouldBlock)));
+        host.out_packet(1, &[2; 10]).unwrap();
+
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), BULK_PACKET_SIZE + 10);
+        assert_eq!(buf[..BULK_PACKET_SIZE], [1; BULK_PACKET_SIZE]);
+        assert_eq!(buf[BULK_PACKET_SIZE..BULK_PACKET_SIZE + 10], [2; 10]);
+        assert_eq!(host.out_packet(1, &[3; 8]), Err(Handshake::Nak));
+
+        // The transfer can be restarted, and ends after the requested number of full packets
+        host.device().bus().start_out_transfer(bulk_out.address(), 2 * BULK_PACKET_SIZE).unwrap();
+        host.out_packet(1, &[3; BULK_PACKET_SIZE]).unwrap();
+        host.out_packet(1, &[4; BULK_PACKET_SIZE]).unwrap();
+        assert_eq!(host.out_packet(1, &[5; 8]), Err(Handshake::Nak));
+
+        assert_eq!(bulk_out.read(&mut buf).unwrap(), 2 * BULK_PACKET_SIZE);
+        assert_eq!(buf[..BULK_PACKET_SIZE], [3; BULK_PACKET_SIZE]);
+        assert_eq!(buf[BULK_PACKET_SIZE..2 * BULK_PACKET_SIZE], [4; BULK_PACKET_SIZE]);
+    });
+}
+
+#[test]
+fn out_transfer_larger_than_buffer_is_rejected() {
+    with_transfer_buffers(|host, _, bulk_out| {
+        let bus = host.device().bus();
+        assert!(matches!(bus.start_out_transfer(bulk_out.address(), TRANSFER_BUFFER_SIZE + 1), Err(UsbError::BufferOverflow)));
+
+        bus.start_out_transfer(bulk_out.address(), TRANSFER_BUFFER_SIZE).unwrap();
+        assert!(matches!(bus.start_out_transfer(bulk_out.address(), 8), Err(UsbError::WouldBlock)));
+    });
+}
+
+#[test]
+fn id_pin_switches_role() {
+    with_bulk_out_on(otg_allocator(), |host, _| {
+        assert_eq!(host.device().bus().role(), UsbRole::Device);
+
+        // The role does not change before the core switched its mode, and the ID pin change is
+        // not left pending meanwhile
+        host.core().set_id_pin(true);
+        host.poll();
+        assert!(!host.core().interrupt_pending());
+        assert_eq!(host.device().bus().take_role_change(), None);
+        assert!(host.device().bus().host().is_none());
+
+        host.core().finish_mode_switch();
+        host.poll();
+        assert_eq!(host.device().bus().take_role_change(), Some(UsbRole::Host));
+        assert_eq!(host.device().bus().role(), UsbRole::Host);
+        assert_eq!(host.device().device.state(), UsbDeviceState::Suspend);
+        assert!(!host.core().is_connected());
+
+        // The host driver is handed out once
+        assert!(host.device().bus().host().is_some());
+        assert!(host.device().bus().host().is_none());
+
+        host.core().set_id_pin(false);
+        host.poll();
+        host.core().finish_mode_switch();
+        host.poll();
+        assert_eq!(host.device().bus().take_role_change(), Some(UsbRole::Device));
+        assert!(host.core().is_connected());
+
+        host.enumerate(5).unwrap();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Configured);
+    });
+}
+
+#[test]
+fn session_request_switches_vbus_on() {
+    with_bulk_out_on(otg_allocator(), |host, _| {
+        // A session is already valid
+        assert!(matches!(host.device().bus().request_session(), Err(UsbError::InvalidState)));
+
+        host.core().set_vbus(false);
+        host.poll();
+        assert!(host.device().bus().take_otg_events().session_end);
+        assert_eq!(host.device().bus().take_cable_event(), Some(CableEvent::Detached));
+
+        host.device().bus().request_session().unwrap();
+        assert!(host.core().session_requested());
+        host.core().answer_session_request(false);
+        host.poll();
+        let events = host.device().bus().take_otg_events();
+        assert!(events.session_request_failure && !events.session_request_success);
+        assert!(!host.core().session_requested());
+        assert!(!host.core().is_connected());
+
+        host.device().bus().request_session().unwrap();
+        host.core().answer_session_request(true);
+        host.poll();
+        let events = host.device().bus().take_otg_events();
+        assert!(events.session_request_success && !events.session_request_failure);
+        assert_eq!(host.device().bus().take_cable_event(), Some(CableEvent::Attached));
+        assert!(host.core().is_connected());
+
+        host.enumerate(5).unwrap();
+    });
+}
+
+#[test]
+fn host_negotiation_switches_role() {
+    with_bulk_out_on(otg_allocator(), |host, _| {
+        // The host must enable HNP first, which is cleared by a bus reset
+        assert!(matches!(host.device().bus().request_host_role(), Err(UsbError::InvalidState)));
+        host.device().bus().set_hnp_enabled(true).unwrap();
+        host.enumerate(5).unwrap();
+        assert!(matches!(host.device().bus().request_host_role(), Err(UsbError::InvalidState)));
+        host.device().bus().set_hnp_enabled(true).unwrap();
+
+        // The role switch is requested while the bus is suspended
+        host.suspend();
+        host.device().bus().request_host_role().unwrap();
+        assert!(host.core().host_negotiation_requested());
+        host.poll();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Suspend);
+
+        host.core().answer_host_negotiation(true);
+        host.poll();
+        let events = host.device().bus().take_otg_events();
+        assert!(events.host_negotiation_success && !events.host_negotiation_failure);
+        assert!(!host.core().host_negotiation_requested());
+        assert_eq!(host.device().bus().take_role_change(), Some(UsbRole::Host));
+        assert!(!host.core().is_connected());
+        assert!(host.device().bus().host().is_some());
+    });
+}
@@ -0,0 +1,343 @@
+#![cfg(feature = "sim")]
+
+//! Enumeration of the test class and of CDC-ACM and HID class configurations through the virtual
+//! host
+
+use synopsys_usb_otg::sim::{Enumeration, Handshake, Request, SimCore, SimDevice, VirtualHost};
+use usb_device::bus::{InterfaceNumber, UsbBusAllocator};
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::control::{Recipient, RequestType};
+use usb_device::descriptor::DescriptorWriter;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+use usb_device::endpoint::{EndpointAddress, EndpointIn, EndpointOut};
+use usb_device::test_class;
+
+mod common;
+
+use common::{allocator, with_host, Bus};
+
+struct Device<'a, C> {
+    device: UsbDevice<'a, Bus>,
+    class: C,
+}
+
+impl<C: UsbClass<Bus>> SimDevice for Device<'_, C> {
+    fn poll(&mut self) {
+        self.device.poll(&mut [&mut self.class]);
+    }
+}
+
+/// Returns the addresses of the endpoints of an interface class, in descriptor order.
+fn endpoints(enumeration: &Enumeration, interface_class: u8) -> Vec<u8> {
+    let mut in_interface = false;
+    let mut addresses = Vec::new();
+    for descriptor in enumeration.descriptors() {
+        match descriptor[1] {
+            0x04 => in_interface = descriptor[5] == interface_class,
+            0x05 if in_interface => addresses.push(descriptor[2]),
+            _ => {}
+        }
+    }
+    addresses
+}
+
+const CDC_SET_LINE_CODING: u8 = 0x20;
+const CDC_GET_LINE_CODING: u8 = 0x21;
+const CDC_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// Minimal CDC-ACM serial port that echoes received data
+struct CdcAcm<'a> {
+    comm_if: InterfaceNumber,
+    data_if: InterfaceNumber,
+    comm_ep: EndpointIn<'a, Bus>,
+    read_ep: EndpointOut<'a, Bus>,
+    write_ep: EndpointIn<'a, Bus>,
+    line_coding: [u8; 7],
+    dtr: bool,
+}
+
+impl<'a> CdcAcm<'a> {
+    fn new(alloc: &'a UsbBusAllocator<Bus>) -> Self {
+        CdcAcm {
+            comm_if: alloc.interface(),
+            data_if: alloc.interface(),
+            comm_ep: alloc.interrupt(8, 255),
+            read_ep: alloc.bulk(64),
+            write_ep: alloc.bulk(64),
+            line_coding: [0x80, 0x25, 0x00, 0x00, 0x00, 0x00, 0x08],
+            dtr: false,
+        }
+    }
+}
+
+impl UsbClass<Bus> for CdcAcm<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.iad(self.comm_if, 2, 0x02, 0x02, 0x00)?;
+        writer.interface(self.comm_if, 0x02, 0x02, 0x00)?;
+        // Header, call management, ACM and union functional descriptors
+        writer.write(0x24, &[0x00, 0x10, 0x01])?;
+        writer.write(0x24, &[0x01, 0x00, self.data_if.into()])?;
+        writer.write(0x24, &[0x02, 0x02])?;
+        writer.write(0x24, &[0x06, self.comm_if.into(), self.data_if.into()])?;
+        writer.endpoint(&self.comm_ep)?;
+        writer.interface(self.data_if, 0x0a, 0x00, 0x00)?;
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+        Ok(())
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr == self.read_ep.address() {
+            let mut buf = [0; 64];
+            let count = self.read_ep.read(&mut buf).unwrap();
+            self.write_ep.write(&buf[..count]).unwrap();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<Bus>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Class && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.comm_if) as u16 && req.request == CDC_GET_LINE_CODING
+        {
+            xfer.accept_with(&self.line_coding).unwrap();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<Bus>) {
+        let req = *xfer.request();
+        if req.request_type != RequestType::Class || req.recipient != Recipient::Interface
+            || req.index != u8::from(self.comm_if) as u16
+        {
+            return;
+        }
+
+        match req.request {
+            CDC_SET_LINE_CODING if xfer.data().len() == 7 => {
+                self.line_coding.copy_from_slice(xfer.data());
+                xfer.accept().unwrap();
+            }
+            CDC_SET_CONTROL_LINE_STATE => {
+                self.dtr = req.value & 1 != 0;
+                xfer.accept().unwrap();
+            }
+            _ => xfer.reject().unwrap(),
+        }
+    }
+}
+
+const HID_GET_REPORT: u8 = 0x01;
+const HID_SET_IDLE: u8 = 0x0a;
+
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xff, // Usage Page (Vendor Defined)
+    0x09, 0x01, // Usage (1)
+    0xa1, 0x01, // Collection (Application)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x04, //   Report Count (4)
+    0x09, 0x01, //   Usage (1)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0xc0, // End Collection
+];
+
+/// Minimal HID device with a single 4-byte input report
+struct Hid<'a> {
+    interface: InterfaceNumber,
+    ep: EndpointIn<'a, Bus>,
+    report: [u8; 4],
+    idle_rate: Option<u8>,
+}
+
+impl<'a> Hid<'a> {
+    fn new(alloc: &'a UsbBusAllocator<Bus>) -> Self {
+        Hid { interface: alloc.interface(), ep: alloc.interrupt(8, 10), report: [0; 4], idle_rate: None }
+    }
+
+    fn push_report(&mut self, report: [u8; 4]) {
+        self.report = report;
+        self.ep.write(&report).unwrap();
+    }
+}
+
+impl UsbClass<Bus> for Hid<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.interface(self.interface, 0x03, 0x00, 0x00)?;
+        let length = (REPORT_DESCRIPTOR.len() as u16).to_le_bytes();
+        writer.write(0x21, &[0x11, 0x01, 0x00, 0x01, 0x22, length[0], length[1]])?;
+        writer.endpoint(&self.ep)?;
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<Bus>) {
+        let req = *xfer.request();
+        if req.recipient != Recipient::Interface || req.index != u8::from(self.interface) as u16 {
+            return;
+        }
+
+        match (req.request_type, req.request) {
+            (RequestType::Standard, usb_device::control::Request::GET_DESCRIPTOR) if req.value >> 8 == 0x22 => {
+                xfer.accept_with_static(REPORT_DESCRIPTOR).unwrap();
+            }
+            (RequestType::Class, HID_GET_REPORT) => {
+                xfer.accept_with(&self.report).unwrap();
+            }
+            _ => {}
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<Bus>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Class && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface) as u16 && req.request == HID_SET_IDLE
+        {
+            self.idle_rate = Some((req.value >> 8) as u8);
+            xfer.accept().unwrap();
+        }
+    }
+}
+
+fn build<'a, C>(alloc: &'a UsbBusAllocator<Bus>, class: C, vid_pid: UsbVidPid, device_class: u8) -> Device<'a, C> {
+    let device = UsbDeviceBuilder::new(alloc, vid_pid)
+        .manufacturer("Simulated")
+        .product("Virtual host test")
+        .device_class(device_class)
+        .build();
+    Device { device, class }
+}
+
+#[test]
+fn cdc_acm() {
+    let sim = SimCore::new();
+    let alloc = allocator();
+    let class = CdcAcm::new(&alloc);
+    // Miscellaneous device class with an interface association
+    let device = build(&alloc, class, UsbVidPid(0x16c0, 0x27dd), 0xef);
+    let mut host = VirtualHost::new(sim, device);
+
+    let enumeration = host.enumerate(3).unwrap();
+    assert_eq!(host.device().device.state(), UsbDeviceState::Configured);
+    assert_eq!(u16::from_le_bytes([enumeration.device_descriptor[10], enumeration.device_descriptor[11]]), 0x27dd);
+    assert_eq!(host.get_string(2, 0x0409).unwrap(), "Virtual host test");
+
+    let comm = endpoints(&enumeration, 0x02);
+    let data = endpoints(&enumeration, 0x0a);
+    assert_eq!(comm.len(), 1);
+    assert_eq!(data.len(), 2);
+    let data_in = *data.iter().find(|&&ep| ep & 0x80 != 0).unwrap();
+    let data_out = *data.iter().find(|&&ep| ep & 0x80 == 0).unwrap();
+
+    let comm_if = u8::from(host.device().class.comm_if) as u16;
+    let line_coding = [0x00, 0xc2, 0x01, 0x00, 0x00, 0x00, 0x08];
+    let set_line_coding = Request { request_type: 0x21, request: CDC_SET_LINE_CODING, value: 0, index: comm_if };
+    host.control_out(set_line_coding, &line_coding).unwrap();
+    let get_line_coding = Request { request_type: 0xa1, request: CDC_GET_LINE_CODING, value: 0, index: comm_if };
+    assert_eq!(host.control_in(get_line_coding, 7).unwrap(), line_coding);
+
+    let set_control_line_state = Request { request_type: 0x21, request: CDC_SET_CONTROL_LINE_STATE, value: 0x0003, index: comm_if };
+    host.control_out(set_control_line_state, &[]).unwrap();
+    assert!(host.device().class.dtr);
+
+    let max_packet_size = enumeration.max_packet_size(data_out).unwrap();
+    host.out_transfer(data_out & 0x7f, max_packet_size, b"hello").unwrap();
+    assert_eq!(host.in_transfer(data_in & 0x7f, max_packet_size, 64).unwrap(), b"hello");
+
+    // Unsupported class requests are stalled
+    let send_break = Request { request_type: 0x21, request: 0x23, value: 0, index: comm_if };
+    assert_eq!(host.control_out(send_break, &[]), Err(Handshake::Stall));
+}
+
+#[test]
+fn hid() {
+    let sim = SimCore::new();
+    let alloc = allocator();
+    let class = Hid::new(&alloc);
+    let device = build(&alloc, class, UsbVidPid(0x16c0, 0x05df), 0x00);
+    let mut host = VirtualHost::new(sim, device);
+
+    let enumeration = host.enumerate(9).unwrap();
+    assert_eq!(host.device().device.state(), UsbDeviceState::Configured);
+
+    let hid_descriptor = enumeration.descriptors().find(|d| d[1] == 0x21).unwrap();
+    let report_length = u16::from_le_bytes([hid_descriptor[7], hid_descriptor[8]]);
+    let interface = u8::from(host.device().class.interface) as u16;
+    let get_report_descriptor = Request { request_type: 0x81, request: 0x06, value: 0x2200, index: interface };
+    assert_eq!(host.control_in(get_report_descriptor, report_length).unwrap(), REPORT_DESCRIPTOR);
+
+    let set_idle = Request { request_type: 0x21, request: HID_SET_IDLE, value: 0x0000, index: interface };
+    host.control_out(set_idle, &[]).unwrap();
+    assert_eq!(host.device().class.idle_rate, Some(0));
+
+    let ep = endpoints(&enumeration, 0x03)[0];
+    assert_eq!(host.in_packet(ep & 0x7f), Err(Handshake::Nak));
+
+    host.device().class.push_report([1, 2, 3, 4]);
+    assert_eq!(host.in_packet(ep & 0x7f).unwrap(), [1, 2, 3, 4]);
+
+    let get_report = Request { request_type: 0xa1, request: HID_GET_REPORT, value: 0x0100, index: interface };
+    assert_eq!(host.control_in(get_report, 4).unwrap(), [1, 2, 3, 4]);
+}
+
+// Full Speed packet size of the bulk endpoints of `TestClass`
+const BULK_PACKET_SIZE: usize = 64;
+
+#[test]
+fn get_device_descriptor() {
+    with_host(|host| {
+        let descriptor = host.get_descriptor(0x01, 0, 0, 18).unwrap();
+
+        assert_eq!(descriptor.len(), 18);
+        assert_eq!(descriptor[1], 0x01);
+        assert_eq!(u16::from_le_bytes([descriptor[8], descriptor[9]]), test_class::VID);
+        assert_eq!(u16::from_le_bytes([descriptor[10], descriptor[11]]), test_class::PID);
+    });
+}
+
+#[test]
+fn set_address_and_configuration() {
+    with_host(|host| {
+        host.control_out(Request::set_address(5), &[]).unwrap();
+        assert_eq!(host.core().active_address(), 5);
+        assert_eq!(host.device().device.state(), UsbDeviceState::Addressed);
+
+        host.control_out(Request::set_configuration(1), &[]).unwrap();
+        assert_eq!(host.device().device.state(), UsbDeviceState::Configured);
+    });
+}
+
+#[test]
+fn enumerate() {
+    with_host(|host| {
+        let enumeration = host.enumerate(7).unwrap();
+
+        assert_eq!(host.core().active_address(), 7);
+        assert_eq!(host.device().device.state(), UsbDeviceState::Configured);
+        assert_eq!(enumeration.max_packet_size(0x81), Some(BULK_PACKET_SIZE));
+        assert_eq!(host.get_string(2, 0x0409).unwrap(), test_class::PRODUCT);
+    });
+}
+
+#[test]
+fn bulk_loopback() {
+    with_host(|host| {
+        host.enumerate(5).unwrap();
+
+        // TestClass allocates bulk IN/OUT as endpoint 1 and echoes OUT data back on IN
+        let data: Vec<u8> = (0..100).collect();
+        host.out_transfer(1, BULK_PACKET_SIZE, &data).unwrap();
+
+        let echoed = host.in_transfer(1, BULK_PACKET_SIZE, data.len()).unwrap();
+        assert_eq!(echoed, data);
+    });
+}
+
+#[test]
+fn unknown_request_stalls() {
+    with_host(|host| {
+        let request = Request { request_type: 0xc0, request: test_class::REQ_UNKNOWN, value: 0, index: 0 };
+        assert_eq!(host.control_in(request, 8), Err(Handshake::Stall));
+
+        // The next SETUP packet clears the STALL condition
+        assert!(host.get_descriptor(0x01, 0, 0, 18).is_ok());
+    });
+}